@@ -0,0 +1,72 @@
+//! Accept throughput of a [`Connections`] implementation returning an unboxed
+//! future, compared to one boxing the future on every accept
+//! (which is how the `Connections` trait used to work).
+//!
+//! Run with `cargo bench --bench accept`.
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tinyroute::errors::Result;
+use tinyroute::server::{Connections, ConnectionAddr, UdsConnections, UnixListener, UnixStream};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::runtime::Runtime;
+
+type Accepted = (OwnedReadHalf, OwnedWriteHalf, ConnectionAddr);
+
+struct BoxedUdsConnections(UnixListener);
+
+impl BoxedUdsConnections {
+    fn accept(&mut self) -> Pin<Box<dyn Future<Output = Result<Accepted>> + Send + '_>> {
+        Box::pin(async move {
+            let (socket, _) = self.0.accept().await?;
+            let (reader, writer) = socket.into_split();
+            Ok((reader, writer, ConnectionAddr::Uds))
+        })
+    }
+}
+
+fn socket_path(name: &str) -> String {
+    let path = format!("/tmp/tinyroute-bench-{}.sock", name);
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+// A closure can't return a future borrowing the listener, so this is a macro
+macro_rules! connect_and_accept {
+    ($path:expr, $iters:expr, $connections:expr) => {{
+        let start = Instant::now();
+        for _ in 0..$iters {
+            let _client = UnixStream::connect($path).await.unwrap();
+            let _connection: Accepted = $connections.accept().await.unwrap();
+        }
+        start.elapsed()
+    }};
+}
+
+fn accept(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("accept");
+
+    group.bench_function("associated future", |b| {
+        b.to_async(&runtime).iter_custom(|iters| async move {
+            let path = socket_path("accept");
+            let mut connections = UdsConnections::bind(&path).await.unwrap();
+            connect_and_accept!(&path, iters, connections)
+        })
+    });
+
+    group.bench_function("boxed future", |b| {
+        b.to_async(&runtime).iter_custom(|iters| async move {
+            let path = socket_path("accept-boxed");
+            let mut connections = BoxedUdsConnections(UnixListener::bind(&path).unwrap());
+            connect_and_accept!(&path, iters, connections)
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, accept);
+criterion_main!(benches);
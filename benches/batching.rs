@@ -0,0 +1,87 @@
+//! Router throughput with and without batching.
+//!
+//! A single sender pushes messages through the router as fast as it can
+//! (well over a million messages a second), and the time is measured until
+//! the receiver has seen every message.
+//!
+//! Batching only pays off when the router channel fills up faster than the
+//! router can wake up. The price is latency when traffic is light:
+//! a message can sit in the router for up to `max_delay` while the router
+//! waits for the rest of the batch.
+//!
+//! Run with `cargo bench --bench batching`.
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tinyroute::{Message, Router, ToAddress};
+use tokio::runtime::Runtime;
+
+const MESSAGES: u64 = 100_000;
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Address {
+    Sender,
+    Receiver,
+}
+
+impl ToAddress for Address {}
+
+async fn send_and_receive(mut router: Router<Address>) -> Duration {
+    let sender = router.new_agent::<u64>(None, Address::Sender).unwrap();
+    let mut receiver = router.new_agent::<u64>(None, Address::Receiver).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let start = Instant::now();
+    let producer = tokio::spawn(async move {
+        for i in 0..MESSAGES {
+            sender.send(Address::Receiver, i).await.unwrap();
+        }
+        sender
+    });
+
+    for _ in 0..MESSAGES {
+        match receiver.recv().await.unwrap() {
+            Message::Value(_, _) => {}
+            _ => unreachable!(),
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let sender = producer.await.unwrap();
+    sender.shutdown_router().await;
+    handle.await.unwrap();
+    elapsed
+}
+
+fn batching(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("router");
+    group.throughput(Throughput::Elements(MESSAGES));
+
+    group.bench_function("unbatched", |b| {
+        b.to_async(&runtime).iter_custom(|iters| async move {
+            let mut total = Duration::ZERO;
+            for _ in 0..iters {
+                total += send_and_receive(Router::with_capacity(CAPACITY).unwrap()).await;
+            }
+            total
+        })
+    });
+
+    group.bench_function("batched", |b| {
+        b.to_async(&runtime).iter_custom(|iters| async move {
+            let mut total = Duration::ZERO;
+            for _ in 0..iters {
+                let router = Router::new_batched(CAPACITY, 64, Duration::from_micros(50)).unwrap();
+                total += send_and_receive(router).await;
+            }
+            total
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, batching);
+criterion_main!(benches);
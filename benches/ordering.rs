@@ -0,0 +1,95 @@
+//! Router throughput with `DeliveryOrder::Fifo` and `DeliveryOrder::Relaxed`.
+//!
+//! A single sender pushes messages to a number of receivers with small channels,
+//! one of which is slow. With `Fifo` the router waits for the slow receiver,
+//! holding up the others. With `Relaxed` it moves on, at the cost of a task
+//! for every message waiting for room.
+//!
+//! When the slow receiver only yields now and then (as it does here),
+//! the cost of the tasks outweighs the time spent waiting, and `Fifo` comes out ahead.
+//! `Relaxed` pays off once a receiver is slow enough to starve the others.
+//!
+//! Run with `cargo bench --bench ordering`.
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tinyroute::{DeliveryOrder, Message, Router, ToAddress};
+use tokio::runtime::Runtime;
+
+const MESSAGES: u64 = 10_000;
+const RECEIVERS: usize = 4;
+const CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Address {
+    Sender,
+    Receiver(usize),
+}
+
+impl ToAddress for Address {}
+
+async fn send_and_receive(ordering: DeliveryOrder) -> Duration {
+    let mut router = Router::new();
+    router.ordering(ordering);
+    let sender = router.new_agent::<u64>(None, Address::Sender).unwrap();
+    let receivers = (0..RECEIVERS)
+        .map(|id| router.new_agent::<u64>(Some(CAPACITY), Address::Receiver(id)).unwrap())
+        .collect::<Vec<_>>();
+    let handle = tokio::spawn(router.run());
+
+    let start = Instant::now();
+    let consumers = receivers
+        .into_iter()
+        .enumerate()
+        .map(|(id, mut receiver)| {
+            tokio::spawn(async move {
+                for _ in 0..MESSAGES / RECEIVERS as u64 {
+                    match receiver.recv().await.unwrap() {
+                        Message::Value(_, _) => {}
+                        _ => unreachable!(),
+                    }
+                    // The first receiver is slow
+                    if id == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for i in 0..MESSAGES {
+        sender.send(Address::Receiver(i as usize % RECEIVERS), i).await.unwrap();
+    }
+
+    for consumer in consumers {
+        consumer.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    sender.shutdown_router().await;
+    handle.await.unwrap();
+    elapsed
+}
+
+fn ordering(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("router");
+    group.throughput(Throughput::Elements(MESSAGES));
+
+    for (name, ordering) in [("fifo", DeliveryOrder::Fifo), ("relaxed", DeliveryOrder::Relaxed)] {
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter_custom(|iters| async move {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    total += send_and_receive(ordering).await;
+                }
+                total
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, ordering);
+criterion_main!(benches);
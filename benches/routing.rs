@@ -0,0 +1,124 @@
+//! Routing messages between local agents.
+//!
+//! * `single`: one sender, one recipient.
+//! * `fan_out/N`: one sender, sending every message to each of N recipients.
+//! * `senders/K`: K senders on their own tasks, all sending to one recipient.
+//!   The router is a single task, so this shows how it copes with contention
+//!   on its channel rather than how it scales.
+//!
+//! Throughput is in messages delivered.
+//!
+//! Baseline (release build, multi threaded runtime, x86_64 linux):
+//!
+//! | bench        | throughput   |
+//! |--------------|--------------|
+//! | single       | ~2.0M msg/s  |
+//! | fan_out/10   | ~2.2M msg/s  |
+//! | fan_out/100  | ~2.3M msg/s  |
+//! | senders/4    | ~3.1M msg/s  |
+//! | senders/16   | ~3.1M msg/s  |
+//!
+//! Run with `cargo bench --bench routing`.
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tinyroute::{Agent, Message, Router, ToAddress};
+use tokio::runtime::Runtime;
+
+const MESSAGES: usize = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Address {
+    Sender(usize),
+    Receiver(usize),
+}
+
+impl ToAddress for Address {}
+
+async fn receive_all(mut receiver: Agent<usize, Address>, count: usize) {
+    for _ in 0..count {
+        match receiver.recv().await.unwrap() {
+            Message::Value(_, _) => {}
+            _ => unreachable!(),
+        }
+    }
+}
+
+// `senders` senders each send `per_sender` messages to every one of `receivers` receivers
+async fn route(senders: usize, receivers: usize, per_sender: usize) -> Duration {
+    let mut router = Router::new();
+    let sender_agents = (0..senders)
+        .map(|i| router.new_agent::<usize>(None, Address::Sender(i)).unwrap())
+        .collect::<Vec<_>>();
+    let receiver_agents = (0..receivers)
+        .map(|i| router.new_agent::<usize>(None, Address::Receiver(i)).unwrap())
+        .collect::<Vec<_>>();
+    let handle = tokio::spawn(router.run());
+
+    let start = Instant::now();
+    let receiving = receiver_agents
+        .into_iter()
+        .map(|receiver| tokio::spawn(receive_all(receiver, senders * per_sender)))
+        .collect::<Vec<_>>();
+
+    let sending = sender_agents
+        .into_iter()
+        .map(|sender| {
+            tokio::spawn(async move {
+                for i in 0..per_sender {
+                    for r in 0..receivers {
+                        sender.send(Address::Receiver(r), i).await.unwrap();
+                    }
+                }
+                sender
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for receiver in receiving {
+        receiver.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    let mut senders = Vec::new();
+    for sender in sending {
+        senders.push(sender.await.unwrap());
+    }
+    senders[0].shutdown_router().await;
+    handle.await.unwrap();
+    elapsed
+}
+
+fn bench_route(c: &mut Criterion, name: &str, params: &[usize], f: fn(usize) -> (usize, usize, usize)) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group(name);
+    for &param in params {
+        let (senders, receivers, per_sender) = f(param);
+        group.throughput(Throughput::Elements((senders * receivers * per_sender) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(param), &param, |b, _| {
+            b.to_async(&runtime).iter_custom(|iters| async move {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    total += route(senders, receivers, per_sender).await;
+                }
+                total
+            })
+        });
+    }
+    group.finish();
+}
+
+fn single(c: &mut Criterion) {
+    bench_route(c, "routing/single", &[1], |_| (1, 1, MESSAGES));
+}
+
+fn fan_out(c: &mut Criterion) {
+    bench_route(c, "routing/fan_out", &[10, 100], |n| (1, n, MESSAGES / n));
+}
+
+fn senders(c: &mut Criterion) {
+    bench_route(c, "routing/senders", &[4, 16], |k| (k, 1, MESSAGES / k));
+}
+
+criterion_group!(benches, single, fan_out, senders);
+criterion_main!(benches);
@@ -4,7 +4,7 @@ use std::thread;
 use std::env::args;
 use flume::Receiver;
 
-use tinyroute::client::{connect, TcpClient, ClientMessage, ClientReceiver};
+use tinyroute::client::{connect, TcpClient, ClientMessage, ClientReceiver, Heartbeat};
 use tinyroute::frame::{FramedMessage, Frame};
 
 fn input() -> Receiver<FramedMessage> {
@@ -16,7 +16,7 @@ fn input() -> Receiver<FramedMessage> {
 
         loop {
             stdin.read_line(&mut buffer)?;
-            let mut line = buffer.drain(..).collect::<String>();
+            let mut line = std::mem::take(&mut buffer);
             line.pop();
             let framed_msg = Frame::frame_message(line.as_bytes());
             let _ = tx.send(framed_msg);
@@ -29,12 +29,12 @@ fn input() -> Receiver<FramedMessage> {
 async fn run(rx: Receiver<FramedMessage>, port: u16) {
     let addr = format!("127.0.0.1:{}", port);
     let client = TcpClient::connect(addr).await.unwrap();
-    let (write_tx, read_rx) = connect(client, Some(Duration::from_secs(30)));
+    let (write_tx, read_rx) = connect(client, Some(Heartbeat::new(Duration::from_secs(30)))).unwrap();
 
     tokio::spawn(output(read_rx));
 
     while let Ok(bytes) = rx.recv() {
-        if let Err(_) = write_tx.send(ClientMessage::Payload(bytes)) {
+        if write_tx.send(ClientMessage::Payload(bytes)).is_err() {
             break
         }
     }
@@ -52,7 +52,7 @@ async fn output(read_rx: ClientReceiver) -> Option<()> {
 async fn main() {
     pretty_env_logger::init();
 
-    let port = args().skip(1).next().map(|s| s.parse::<u16>().ok()).flatten().unwrap_or(6789);
+    let port = args().nth(1).and_then(|s| s.parse::<u16>().ok()).unwrap_or(6789);
     let rx = input();
     run(rx, port).await;
 }
@@ -4,7 +4,7 @@ use std::thread;
 use std::env::args;
 use flume::Receiver;
 
-use tinyroute::client::{connect, UdsClient, ClientMessage};
+use tinyroute::client::{connect, UdsClient, ClientMessage, Heartbeat};
 use tinyroute::frame::{FramedMessage, Frame};
 
 fn input() -> Receiver<FramedMessage> {
@@ -16,7 +16,7 @@ fn input() -> Receiver<FramedMessage> {
 
         loop {
             stdin.read_line(&mut buffer)?;
-            let mut line = buffer.drain(..).collect::<String>();
+            let mut line = std::mem::take(&mut buffer);
             line.pop();
             let framed_msg = Frame::frame_message(line.as_bytes());
             let _ = tx.send(framed_msg);
@@ -28,12 +28,12 @@ fn input() -> Receiver<FramedMessage> {
 
 async fn run(rx: Receiver<FramedMessage>, addr: String) {
     let client = UdsClient::connect(addr).await.unwrap();
-    let (write_tx, read_rx) = connect(client, Some(Duration::from_secs(30)));
+    let (write_tx, read_rx) = connect(client, Some(Heartbeat::new(Duration::from_secs(30)))).unwrap();
 
     let read_handle = tokio::spawn(output(read_rx));
 
     while let Ok(bytes) = rx.recv() {
-        if let Err(_) = write_tx.send_async(ClientMessage::Payload(bytes)).await {
+        if write_tx.send_async(ClientMessage::Payload(bytes)).await.is_err() {
             break
         }
     }
@@ -53,7 +53,7 @@ async fn output(read_rx: Receiver<Vec<u8>>) -> Option<()> {
 async fn main() {
     pretty_env_logger::init();
 
-    let addr = args().skip(1).next().expect("provide an address");
+    let addr = args().nth(1).expect("provide an address");
     let rx = input();
     run(rx, addr).await;
 }
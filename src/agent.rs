@@ -85,33 +85,52 @@
 //!         Message::Fetch(_) => println!("fetch received"),
 //!         Message::RemoteMessage { bytes, sender, host } => println!("{}@{} sent {} bytes", sender.to_string(), host, bytes.len()),
 //!         Message::Shutdown => break,
-//!         Message::AgentRemoved(address) => println!("Agent {} was removed, and we care", address.to_string()),
+//!         Message::AgentRemoved(address, reason) => println!("Agent {} was removed ({}), and we care", address.to_string(), reason),
+//!         Message::Connected(address) => println!("{} is connected", address.to_string()),
+//!         _ => {}
 //!     }
 //! }
 //! # }
 //! ```
 use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
 use std::fmt::{Debug, Display, Formatter, Result as DisplayResult};
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
+use log::error;
 
 use crate::bridge::BridgeMessageOut;
 use crate::errors::{Error, Result};
-use crate::frame::Frame;
-use crate::router::{Request, RouterMessage, RouterTx, ToAddress};
+use crate::queues::QueueLog;
+use crate::frame::{Frame, FramedMessage};
+use crate::router::{DropReason, Lossy, Queued, Request, RouterMessage, RouterTx, ToAddress};
 use crate::server::ConnectionAddr;
-use flume::Receiver;
+use flume::{Receiver, TryRecvError};
+use futures_core::Stream;
+use rand::seq::SliceRandom;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
 
 // -----------------------------------------------------------------------------
 //     - Any message -
 //     Used to send local messages between agents
 // -----------------------------------------------------------------------------
-pub(crate) struct AnyMessage(pub(crate) Box<dyn Any + Send + 'static>);
+pub(crate) struct AnyMessage(
+    pub(crate) Box<dyn Any + Send + 'static>,
+    // Only held to release the routers queue budget once the message is dropped
+    #[allow(dead_code)] Option<Queued>,
+);
 
 impl AnyMessage {
     pub(crate) fn new<T: Send + 'static>(val: T) -> Self {
-        Self(Box::new(val))
+        Self(Box::new(val), None)
     }
 }
 
@@ -120,6 +139,10 @@ impl AnyMessage {
 //     This is exposed to the end user
 // -----------------------------------------------------------------------------
 /// A message received by an [`Agent`]
+///
+/// New kinds of messages can be added without a major version bump,
+/// so a `match` on a `Message` needs a wildcard arm.
+#[non_exhaustive]
 pub enum Message<T: 'static, A: ToAddress> {
     /// Value containing an instance of T and the address of the sender.
     Value(T, A),
@@ -127,12 +150,43 @@ pub enum Message<T: 'static, A: ToAddress> {
     Fetch(Request),
     /// Bytes received from a socket
     RemoteMessage { bytes: Bytes, sender: A, host: ConnectionAddr },
-    /// A tracked agent was removed
-    AgentRemoved(A),
+    /// A tracked agent was removed, and why
+    AgentRemoved(A, RemovalReason),
+    /// The connection of a [`crate::bridge::Bridge`] at this address
+    /// was established, or reestablished after a reconnect.
+    ///
+    /// This is returned by [`crate::bridge::Bridge::exec`] before anything
+    /// is written to the new connection, so any handshake sent in response
+    /// is written after the messages already queued for the bridge, but before
+    /// anything sent after it.
+    Connected(A),
     /// Close this agent down.
     Shutdown,
 }
 
+/// Why a tracked agent was removed. See [`Message::AgentRemoved`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// The agent was sent a `Message::Shutdown` (e.g with [`Agent::send_shutdown`]
+    /// or a [`crate::server::ConnectionHandle::close`])
+    Shutdown,
+    /// The agent was dropped, or its channel was found closed
+    Dropped,
+    /// The agent was unregistered on purpose: with [`Agent::close`],
+    /// or once the grace period of [`Agent::rename`] was over
+    Unregistered,
+}
+
+impl Display for RemovalReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> DisplayResult {
+        match self {
+            Self::Shutdown => write!(f, "shutdown"),
+            Self::Dropped => write!(f, "dropped"),
+            Self::Unregistered => write!(f, "unregistered"),
+        }
+    }
+}
+
 impl<T: Clone + 'static, A: ToAddress> Clone for Message<T, A> {
     fn clone(&self) -> Self {
         match self {
@@ -145,7 +199,8 @@ impl<T: Clone + 'static, A: ToAddress> Clone for Message<T, A> {
                     host: host.clone(),
                 }
             }
-            Self::AgentRemoved(addr) => Self::AgentRemoved(addr.clone()),
+            Self::AgentRemoved(addr, reason) => Self::AgentRemoved(addr.clone(), *reason),
+            Self::Connected(addr) => Self::Connected(addr.clone()),
             Self::Shutdown => Self::Shutdown,
         }
     }
@@ -165,8 +220,11 @@ impl<T: Display + 'static, A: ToAddress> Display for Message<T, A> {
                 host,
                 bytes.len()
             ),
-            Self::AgentRemoved(addr) => {
-                write!(f, "AgentRemoved<{}>", addr.to_string())
+            Self::AgentRemoved(addr, reason) => {
+                write!(f, "AgentRemoved<{}> ({})", addr.to_string(), reason)
+            }
+            Self::Connected(addr) => {
+                write!(f, "Connected<{}>", addr.to_string())
             }
             Self::Shutdown => write!(f, "Shutdown"),
         }
@@ -189,14 +247,39 @@ impl<T: Debug + 'static, A: ToAddress> Debug for Message<T, A> {
                 host,
                 bytes.len()
             ),
-            Self::AgentRemoved(addr) => {
-                write!(f, "AgentRemoved<{}>", addr.to_string())
+            Self::AgentRemoved(addr, reason) => {
+                write!(f, "AgentRemoved<{}> ({})", addr.to_string(), reason)
+            }
+            Self::Connected(addr) => {
+                write!(f, "Connected<{}>", addr.to_string())
             }
             Self::Shutdown => write!(f, "Shutdown"),
         }
     }
 }
 
+// -----------------------------------------------------------------------------
+//     - Timing -
+// -----------------------------------------------------------------------------
+/// When a message was queued for, and received by, an agent.
+/// See [`Agent::recv_with_timing`].
+#[cfg(feature = "timing")]
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    /// When the router handed the message to the agent
+    pub enqueued_at: Instant,
+    /// When the agent received the message
+    pub received_at: Instant,
+}
+
+#[cfg(feature = "timing")]
+impl Timing {
+    /// How long the message was waiting to be received
+    pub fn queued_for(&self) -> Duration {
+        self.received_at.saturating_duration_since(self.enqueued_at)
+    }
+}
+
 // -----------------------------------------------------------------------------
 //     - Agent message -
 // -----------------------------------------------------------------------------
@@ -204,43 +287,293 @@ pub(crate) enum AgentMsg<A> {
     Message(AnyMessage, A), // A is the address of the sender
     Fetch(Request),
     RemoteMessage(Bytes, A, ConnectionAddr),
-    AgentRemoved(A),
+    AgentRemoved(A, RemovalReason),
     Shutdown,
 }
 
 impl<A: ToAddress> AgentMsg<A> {
-    fn into_local_message<U: 'static>(self) -> Result<Message<U, A>> {
+    // A value that isn't a `U` is handed back, along with the sender
+    fn into_local_message<U: 'static>(self) -> std::result::Result<Message<U, A>, (AnyMessage, A)> {
         match self {
-            Self::Message(val, sender) => match val.0.downcast() {
+            Self::Message(AnyMessage(val, queued), sender) => match val.downcast() {
                 Ok(val) => Ok(Message::Value(*val, sender)),
-                Err(_) => Err(Error::InvalidMessageType),
+                Err(val) => Err((AnyMessage(val, queued), sender)),
             },
             Self::Fetch(request) => Ok(Message::Fetch(request)),
             Self::RemoteMessage(bytes, sender, host) => {
                 Ok(Message::RemoteMessage { bytes, sender, host })
             }
-            Self::AgentRemoved(address) => Ok(Message::AgentRemoved(address)),
+            Self::AgentRemoved(address, reason) => Ok(Message::AgentRemoved(address, reason)),
             Self::Shutdown => Ok(Message::Shutdown),
         }
     }
 }
 
+// Box the value, holding on to a place in the routers limit on queued
+// messages until it's received (or dropped)
+fn queued_message<A: ToAddress, U: Send + 'static>(router_tx: &RouterTx<A>, message: U) -> Result<AnyMessage> {
+    let queued = router_tx.queued()?;
+    Ok(AnyMessage(Box::new(message), queued))
+}
+
+// A message to another agent, counted towards the routers
+// limit on queued messages (see `Router::max_queued`)
+fn local_message<A: ToAddress, U: Send + 'static>(
+    router_tx: &RouterTx<A>,
+    sender: A,
+    recipient: A,
+    message: U,
+) -> Result<RouterMessage<A>> {
+    let msg = queued_message(router_tx, message)?;
+    Ok(RouterMessage::Message { recipient, sender, msg })
+}
+
+// -----------------------------------------------------------------------------
+//     - Inbound -
+//     A message taken off the channel of an agent, once it went through
+//     the auto replies and the inbound transform (see `Agent::receive`).
+// -----------------------------------------------------------------------------
+enum Inbound<T: 'static, A: ToAddress> {
+    Message(Message<T, A>),
+    // A value that isn't a `T`, only `request` and `recv_as_type` can take it
+    Other(AnyMessage, A),
+}
+
+impl<T: 'static, A: ToAddress> Inbound<T, A> {
+    fn into_message(self) -> Result<Message<T, A>> {
+        match self {
+            Self::Message(msg) => Ok(msg),
+            Self::Other(..) => Err(Error::InvalidMessageType),
+        }
+    }
+
+    // The sender, if this is a value
+    fn value_sender(&self) -> Option<&A> {
+        match self {
+            Self::Message(Message::Value(_, sender)) | Self::Other(_, sender) => Some(sender),
+            Self::Message(_) => None,
+        }
+    }
+
+    // The value and the sender if the value is a `U`, otherwise the message is handed back
+    fn downcast<U: 'static>(self) -> std::result::Result<(U, A), Self> {
+        match self {
+            Self::Message(Message::Value(val, sender)) => {
+                let val: Box<dyn Any> = Box::new(val);
+                match val.downcast::<U>() {
+                    Ok(val) => Ok((*val, sender)),
+                    Err(val) => match val.downcast::<T>() {
+                        Ok(val) => Err(Self::Message(Message::Value(*val, sender))),
+                        Err(_) => unreachable!(),
+                    },
+                }
+            }
+            Self::Other(AnyMessage(val, queued), sender) => match val.downcast::<U>() {
+                Ok(val) => Ok((*val, sender)),
+                Err(val) => Err(Self::Other(AnyMessage(val, queued), sender)),
+            },
+            inbound => Err(inbound),
+        }
+    }
+}
+
+// What became of a message taken off the channel
+enum Received<T: 'static, A: ToAddress> {
+    // It was answered by an auto reply, which is yet to be sent
+    // (or can't be, if there are too many queued messages)
+    Reply(Result<RouterMessage<A>>),
+    // The inbound transform dropped it
+    Dropped,
+    // Along with when the router handed it to the agent, if that was recorded
+    Inbound(Inbound<T, A>, Option<Instant>),
+}
+
+// -----------------------------------------------------------------------------
+//     - Auto reply -
+//     Answers messages of a given type without passing them on to the agent.
+//     Hands the message back if it's not the right type.
+// -----------------------------------------------------------------------------
+type AutoReply<A> = Box<dyn Fn(AnyMessage, &A) -> std::result::Result<Result<AnyMessage>, AnyMessage> + Send + Sync>;
+
+// -----------------------------------------------------------------------------
+//     - Unmatched -
+// -----------------------------------------------------------------------------
+/// What [`Agent::recv_as_type`] does with values of another type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Unmatched {
+    /// Hold on to them, so they are returned by the following calls to `recv`
+    /// in the order they were received.
+    Keep,
+    /// Drop them
+    Drop,
+}
+
+// -----------------------------------------------------------------------------
+//     - Agent sender -
+// -----------------------------------------------------------------------------
+/// Send messages on behalf of an agent, from anywhere.
+///
+/// Unlike the [`Agent`] itself this is cheap to clone, so send capability
+/// can be handed to other tasks while the agent keeps receiving.
+/// See [`Agent::sender`] and [`crate::Router::spawn_agent`].
+#[derive(Clone)]
+pub struct AgentSender<A: ToAddress> {
+    router_tx: RouterTx<A>,
+    address: A,
+}
+
+impl<A: ToAddress> AgentSender<A> {
+    /// The address of the agent
+    pub fn address(&self) -> &A {
+        &self.address
+    }
+
+    /// Send a message to another agent, from this agent. See [`Agent::send`].
+    pub async fn send<U: Send + 'static>(&self, recipient: A, message: U) -> Result<()> {
+        let router_msg = local_message(&self.router_tx, self.address.clone(), recipient, message)?;
+        self.router_tx.send(router_msg).await
+    }
+
+    /// Send a message to remote agents, from this agent. See [`Agent::send_remote`].
+    pub async fn send_remote(&self, recipients: impl IntoIterator<Item = A>, message: &[u8]) -> Result<()> {
+        let framed_message = Frame::frame_message(message);
+
+        for recipient in recipients.into_iter() {
+            let router_msg = local_message(&self.router_tx, self.address.clone(), recipient, framed_message.clone())?;
+            self.router_tx.send(router_msg).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Tell the router to shut the agent down.
+    /// The agent receives a `Message::Shutdown`
+    /// (an agent created with [`crate::Router::spawn_agent`] has its handler
+    /// called with it one last time).
+    pub async fn shutdown(&self) -> Result<()> {
+        self.router_tx.send(RouterMessage::Shutdown(self.address.clone())).await
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Capabilities -
+// -----------------------------------------------------------------------------
+/// Proof that an address was allowed by a [`CapPolicy`].
+///
+/// A `Cap` can only be created with [`CapPolicy::mint`], so a function
+/// that takes a `Cap` rather than an address can only be handed addresses
+/// that went through the policy. See [`Agent::send_cap`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Cap<A>(A);
+
+impl<A> Cap<A> {
+    /// The address this capability allows sending to
+    pub fn address(&self) -> &A {
+        &self.0
+    }
+}
+
+/// Decides which addresses a [`Cap`] can be minted for.
+///
+/// ```
+/// use tinyroute::CapPolicy;
+///
+/// #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// enum Address { Log, Metrics, Database }
+///
+/// // Plugins are only allowed to talk to the log and the metrics agents
+/// let plugins = CapPolicy::new(|address| matches!(address, Address::Log | Address::Metrics));
+/// assert!(plugins.mint(Address::Log).is_some());
+/// assert!(plugins.mint(Address::Database).is_none());
+/// ```
+pub struct CapPolicy<A> {
+    allowed: Box<dyn Fn(&A) -> bool + Send + Sync>,
+}
+
+impl<A> CapPolicy<A> {
+    /// Create a policy allowing any address for which `allowed` returns true
+    pub fn new(allowed: impl Fn(&A) -> bool + Send + Sync + 'static) -> Self {
+        Self { allowed: Box::new(allowed) }
+    }
+
+    /// Mint a [`Cap`] for the address, if the policy allows it
+    pub fn mint(&self, address: A) -> Option<Cap<A>> {
+        match (self.allowed)(&address) {
+            true => Some(Cap(address)),
+            false => None,
+        }
+    }
+}
+
 // -----------------------------------------------------------------------------
 //     - Agent -
 // -----------------------------------------------------------------------------
 /// An agent receives messages from the [`crate::router::Router`].
-pub struct Agent<T, A: ToAddress> {
+///
+/// An agent is the only consumer of the messages sent to its address.
+/// It can't be cloned, and every way of receiving a message takes `&mut self`,
+/// so two receives can't run at the same time and a message is never split
+/// between consumers. To send from several tasks, hand out an [`AgentSender`]
+/// (see [`Agent::sender`]) and keep the agent itself on one task.
+///
+/// ```compile_fail,E0599
+/// # use tinyroute::{Agent, ToAddress};
+/// # fn run<A: ToAddress>(agent: Agent<String, A>) {
+/// let other = agent.clone();
+/// # }
+/// ```
+///
+/// ```compile_fail,E0499
+/// # use tinyroute::{Agent, ToAddress};
+/// # async fn run<A: ToAddress>(mut agent: Agent<String, A>) {
+/// let first = agent.recv();
+/// let second = agent.recv();
+/// tokio::join!(first, second);
+/// # }
+/// ```
+pub struct Agent<T: 'static, A: ToAddress> {
     pub(crate) router_tx: RouterTx<A>,
     pub(crate) address: A,
-    rx: Receiver<AgentMsg<A>>,
+    pub(crate) rx: Receiver<AgentMsg<A>>,
+    pub(crate) queue: QueueLog<A>,
+    pub(crate) dropped: Option<Arc<AtomicUsize>>,
+    closed: bool,
+    auto_replies: Vec<AutoReply<A>>,
+    // Messages received while waiting for a reply in `request`.
+    //
+    // Neither a boxed message nor the inbound transform is `Sync`, so both are
+    // behind a mutex to keep the agent `Sync`. They are only changed through
+    // `&mut self` (with `get_mut`), so the lock is never waited on.
+    pending: Mutex<VecDeque<Inbound<T, A>>>,
+    // When the message being handled was received, or `None` while the agent
+    // is waiting for a message (see `Agent::with_watchdog`)
+    watchdog: Option<flume::Sender<Option<tokio::time::Instant>>>,
+    // The tasks of the messages sent with `send_after` that aren't sent yet
+    timers: Mutex<Vec<JoinHandle<()>>>,
+    max_timers: usize,
+    // See `Agent::with_inbound_transform`
+    inbound: Option<Mutex<InboundTransform<T, A>>>,
     _p: PhantomData<T>,
 }
 
-impl<S, A: ToAddress> Drop for Agent<S, A> {
+type InboundTransform<T, A> = Box<dyn FnMut(Message<T, A>) -> Option<Message<T, A>> + Send>;
+
+/// The default for [`Agent::with_max_timers`].
+pub const DEFAULT_MAX_TIMERS: usize = 1_000;
+
+impl<S: 'static, A: ToAddress> Drop for Agent<S, A> {
     fn drop(&mut self) {
+        for timer in self.timers.get_mut().unwrap_or_else(PoisonError::into_inner).drain(..) {
+            timer.abort();
+        }
+
+        if self.closed {
+            return;
+        }
+
         let _ = self
             .router_tx
-            .send_sync(RouterMessage::Unregister(self.address.clone()));
+            .send_detached(RouterMessage::Unregister(self.address.clone(), RemovalReason::Dropped));
     }
 }
 
@@ -250,11 +583,28 @@ impl<T: Send + 'static, A: ToAddress> Agent<T, A> {
         address: A,
         rx: Receiver<AgentMsg<A>>,
     ) -> Self {
-        Self { router_tx, rx, address, _p: PhantomData }
+        Self {
+            router_tx,
+            rx,
+            address,
+            queue: QueueLog::new(),
+            dropped: None,
+            closed: false,
+            auto_replies: Vec::new(),
+            pending: Mutex::new(VecDeque::new()),
+            watchdog: None,
+            timers: Mutex::new(Vec::new()),
+            max_timers: DEFAULT_MAX_TIMERS,
+            inbound: None,
+            _p: PhantomData,
+        }
     }
 
     /// Create a new agent and register it with the router.
     ///
+    /// Returns [`Error::RouterGone`] if the router is shut down,
+    /// or is shutting down, rather than an agent no one can reach.
+    ///
     /// ```
     /// # use tinyroute::{Agent, ToAddress};
     /// #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -284,38 +634,189 @@ impl<T: Send + 'static, A: ToAddress> Agent<T, A> {
         };
         let agent =
             Agent::new(self.router_tx.clone(), address.clone(), transport_rx);
-        self.router_tx.register_agent(address, transport_tx).await?;
+        self.router_tx.register_agent(address, transport_tx, agent.queue.clone(), None).await?;
         Ok(agent)
     }
 
+    /// Create a new agent that keeps the newest `cap` messages,
+    /// and register it with the router.
+    /// See [`crate::Router::new_lossy_agent`] for more information.
+    ///
+    /// Returns [`Error::InvalidCapacity`] if `cap` is zero.
+    pub async fn new_lossy_agent<U: Send + 'static>(
+        &self,
+        cap: usize,
+        address: A,
+    ) -> Result<Agent<U, A>> {
+        if cap == 0 {
+            return Err(Error::InvalidCapacity);
+        }
+        let (transport_tx, transport_rx) = flume::bounded(cap);
+        let mut agent =
+            Agent::new(self.router_tx.clone(), address.clone(), transport_rx);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let lossy = Lossy::new(agent.rx.clone(), dropped.clone());
+        agent.dropped = Some(dropped);
+        self.router_tx.register_agent(address, transport_tx, agent.queue.clone(), Some(lossy)).await?;
+        Ok(agent)
+    }
+
+    /// The number of messages dropped to make room for newer messages.
+    /// This is always zero unless the agent was created as a lossy agent.
+    pub fn dropped(&self) -> usize {
+        match self.dropped {
+            Some(ref dropped) => dropped.load(Ordering::Relaxed),
+            None => 0,
+        }
+    }
+
     pub fn router_tx(&self) -> RouterTx<A> {
         self.router_tx.clone()
     }
 
+    /// A sender to send messages on behalf of this agent
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # async fn run<A: ToAddress + Sync>(mut agent: Agent<String, A>, other: A) {
+    /// let sender = agent.sender();
+    /// tokio::spawn(async move {
+    ///     sender.send(other, "hello".to_string()).await.unwrap();
+    /// });
+    ///
+    /// while let Ok(message) = agent.recv().await {
+    ///     // ...
+    /// }
+    /// # }
+    /// ```
+    pub fn sender(&self) -> AgentSender<A> {
+        AgentSender { router_tx: self.router_tx.clone(), address: self.address.clone() }
+    }
+
     /// Track an agent (or more precisely an address).
     /// If the address is unregistered, the tracking agent will
     /// receive a `Message::AgentRemoved(tracked_address)`.
+    ///
+    /// Returns [`Error::TooManyTracks`] if this agent is already tracking
+    /// as many addresses as the router allows (see [`crate::Router::max_tracks`]).
     pub async fn track(&self, address: A) -> Result<()> {
-        self.router_tx
-            .send(RouterMessage::Track {
-                from: self.address.clone(),
-                to: address,
-            })
-            .await?;
-        Ok(())
+        self.router_tx.track(self.address.clone(), address).await
+    }
+
+    /// Wait at the barrier called `name` until `expected` agents
+    /// (this one included) have arrived, e.g for a leader to wait for its workers
+    /// to be ready before starting.
+    ///
+    /// All the agents at the barrier are released at once,
+    /// after which the barrier can be used again.
+    /// Every agent should arrive with the same `expected` count:
+    /// the count of the first agent to arrive is the one used.
+    ///
+    /// Returns [`Error::RouterGone`] if the router shuts down before the barrier is released.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # use tinyroute::errors::Result;
+    /// # async fn run<A: ToAddress>(worker: Agent<(), A>) -> Result<()> {
+    /// // one leader and two workers
+    /// worker.arrive_barrier("startup", 3).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn arrive_barrier(&self, name: impl Into<String>, expected: usize) -> Result<()> {
+        self.router_tx.arrive_barrier(name.into(), self.address.clone(), expected).await
+    }
+
+    /// Stop tracking an address tracked with [`Agent::track`].
+    /// This agent will no longer receive a `Message::AgentRemoved` for it.
+    ///
+    /// Untracking an address that isn't tracked does nothing.
+    pub async fn untrack(&self, address: A) -> Result<()> {
+        self.router_tx.send(RouterMessage::Untrack { from: self.address.clone(), to: address }).await
+    }
+
+    /// Subscribe this agent to `topic`, to receive every value published
+    /// to it with [`Agent::publish`].
+    ///
+    /// The subscription lasts until [`Agent::unsubscribe`] is called,
+    /// or the agent is removed. Subscribing more than once does nothing.
+    pub async fn subscribe(&self, topic: impl Into<String>) -> Result<()> {
+        self.router_tx.send(RouterMessage::Subscribe { topic: topic.into(), subscriber: self.address.clone() }).await
+    }
+
+    /// Stop receiving the values published to `topic`.
+    pub async fn unsubscribe(&self, topic: impl Into<String>) -> Result<()> {
+        self.router_tx.send(RouterMessage::Unsubscribe { topic: topic.into(), subscriber: self.address.clone() }).await
+    }
+
+    /// Send `value` to every agent subscribed to `topic`, as a `Message::Value`
+    /// from this agent (this agent included, if it's subscribed).
+    ///
+    /// Every subscriber gets its own clone of the value, so a value that is
+    /// expensive to clone is best shared, e.g behind an `Arc`.
+    /// The values are sent as is, nothing is serialized.
+    ///
+    /// Returns the number of subscribers the value was delivered to.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # use tinyroute::errors::Result;
+    /// # async fn run<A: ToAddress>(agent: Agent<(), A>) -> Result<()> {
+    /// let delivered = agent.publish("config", "reload".to_string()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn publish<U: Send + Clone + 'static>(&self, topic: impl Into<String>, value: U) -> Result<usize> {
+        let subscribers = self.subscribers(topic.into()).await?;
+        self.publish_to(subscribers, value).await
+    }
+
+    /// Like [`Agent::publish`], but only to a random sample of the subscribers,
+    /// e.g to sample metrics or for a canary push.
+    ///
+    /// The sample is `fraction` of the subscribers (clamped to `0.0..=1.0`),
+    /// rounded to the nearest subscriber. A new sample is picked on every call,
+    /// so the same subscribers are not picked every time.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # use tinyroute::errors::Result;
+    /// # async fn run<A: ToAddress>(agent: Agent<(), A>) -> Result<()> {
+    /// // One in ten subscribers
+    /// agent.publish_sampled("metrics", "report".to_string(), 0.1).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn publish_sampled<U: Send + Clone + 'static>(
+        &self,
+        topic: impl Into<String>,
+        value: U,
+        fraction: f64,
+    ) -> Result<usize> {
+        let subscribers = self.subscribers(topic.into()).await?;
+        let amount = (subscribers.len() as f64 * fraction.clamp(0.0, 1.0)).round() as usize;
+        let sample = subscribers.choose_multiple(&mut rand::thread_rng(), amount).cloned().collect();
+        self.publish_to(sample, value).await
+    }
+
+    async fn subscribers(&self, topic: String) -> Result<Vec<A>> {
+        let (reply_tx, reply_rx) = flume::bounded(1);
+        self.router_tx.send(RouterMessage::Subscribers { topic, reply_tx }).await?;
+        reply_rx.recv_async().await.map_err(|_| Error::RouterUnrecoverableError)
+    }
+
+    // Returns the number of subscribers the value was delivered to
+    async fn publish_to<U: Send + Clone + 'static>(&self, subscribers: Vec<A>, value: U) -> Result<usize> {
+        let count = subscribers.len();
+        let failed = self.send_all_undelivered(subscribers, value).await?;
+        Ok(count - failed.len())
     }
 
     /// Tell one address to track this agents address.
     /// If this agents address is unregistered, the tracking agent will
     /// receive a `Message::AgentRemoved(tracked_address)`.
     pub async fn reverse_track(&self, address: A) -> Result<()> {
-        self.router_tx
-            .send(RouterMessage::Track {
-                from: address,
-                to: self.address.clone(),
-            })
-            .await?;
-        Ok(())
+        self.router_tx.track(address, self.address.clone()).await
     }
 
     /// The agents address
@@ -323,31 +824,760 @@ impl<T: Send + 'static, A: ToAddress> Agent<T, A> {
         &self.address
     }
 
+    /// The number of messages waiting to be received,
+    /// including any held on to by [`Agent::request`] or [`Agent::recv_as_type`].
+    pub fn pending(&self) -> usize {
+        let held = self.pending.lock().unwrap_or_else(PoisonError::into_inner).len();
+        held + self.rx.len()
+    }
+
+    /// The number of messages that can be queued for the agent before
+    /// the router has to wait for room (or drops the oldest message, for a lossy agent).
+    /// The capacity of an unbounded agent is `usize::MAX`.
+    pub fn capacity(&self) -> usize {
+        self.rx.capacity().unwrap_or(usize::MAX)
+    }
+
+    /// Move the agent to a new address.
+    ///
+    /// For the duration of `grace` the agent is registered at both the old
+    /// and the new address, so messages sent to the old address are still received.
+    /// Once the grace period is over the old address is unregistered,
+    /// and any agent tracking the old address receives a `Message::AgentRemoved`.
+    ///
+    /// Tracking is not moved to the new address.
+    pub async fn rename(&mut self, address: A, grace: Duration) -> Result<()> {
+        self.router_tx.register_alias(self.address.clone(), address.clone()).await?;
+        let old_address = std::mem::replace(&mut self.address, address);
+
+        let router_tx = self.router_tx.clone();
+        tokio::spawn(async move {
+            sleep(grace).await;
+            let _ = router_tx.send(RouterMessage::Unregister(old_address, RemovalReason::Unregistered)).await;
+        });
+
+        Ok(())
+    }
+
+    /// Answer every message of type `M` sent to this agent with the
+    /// result of `f`, rather than returning it from `recv`.
+    ///
+    /// `f` is called with the message and the address of the sender,
+    /// and the reply is sent back to the sender.
+    /// The reply counts towards [`crate::Router::max_queued`] like any other message,
+    /// and is dropped (logging an error) if the limit has been reached.
+    /// Messages are matched by downcasting them to `M`, so `M` has to be the
+    /// exact type that was sent (a `&str` will not match a `String`).
+    /// If there is more than one auto reply for the same type,
+    /// the first one registered is used.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// struct Ping;
+    /// struct Pong;
+    ///
+    /// # fn run<A: ToAddress>(mut agent: Agent<String, A>) {
+    /// agent.auto_reply_to(|_ping: Ping, _sender| Pong);
+    /// # }
+    /// ```
+    pub fn auto_reply_to<M, R, F>(&mut self, f: F)
+    where
+        M: Send + 'static,
+        R: Send + 'static,
+        F: Fn(M, &A) -> R + Send + Sync + 'static,
+    {
+        let router_tx = self.router_tx.clone();
+        self.auto_replies.push(Box::new(move |AnyMessage(val, queued), sender| match val.downcast::<M>() {
+            Ok(msg) => {
+                // The message is received, so it makes room for the reply
+                drop(queued);
+                Ok(queued_message(&router_tx, f(*msg, sender)))
+            }
+            Err(val) => Err(AnyMessage(val, queued)),
+        }));
+    }
+
+    // The auto reply to a message, or the message itself if there is no auto reply for it.
+    // The reply counts towards the routers limit on queued messages, like any other message
+    fn auto_reply(&self, msg: AgentMsg<A>) -> std::result::Result<Result<RouterMessage<A>>, AgentMsg<A>> {
+        let (mut val, sender) = match msg {
+            AgentMsg::Message(val, sender) if !self.auto_replies.is_empty() => (val, sender),
+            msg => return Err(msg),
+        };
+
+        for auto_reply in &self.auto_replies {
+            match auto_reply(val, &sender) {
+                Ok(reply) => {
+                    return Ok(reply.map(|msg| RouterMessage::Message {
+                        recipient: sender,
+                        sender: self.address.clone(),
+                        msg,
+                    }))
+                }
+                Err(unmatched) => val = unmatched,
+            }
+        }
+
+        Err(AgentMsg::Message(val, sender))
+    }
+
+    // Every message taken off the channel goes through here, however it's received:
+    // it's either answered by an auto reply, or passed through the inbound transform
+    fn receive(&mut self, msg: AgentMsg<A>) -> Received<T, A> {
+        let enqueued_at = self.queue.pop();
+        let msg = match self.auto_reply(msg) {
+            Ok(reply) => return Received::Reply(reply),
+            Err(msg) => msg,
+        };
+
+        match msg.into_local_message() {
+            Ok(msg) => match self.transform_inbound(msg) {
+                Some(msg) => Received::Inbound(Inbound::Message(msg), enqueued_at),
+                None => Received::Dropped,
+            },
+            Err((val, sender)) => Received::Inbound(Inbound::Other(val, sender), enqueued_at),
+        }
+    }
+
+    // The next message, starting with the ones held on to if `held` is true,
+    // along with when the router handed it to the agent (see `Agent::receive`).
+    // The agent is handling a message (see `Agent::with_watchdog`) once this returns
+    async fn next_inbound(&mut self, held: bool) -> Result<(Inbound<T, A>, Option<Instant>)> {
+        self.reset_watchdog(None);
+        let next = match held.then(|| self.pending_mut().pop_front()).flatten() {
+            Some(inbound) => (inbound, None),
+            None => loop {
+                let msg = self.rx.recv_async().await.map_err(|_| Error::ChannelClosed)?;
+                match self.receive(msg) {
+                    Received::Reply(reply) => {
+                        let sent = match reply {
+                            Ok(reply) => self.router_tx.send(reply).await,
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = sent {
+                            error!("Failed to send an auto reply: {}", e);
+                        }
+                    }
+                    Received::Dropped => {}
+                    Received::Inbound(inbound, enqueued_at) => break (inbound, enqueued_at),
+                }
+            },
+        };
+        self.reset_watchdog(Some(tokio::time::Instant::now()));
+        Ok(next)
+    }
+
+    // Like `next_inbound`, but blocking the thread while waiting,
+    // or without waiting at all (returning `None` if nothing is queued) if `wait` is false
+    fn next_inbound_sync(&mut self, wait: bool) -> Result<Option<Inbound<T, A>>> {
+        self.reset_watchdog(None);
+        let inbound = match self.pending_mut().pop_front() {
+            Some(inbound) => inbound,
+            None => loop {
+                let msg = match wait {
+                    true => self.rx.recv().map_err(|_| Error::ChannelClosed)?,
+                    false => match self.rx.try_recv() {
+                        Ok(msg) => msg,
+                        Err(TryRecvError::Empty) => return Ok(None),
+                        Err(TryRecvError::Disconnected) => return Err(Error::ChannelClosed),
+                    },
+                };
+                match self.receive(msg) {
+                    Received::Reply(reply) => {
+                        let sent = match (reply, wait) {
+                            (Ok(reply), true) => self.router_tx.send_sync(reply),
+                            (Ok(reply), false) => self.router_tx.send_detached(reply),
+                            (Err(e), _) => Err(e),
+                        };
+                        if let Err(e) = sent {
+                            error!("Failed to send an auto reply: {}", e);
+                        }
+                    }
+                    Received::Dropped => {}
+                    Received::Inbound(inbound, _) => break inbound,
+                }
+            },
+        };
+        self.reset_watchdog(Some(tokio::time::Instant::now()));
+        Ok(Some(inbound))
+    }
+
     pub async fn recv(&mut self) -> Result<Message<T, A>> {
-        let msg =
-            self.rx.recv_async().await.map_err(|_| Error::ChannelClosed)?;
-        msg.into_local_message()
+        let (inbound, _) = self.next_inbound(true).await?;
+        inbound.into_message()
+    }
+
+    /// Call `on_stall` if the agent takes longer than `timeout` to handle a message:
+    /// from the moment [`Agent::recv`] returns a message until `recv` is called again.
+    /// `on_stall` is called at most once per message, e.g to log, dump state,
+    /// or shut the agent down.
+    ///
+    /// This is reset by every way of receiving a message: `recv` and the methods
+    /// built on it (`recv_timeout`, `recv_many`, `recv_or_shutdown`, an [`AgentStream`] etc.),
+    /// `try_recv`, `recv_sync`, [`Agent::request`] and [`Agent::recv_as_type`].
+    /// An agent waiting for a message never stalls, however long it waits.
+    ///
+    /// The watchdog is a timer on a task of its own, spawned on the current tokio runtime.
+    /// It detects a handler that takes too long between receives, but it can't
+    /// fire while a handler blocks the only thread of a `current_thread` runtime.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # async fn run<A: ToAddress>(agent: Agent<String, A>) {
+    /// let mut agent = agent.with_watchdog(Duration::from_secs(5), |address| {
+    ///     eprintln!("{} is stuck handling a message", address.to_string());
+    /// });
+    /// # }
+    /// ```
+    pub fn with_watchdog(mut self, timeout: Duration, on_stall: impl Fn(&A) + Send + 'static) -> Self {
+        let (tx, rx) = flume::unbounded();
+        let address = self.address.clone();
+        tokio::spawn(async move {
+            let mut handling = None;
+            loop {
+                let update = match handling {
+                    None => rx.recv_async().await,
+                    Some(received) => match tokio::time::timeout_at(received + timeout, rx.recv_async()).await {
+                        Ok(update) => update,
+                        Err(_) => {
+                            on_stall(&address);
+                            Ok(None)
+                        }
+                    },
+                };
+
+                match update {
+                    Ok(update) => handling = update,
+                    // The agent is gone
+                    Err(_) => break,
+                }
+            }
+        });
+
+        self.watchdog = Some(tx);
+        self
+    }
+
+    fn reset_watchdog(&self, received: Option<tokio::time::Instant>) {
+        if let Some(watchdog) = &self.watchdog {
+            let _ = watchdog.send(received);
+        }
+    }
+
+    /// Like [`Agent::recv`], but a closed channel is returned as `Message::Shutdown`
+    /// rather than [`Error::ChannelClosed`], so a single match arm can handle both.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, Message, ToAddress};
+    /// # async fn run<A: ToAddress>(mut agent: Agent<String, A>) {
+    /// while let Ok(msg) = agent.recv_or_shutdown().await {
+    ///     match msg {
+    ///         Message::Shutdown => break,
+    ///         _ => { /* handle the message */ }
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub async fn recv_or_shutdown(&mut self) -> Result<Message<T, A>> {
+        match self.recv().await {
+            Err(Error::ChannelClosed) => Ok(Message::Shutdown),
+            res => res,
+        }
+    }
+
+    /// Receive messages through a [`Stream`], to use stream combinators
+    /// (`filter`, `map`, `take` etc.) on them. See [`AgentStream`].
+    ///
+    /// ```
+    /// use futures_util::StreamExt;
+    /// # use tinyroute::{Agent, Message, ToAddress};
+    /// # async fn run<A: ToAddress>(agent: Agent<String, A>) {
+    /// let mut values = Box::pin(agent.into_stream().filter_map(|msg| async move {
+    ///     match msg {
+    ///         Ok(Message::Value(value, _)) => Some(value),
+    ///         _ => None,
+    ///     }
+    /// }));
+    ///
+    /// while let Some(value) = values.next().await {
+    ///     println!("{}", value);
+    /// }
+    /// # }
+    /// ```
+    pub fn into_stream(self) -> AgentStream<T, A> {
+        AgentStream { agent: Some(self), recv: None }
+    }
+
+    /// Receive the next value of type `U`, from any sender,
+    /// skipping values of any other type.
+    ///
+    /// Values of another type are kept or dropped according to `unmatched`.
+    /// Any other message (`Message::AgentRemoved`, `Message::Fetch` etc.) is always kept,
+    /// and returned by the following calls to `recv` (or `try_recv` etc.),
+    /// ahead of anything still queued.
+    /// Messages kept earlier (e.g by [`Agent::request`]) are looked at first.
+    ///
+    /// Returns [`Error::ChannelClosed`] on `Message::Shutdown`
+    /// without waiting for a value, and keeps the shutdown message
+    /// for the next call to `recv`.
+    pub async fn recv_as_type<U: 'static>(&mut self, unmatched: Unmatched) -> Result<(U, A)> {
+        let mut kept = std::mem::take(self.pending_mut());
+        while let Some(inbound) = kept.pop_front() {
+            if let Some(res) = self.match_type(inbound, unmatched) {
+                self.pending_mut().append(&mut kept);
+                self.reset_watchdog(Some(tokio::time::Instant::now()));
+                return res;
+            }
+        }
+
+        loop {
+            let (inbound, _) = self.next_inbound(false).await?;
+            if let Some(res) = self.match_type(inbound, unmatched) {
+                break res;
+            }
+        }
+    }
+
+    // Take the value if it's a `U`, or hold on to the message (see `recv_as_type`)
+    fn match_type<U: 'static>(&mut self, inbound: Inbound<T, A>, unmatched: Unmatched) -> Option<Result<(U, A)>> {
+        match inbound {
+            Inbound::Message(Message::Shutdown) => {
+                self.pending_mut().push_back(Inbound::Message(Message::Shutdown));
+                Some(Err(Error::ChannelClosed))
+            }
+            inbound if inbound.value_sender().is_some() => match inbound.downcast::<U>() {
+                Ok(value) => Some(Ok(value)),
+                Err(inbound) => {
+                    if unmatched == Unmatched::Keep {
+                        self.pending_mut().push_back(inbound);
+                    }
+                    None
+                }
+            },
+            inbound => {
+                self.pending_mut().push_back(inbound);
+                None
+            }
+        }
+    }
+
+    /// Send a message to `recipient` and wait for the reply:
+    /// the first value of type `R` from `recipient`.
+    ///
+    /// Anything else received in the meantime is held on to, and returned by
+    /// the following calls to `recv` (or `try_recv` etc.) in the order it was received,
+    /// ahead of anything still queued.
+    ///
+    /// There is no correlation beyond the sender and the type of the reply,
+    /// so the recipient has to reply to requests in the order it receives them,
+    /// and not send other values of type `R` to this agent in between.
+    ///
+    /// Returns [`Error::ChannelClosed`] if the agent is shut down while waiting.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # use tinyroute::errors::Result;
+    /// # async fn run<A: ToAddress>(mut agent: Agent<(), A>, counter: A) -> Result<()> {
+    /// let count: usize = agent.request(counter, "count").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn request<U: Send + 'static, R: 'static>(&mut self, recipient: A, msg: U) -> Result<R> {
+        self.send(recipient.clone(), msg).await?;
+
+        loop {
+            let (inbound, _) = self.next_inbound(false).await?;
+            match inbound {
+                Inbound::Message(Message::Shutdown) => {
+                    self.pending_mut().push_back(Inbound::Message(Message::Shutdown));
+                    break Err(Error::ChannelClosed);
+                }
+                inbound if inbound.value_sender() == Some(&recipient) => match inbound.downcast::<R>() {
+                    Ok((reply, _)) => break Ok(reply),
+                    Err(inbound) => self.pending_mut().push_back(inbound),
+                },
+                inbound => self.pending_mut().push_back(inbound),
+            }
+        }
+    }
+
+    /// Receive a message, waiting at most `dur`.
+    ///
+    /// Returns [`Error::Timeout`] if no message arrived in time.
+    /// A timeout leaves the queue as it was: a message arriving once
+    /// the timeout has fired is received by the next call to `recv`.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # use tinyroute::errors::Error;
+    /// # async fn run<A: ToAddress>(mut agent: Agent<String, A>) {
+    /// match agent.recv_timeout(Duration::from_secs(1)).await {
+    ///     Ok(reply) => { /* handle the reply */ }
+    ///     Err(Error::Timeout) => println!("no reply"),
+    ///     Err(e) => println!("failed to receive: {}", e),
+    /// }
+    /// # }
+    /// ```
+    pub async fn recv_timeout(&mut self, dur: Duration) -> Result<Message<T, A>> {
+        timeout(dur, self.recv()).await.map_err(|_| Error::Timeout)?
+    }
+
+    /// Receive a message if one is already queued, without waiting.
+    ///
+    /// Returns `Ok(None)` if there is nothing queued.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # fn run<A: ToAddress>(mut agent: Agent<(), A>) {
+    /// // Drain the queue before doing anything else
+    /// while let Ok(Some(message)) = agent.try_recv() {
+    ///     // handle the message
+    /// }
+    /// # }
+    /// ```
+    pub fn try_recv(&mut self) -> Result<Option<Message<T, A>>> {
+        self.next_inbound_sync(false)?.map(Inbound::into_message).transpose()
+    }
+
+    /// Wait for a message, then receive up to `max - 1` more without waiting,
+    /// returning as soon as nothing more is queued.
+    ///
+    /// Returns [`Error::ChannelClosed`] only if the channel is closed before
+    /// the first message. Once the first message is received,
+    /// a closed channel just ends the batch.
+    ///
+    /// With a `max` of zero nothing is received, and an empty `Vec` is returned right away.
+    pub async fn recv_many(&mut self, max: usize) -> Result<Vec<Message<T, A>>> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut messages = Vec::with_capacity(max.min(self.rx.len() + 1));
+        messages.push(self.recv().await?);
+        while messages.len() < max {
+            match self.try_recv() {
+                Ok(Some(msg)) => messages.push(msg),
+                Ok(None) | Err(Error::ChannelClosed) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Receive a message, along with the number of messages
+    /// still queued for this agent.
+    pub async fn recv_with_backlog(&mut self) -> Result<(Message<T, A>, usize)> {
+        let msg = self.recv().await?;
+        Ok((msg, self.pending_mut().len() + self.rx.len()))
     }
 
     pub fn recv_sync(&mut self) -> Result<Message<T, A>> {
-        let msg = self.rx.recv().map_err(|_| Error::ChannelClosed)?;
-        msg.into_local_message()
+        match self.next_inbound_sync(true)? {
+            Some(inbound) => inbound.into_message(),
+            // Only returned when not waiting
+            None => Err(Error::ChannelClosed),
+        }
     }
 
+    /// Receive a message, along with when the router handed it to
+    /// this agent and when it was received.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # async fn run<A: ToAddress>(mut agent: Agent<(), A>) {
+    /// let (message, timing) = agent.recv_with_timing().await.unwrap();
+    /// println!("Queued for {:?}", timing.queued_for());
+    /// # }
+    /// ```
+    ///
+    /// Requires the `timing` feature.
+    #[cfg(feature = "timing")]
+    pub async fn recv_with_timing(&mut self) -> Result<(Message<T, A>, Timing)> {
+        let (inbound, enqueued_at) = self.next_inbound(true).await?;
+        let received_at = Instant::now();
+        // Messages held on to by `request` are timed from when they're returned
+        let enqueued_at = enqueued_at.unwrap_or(received_at);
+        Ok((inbound.into_message()?, Timing { enqueued_at, received_at }))
+    }
+
+    // Shutdown is never passed to the transform, so it can't be dropped
+    fn transform_inbound(&mut self, msg: Message<T, A>) -> Option<Message<T, A>> {
+        if let Message::Shutdown = msg {
+            return Some(msg);
+        }
+        match &mut self.inbound {
+            Some(transform) => (transform.get_mut().unwrap_or_else(PoisonError::into_inner))(msg),
+            None => Some(msg),
+        }
+    }
+
+    fn pending_mut(&mut self) -> &mut VecDeque<Inbound<T, A>> {
+        self.pending.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn local_message<U: Send + 'static>(&self, recipient: A, message: U) -> Result<RouterMessage<A>> {
+        local_message(&self.router_tx, self.address.clone(), recipient, message)
+    }
+
+    /// Send a message to another agent via the router.
+    ///
+    /// If the router was created with [`crate::Router::with_capacity`]
+    /// and the router channel is full, this waits until there is room.
+    /// A router created with [`crate::Router::new`] never makes the sender wait.
+    ///
+    /// Don't wait on `send` in an agent that other agents are waiting to send to:
+    /// with a bounded router and bounded agents, two agents sending to each other
+    /// faster than they receive can end up with the router waiting for room in one
+    /// of the agents, while that agent waits for room in the router.
+    /// Use [`Agent::try_send_or_keep`] there, and receive while the router is busy.
+    ///
+    /// Returns [`Error::QueuesFull`] if the router has a limit on
+    /// queued messages (see [`crate::Router::max_queued`]) and it's been reached.
     pub async fn send<U: Send + 'static>(
         &self,
         recipient: A,
         message: U,
     ) -> Result<()> {
-        let router_msg = RouterMessage::Message {
+        let router_msg = self.local_message(recipient, message)?;
+        self.router_tx.send(router_msg).await?;
+        Ok(())
+    }
+
+    /// Send a message like [`Agent::send`], calling `on_fail` with the reason
+    /// if the router can't deliver it: there is no agent at `recipient`
+    /// ([`DropReason::NoRecipient`]), or the recipient is gone ([`DropReason::RecipientGone`]).
+    ///
+    /// The router takes `on_fail` along with the message, and calls it from the router
+    /// loop (so it should return quickly), or drops it once the message is delivered.
+    /// A message sent with [`crate::DeliveryOrder::Relaxed`] to a recipient with a full
+    /// channel counts as delivered once it's handed to the task waiting for room.
+    /// If the router itself is gone an error is returned instead, and `on_fail` isn't called.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # use tinyroute::errors::Result;
+    /// # async fn run<A: ToAddress>(agent: Agent<(), A>, logger: A) -> Result<()> {
+    /// agent.send_with_on_fail(logger, "hello".to_string(), |reason| {
+    ///     eprintln!("The logger didn't get the message: {:?}", reason);
+    /// }).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_with_on_fail<U: Send + 'static>(
+        &self,
+        recipient: A,
+        message: U,
+        on_fail: impl FnOnce(DropReason) + Send + 'static,
+    ) -> Result<()> {
+        let msg = queued_message(&self.router_tx, message)?;
+        let router_msg = RouterMessage::MessageOnFail {
             recipient,
             sender: self.address.clone(),
-            msg: AnyMessage::new(message),
+            msg,
+            on_fail: Box::new(on_fail),
         };
-        self.router_tx.send(router_msg).await?;
+        self.router_tx.send(router_msg).await
+    }
+
+    /// Send a message to `recipient` once `delay` has passed,
+    /// without waiting for it.
+    ///
+    /// The message counts towards [`crate::Router::max_queued`] from the moment
+    /// `send_after` is called, and keeps the router running (see [`crate::Router`])
+    /// until it's sent.
+    /// If the router is gone by the time the delay has passed, the message is dropped.
+    ///
+    /// Every message waits on a task of its own. At most [`DEFAULT_MAX_TIMERS`]
+    /// messages (see [`Agent::with_max_timers`]) can be waiting at once,
+    /// after that [`Error::TooManyTimers`] is returned.
+    /// Dropping the agent drops the messages that are still waiting, unsent.
+    pub fn send_after<U: Send + 'static>(&self, recipient: A, message: U, delay: Duration) -> Result<()> {
+        let mut timers = self.timers.lock().unwrap_or_else(PoisonError::into_inner);
+        timers.retain(|timer| !timer.is_finished());
+        if timers.len() >= self.max_timers {
+            return Err(Error::TooManyTimers);
+        }
+
+        let router_msg = self.local_message(recipient, message)?;
+        let router_tx = self.router_tx.clone();
+        timers.push(tokio::spawn(async move {
+            sleep(delay).await;
+            let _ = router_tx.send(router_msg).await;
+        }));
         Ok(())
     }
 
+    /// Allow at most `max` messages sent with [`Agent::send_after`]
+    /// to be waiting at once (defaults to [`DEFAULT_MAX_TIMERS`]).
+    pub fn with_max_timers(mut self, max: usize) -> Self {
+        self.max_timers = max;
+        self
+    }
+
+    /// Pass every received message through `transform` before it's returned
+    /// by [`Agent::recv`] or any other way of receiving a message
+    /// (a reply to [`Agent::request`] included).
+    /// Returning `None` drops the message, and the agent waits for the next one.
+    ///
+    /// `Message::Shutdown` is never passed to the transform,
+    /// and auto replies (see [`Agent::auto_reply_to`]) are sent before it.
+    /// Values that aren't a `T` can't be passed to it either, those are only
+    /// ever taken by `request` or [`Agent::recv_as_type`].
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, Message, ToAddress};
+    /// # async fn run<A: ToAddress>(agent: Agent<u32, A>, blocked: A) {
+    /// let mut agent = agent.with_inbound_transform(move |msg| match msg {
+    ///     Message::Value(_, sender) if sender == blocked => None,
+    ///     msg => Some(msg),
+    /// });
+    /// # }
+    /// ```
+    pub fn with_inbound_transform(
+        mut self,
+        transform: impl FnMut(Message<T, A>) -> Option<Message<T, A>> + Send + 'static,
+    ) -> Self {
+        self.inbound = Some(Mutex::new(Box::new(transform)));
+        self
+    }
+
+    /// Send a copy of the message to each of the recipients.
+    ///
+    /// Every recipient is sent a copy even if some of them can't be delivered to,
+    /// in which case [`Error::Undelivered`] lists the addresses
+    /// (see [`ToAddress::to_string`]) of the ones that weren't.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # use tinyroute::errors::Result;
+    /// # async fn run<A: ToAddress>(agent: Agent<(), A>, workers: Vec<A>) -> Result<()> {
+    /// agent.send_all(workers, "reload".to_string()).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_all<U: Send + Clone + 'static>(
+        &self,
+        recipients: impl IntoIterator<Item = A>,
+        message: U,
+    ) -> Result<()> {
+        let failed = self.send_all_undelivered(recipients, message).await?;
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(Error::Undelivered(failed.iter().map(ToAddress::to_string).collect())),
+        }
+    }
+
+    // Like `send_all`, returning the recipients that weren't delivered to
+    pub(crate) async fn send_all_undelivered<U: Send + Clone + 'static>(
+        &self,
+        recipients: impl IntoIterator<Item = A>,
+        message: U,
+    ) -> Result<Vec<A>> {
+        let messages = recipients
+            .into_iter()
+            .map(|recipient| Ok((recipient, queued_message(&self.router_tx, message.clone())?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let (failed_tx, failed_rx) = flume::bounded(1);
+        let router_msg = RouterMessage::SendAll { sender: self.address.clone(), messages, failed_tx };
+        self.router_tx.send(router_msg).await?;
+
+        failed_rx.recv_async().await.map_err(|_| Error::RouterUnrecoverableError)
+    }
+
+    /// Send a message to the address of a [`Cap`].
+    ///
+    /// This is the same as [`Agent::send`], except the recipient
+    /// has to have been allowed by a [`CapPolicy`].
+    pub async fn send_cap<U: Send + 'static>(&self, cap: Cap<A>, message: U) -> Result<()> {
+        self.send(cap.0, message).await
+    }
+
+    /// Send a message to another agent via the router,
+    /// returning the recipient address.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # use tinyroute::errors::Result;
+    /// # async fn run<A: ToAddress>(agent: Agent<String, A>, address: A) -> Result<()> {
+    /// let to = agent.send_returning(address, "hello".to_string()).await?;
+    /// agent.track(to).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn send_returning<U: Send + 'static>(
+        &self,
+        recipient: A,
+        message: U,
+    ) -> Result<A> {
+        self.send(recipient.clone(), message).await?;
+        Ok(recipient)
+    }
+
+    /// Send a message to whichever agent the routers classifier picks
+    /// for the `opcode` (see [`crate::Router::set_classifier`]).
+    ///
+    /// If the classifier picks no one the message is dropped.
+    pub async fn send_classified<U: Send + 'static>(&self, opcode: u32, message: U) -> Result<()> {
+        let msg = queued_message(&self.router_tx, message)?;
+        let router_msg = RouterMessage::Classified { sender: self.address.clone(), opcode, msg };
+        self.router_tx.send(router_msg).await
+    }
+
+    /// Send a message to another agent without waiting.
+    /// Returns [`Error::RouterBusy`] if the router channel is full,
+    /// and [`Error::QueuesFull`] if there are too many queued messages
+    /// (see [`crate::Router::max_queued`]), in which case the message is dropped.
+    pub fn try_send<U: Send + 'static>(
+        &self,
+        recipient: A,
+        message: U,
+    ) -> Result<()> {
+        let router_msg = self.local_message(recipient, message)?;
+        self.router_tx.try_send(router_msg)
+    }
+
+    /// Send a message to another agent without waiting,
+    /// handing the message back if the router channel is full,
+    /// or the limit on queued messages has been reached (see [`crate::Router::max_queued`]).
+    ///
+    /// Unlike [`Agent::try_send`] nothing is lost when the router is busy,
+    /// so an agent can receive (making room for the router to deliver to it)
+    /// and try again with the same message.
+    ///
+    /// ```
+    /// # use tinyroute::{Agent, ToAddress};
+    /// # use tinyroute::errors::Result;
+    /// # async fn run<A: ToAddress>(mut agent: Agent<String, A>, address: A) -> Result<()> {
+    /// let mut message = "hello".to_string();
+    /// while let Some(busy) = agent.try_send_or_keep(address.clone(), message)? {
+    ///     message = busy;
+    ///     let _incoming = agent.recv().await?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn try_send_or_keep<U: Send + 'static>(&self, recipient: A, message: U) -> Result<Option<U>> {
+        // Hold on to a place in the queue before boxing the message,
+        // so it can be handed back if there is none
+        let queued = match self.router_tx.queued() {
+            Err(Error::QueuesFull) => return Ok(Some(message)),
+            queued => queued?,
+        };
+        let msg = AnyMessage(Box::new(message), queued);
+        let router_msg = RouterMessage::Message { recipient, sender: self.address.clone(), msg };
+        match self.router_tx.try_send_or_return(router_msg)? {
+            Some(RouterMessage::Message { msg: AnyMessage(message, _), .. }) => match message.downcast::<U>() {
+                Ok(message) => Ok(Some(*message)),
+                Err(_) => Err(Error::InvalidMessageType),
+            },
+            Some(_) => Err(Error::InvalidMessageType),
+            None => Ok(None),
+        }
+    }
+
     pub async fn send_remote(
         &self,
         recipients: impl IntoIterator<Item = A>,
@@ -356,17 +1586,25 @@ impl<T: Send + 'static, A: ToAddress> Agent<T, A> {
         let framed_message = Frame::frame_message(message);
 
         for recipient in recipients.into_iter() {
-            let router_msg = RouterMessage::Message {
-                recipient,
-                sender: self.address.clone(),
-                msg: AnyMessage::new(framed_message.clone()),
-            };
+            let router_msg = self.local_message(recipient, framed_message.clone())?;
             self.router_tx.send(router_msg).await?;
         }
 
         Ok(())
     }
 
+    /// Forward a message that is already framed, e.g a frame from another
+    /// connection, to an agent expecting a [`FramedMessage`] such as a
+    /// [`crate::server::Connection`], without framing it again.
+    ///
+    /// This only works between transports using the framing in [`crate::frame`],
+    /// and `frame` has to be exactly one message frame
+    /// (see [`FramedMessage::from_framed`]).
+    pub async fn forward_framed(&self, recipient: A, frame: Bytes) -> Result<()> {
+        let framed_message = FramedMessage::from_framed(frame)?;
+        self.send(recipient, framed_message).await
+    }
+
     /// Tell the router to shut down an agent
     pub async fn send_shutdown(&self, recipient: A) -> Result<()> {
         self.router_tx.send(RouterMessage::Shutdown(recipient)).await
@@ -375,24 +1613,63 @@ impl<T: Send + 'static, A: ToAddress> Agent<T, A> {
     /// This is used for debugging, to print
     /// the current list of registered channels on a router.
     pub fn print_channels(&self) {
-        let _ = self.router_tx.send(RouterMessage::PrintChannels);
+        let _ = self.router_tx.send_detached(RouterMessage::PrintChannels);
+    }
+
+    /// Unregister the agent, and wait for the router to confirm it's done.
+    ///
+    /// Once this returns the address is free to use for a new agent,
+    /// and any agent tracking this one has been sent a `Message::AgentRemoved`.
+    /// Any messages still queued for the agent are dropped.
+    ///
+    /// Prefer this over dropping the agent when the teardown order matters:
+    /// dropping an agent also unregisters it, but without waiting for the router
+    /// and without a way to report errors.
+    pub async fn close(mut self) -> Result<()> {
+        self.closed = true;
+        let (success_tx, success_rx) = flume::bounded(1);
+        self.router_tx.send(RouterMessage::Close(self.address.clone(), success_tx)).await?;
+        success_rx.recv_async().await.map_err(|_| Error::RouterUnrecoverableError)?;
+        Ok(())
     }
 
     /// Shutdown the agent and unregister it with the router.
     pub fn shutdown(&self) {
         let router_msg = RouterMessage::Shutdown(self.address.clone());
-        let _ = self.router_tx.send(router_msg);
+        let _ = self.router_tx.send_detached(router_msg);
     }
 
     /// Shutdown the router and unregister ALL agents with the router.
     /// Any agent registered with the router should be dropped at this point
     /// as they can no longer receive or send message.
+    ///
+    /// Every agent receives a `Message::Shutdown`, unless its channel is still full
+    /// after [`crate::SHUTDOWN_TIMEOUT`], in which case it sees the channel closed
+    /// (which [`Agent::recv_or_shutdown`] also returns as `Message::Shutdown`).
     pub async fn shutdown_router(&self) {
         let _ = self.router_tx.send(RouterMessage::ShutdownRouter).await;
     }
+
+    /// Shutdown the router once all agents are done.
+    ///
+    /// Every agent (including this one) receives a `Message::Shutdown`,
+    /// and the router keeps routing messages until all agents have been dropped,
+    /// giving them a chance to finish any work in progress.
+    ///
+    /// If there are agents left after `timeout` the router shuts down anyway,
+    /// and the remaining agents can no longer receive or send messages.
+    pub async fn shutdown_router_graceful(&self, timeout: Duration) {
+        let _ = self.router_tx.send(RouterMessage::ShutdownRouterGraceful(timeout)).await;
+    }
 }
 
 impl<T: Send + 'static, A: ToAddress + Into<Option<Vec<u8>>>> Agent<T, A> {
+    /// Send `message` through the bridge agent at `bridge_address` to `remote`,
+    /// with the address of this agent as the sender.
+    ///
+    /// The sender is encoded with `Into<Option<Vec<u8>>>` rather than [`ToAddress::to_bytes`],
+    /// so an address relying on the default `to_bytes` (and `to_string`) can't send
+    /// a placeholder by accident. `#[derive(ToAddress)]` implements it with `to_bytes`.
     pub async fn send_bridged(
         &self,
         bridge_address: A,
@@ -400,12 +1677,58 @@ impl<T: Send + 'static, A: ToAddress + Into<Option<Vec<u8>>>> Agent<T, A> {
         message: Bytes,
     ) -> Result<()> {
         let msg = BridgeMessageOut::new(self.address.clone(), remote, message);
-        let router_msg = RouterMessage::Message {
-            sender: self.address.clone(),
-            recipient: bridge_address,
-            msg: AnyMessage::new(msg),
-        };
+        let router_msg = self.local_message(bridge_address, msg)?;
         self.router_tx.send(router_msg).await?;
         Ok(())
     }
 }
+
+// -----------------------------------------------------------------------------
+//     - Agent stream -
+// -----------------------------------------------------------------------------
+type Recv<T, A> = Pin<Box<dyn Future<Output = (Agent<T, A>, Result<Message<T, A>>)> + Send>>;
+
+/// A [`Stream`] of the messages for an agent, created with [`Agent::into_stream`].
+///
+/// Every item is the result of [`Agent::recv`], and the stream ends
+/// once the channel of the agent is closed (e.g after `Message::Shutdown`).
+///
+/// The stream owns the agent: dropping the stream drops the agent,
+/// which unregisters it with the router.
+pub struct AgentStream<T: 'static, A: ToAddress> {
+    agent: Option<Agent<T, A>>,
+    // The agent is moved into the future while a message is being received
+    recv: Option<Recv<T, A>>,
+}
+
+// Neither the agent nor the future are pinned by the stream
+impl<T: 'static, A: ToAddress> Unpin for AgentStream<T, A> {}
+
+impl<T: Send + 'static, A: ToAddress> Stream for AgentStream<T, A> {
+    type Item = Result<Message<T, A>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut recv = match (self.recv.take(), self.agent.take()) {
+            (Some(recv), _) => recv,
+            (None, Some(mut agent)) => Box::pin(async move {
+                let msg = agent.recv().await;
+                (agent, msg)
+            }),
+            (None, None) => return Poll::Ready(None),
+        };
+
+        match recv.as_mut().poll(cx) {
+            Poll::Pending => {
+                self.recv = Some(recv);
+                Poll::Pending
+            }
+            Poll::Ready((agent, msg)) => {
+                self.agent = Some(agent);
+                match msg {
+                    Err(Error::ChannelClosed) => Poll::Ready(None),
+                    msg => Poll::Ready(Some(msg)),
+                }
+            }
+        }
+    }
+}
@@ -1,16 +1,25 @@
 //! A [`Bridge`] is a connection between [`crate::Router`]s. 
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
+use flume::{Receiver, Sender};
 // use futures::future::FutureExt;
-use log::{error, info};
+use log::{error, info, warn};
+use rand::Rng;
+use tokio::time::timeout;
 
 use crate::agent::{Agent, Message};
 use crate::client::{
-    connect, ClientMessage, ClientReceiver, ClientSender, TcpClient,
+    connect_with_state, ClientMessage, ClientReceiver, ClientSender,
+    ConnectionState, DisconnectReason, Heartbeat, TcpClient,
 };
 use crate::errors::{Error, Result};
-use crate::frame::{Frame, FramedMessage};
+use crate::frame::{Frame, FrameTransform, FramedMessage};
+use crate::router::RouterMessage;
+use crate::server::ConnectionAddr;
 use crate::{ToAddress, ADDRESS_SEP};
 
 /// An outgoing message from a [`Bridge`]
@@ -19,11 +28,15 @@ pub struct BridgeMessageOut(FramedMessage);
 
 #[derive(thiserror::Error, Debug)]
 pub enum BridgeError {
-    #[error("Failed to reconnect the bridge")]
-    Reconnect,
+    /// Every connection attempt allowed by the [`Retry`] policy failed.
+    #[error("Failed to connect the bridge to {addr} after {attempts} attempts")]
+    Exhausted { addr: String, attempts: usize },
 
     #[error("Failed to communicate with the underlying connection")]
     Connection,
+
+    #[error("The circuit is open, not connecting")]
+    CircuitOpen,
 }
 
 /// An outgoing bridge message, sent through the bridge.
@@ -107,6 +120,36 @@ pub enum Reconnect {
     Exponential { seconds: u64, max: Option<u64> },
 }
 
+impl Reconnect {
+    // How long to wait before the next attempt.
+    // An exponential backoff doubles every time, until it plateaus at `max`
+    fn next_sleep(&mut self) -> Duration {
+        match self {
+            Reconnect::Constant(n) => *n,
+            Reconnect::Exponential { seconds, max } => {
+                let max = max.unwrap_or(u64::MAX);
+                let secs = (*seconds).min(max);
+                *seconds = seconds.saturating_mul(2).min(max);
+                Duration::from_secs(secs)
+            }
+        }
+    }
+}
+
+// Move `sleep` by a random fraction of itself, up to `jitter_frac` either way
+fn jittered(sleep: Duration, jitter_frac: f64) -> Duration {
+    if jitter_frac <= 0.0 {
+        return sleep;
+    }
+    let frac = rand::thread_rng().gen_range(-jitter_frac..=jitter_frac);
+    sleep.mul_f64(1.0 + frac)
+}
+
+/// How many times the bridge tries again after a failed connection attempt,
+/// before giving up with [`BridgeError::Exhausted`].
+///
+/// This applies every time the bridge (re)connects: `Retry::Count(2)` makes
+/// up to three attempts, the first one and two retries.
 #[derive(Debug, Copy, Clone)]
 pub enum Retry {
     Never,
@@ -114,56 +157,167 @@ pub enum Retry {
     Count(usize),
 }
 
-async fn connect_to(
-    addr: impl AsRef<str>,
-    reconnect: &mut Reconnect,
-    heartbeat: &mut Option<Duration>,
-    mut retry: Retry,
-) -> Result<(ClientSender, ClientReceiver)> {
-    loop {
-        match TcpClient::connect(addr.as_ref()).await {
-            Ok(c) => {
-                info!("Bridge connected");
-                break Ok(connect(c, *heartbeat));
-            }
-            Err(e) => {
-                error!("failed to connect. reason: {}", e);
-                let sleep_time = match reconnect {
-                    Reconnect::Constant(n) => *n,
-                    Reconnect::Exponential { seconds, max } => {
-                        let secs = match max {
-                            Some(max) => seconds.min(max),
-                            None => seconds,
-                        };
-                        let sleep = Duration::from_secs(*secs);
-                        *seconds *= 2;
-                        *reconnect = Reconnect::Exponential {
-                            seconds: *seconds,
-                            max: *max,
-                        };
-                        sleep
-                    }
-                };
-                match retry {
-                    Retry::Count(0) => break Err(BridgeError::Reconnect.into()),
-                    Retry::Never => break Err(BridgeError::Reconnect.into()),
-                    Retry::Count(ref mut n) => *n -= 1,
-                    Retry::Forever => {}
-                }
-                tokio::time::sleep(sleep_time).await;
-                info!("retrying...");
+/// Stop connecting to a server that keeps failing.
+///
+/// After `failure_threshold` consecutive failed connection attempts the circuit opens,
+/// and the bridge fails fast with [`BridgeError::CircuitOpen`] rather than connecting.
+///
+/// Once the `cooldown` has passed the circuit is half open, and up to
+/// `half_open_probes` connection attempts are made.
+/// If one of them succeeds the circuit is closed again, otherwise it opens
+/// for another `cooldown`.
+#[derive(Debug, Copy, Clone)]
+pub struct CircuitBreaker {
+    pub failure_threshold: usize,
+    pub cooldown: Duration,
+    pub half_open_probes: usize,
+}
+
+/// The state of the [`CircuitBreaker`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Connecting as normal
+    Closed,
+    /// Not connecting until the cooldown has passed
+    Open,
+    /// Trying to connect to see if the server has recovered
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    breaker: CircuitBreaker,
+    state: CircuitState,
+    failures: usize,
+    opened_at: Instant,
+}
+
+impl Circuit {
+    fn new(breaker: CircuitBreaker) -> Self {
+        Self { breaker, state: CircuitState::Closed, failures: 0, opened_at: Instant::now() }
+    }
+
+    fn state(&self) -> CircuitState {
+        match self.state {
+            CircuitState::Open if self.opened_at.elapsed() >= self.breaker.cooldown => CircuitState::HalfOpen,
+            state => state,
+        }
+    }
+
+    fn set_state(&mut self, state: CircuitState) {
+        if self.state != state {
+            info!("Bridge circuit {:?} -> {:?}", self.state, state);
+        }
+        self.state = state;
+    }
+
+    // Should a connection attempt be made
+    fn allow_attempt(&mut self) -> bool {
+        match self.state() {
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if self.state == CircuitState::Open => {
+                self.failures = 0;
+                self.set_state(CircuitState::HalfOpen);
+                true
             }
+            CircuitState::HalfOpen | CircuitState::Closed => true,
+        }
+    }
+
+    fn success(&mut self) {
+        self.failures = 0;
+        self.set_state(CircuitState::Closed);
+    }
+
+    fn failure(&mut self) {
+        self.failures += 1;
+        let limit = match self.state {
+            CircuitState::HalfOpen => self.breaker.half_open_probes,
+            _ => self.breaker.failure_threshold,
+        };
+
+        if self.failures >= limit {
+            warn!("Bridge circuit open after {} failed attempts", self.failures);
+            self.opened_at = Instant::now();
+            self.set_state(CircuitState::Open);
         }
     }
 }
 
+/// How long a single connection attempt can take before it counts
+/// as a failed attempt, unless changed with [`Bridge::with_connect_timeout`].
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default number of messages a paused [`Bridge`] holds on to,
+/// see [`Bridge::with_max_pending`].
+pub const DEFAULT_MAX_PENDING: usize = 1024;
+
+/// Changes to the connection of a [`Bridge`], passed to the callback
+/// set with [`Bridge::on_event`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BridgeEvent {
+    /// The connection is established (or reestablished)
+    Connected,
+    /// The connection closed, or was dropped by [`BridgeControl::ForceReconnect`]
+    /// (with [`DisconnectReason::Closed`]), and why
+    Disconnected(DisconnectReason),
+    /// A connection attempt failed, and the bridge is about to retry.
+    /// `attempt` is the number of the retry, starting at one.
+    Reconnecting { attempt: usize },
+    /// The bridge gave up connecting: the retries are exhausted
+    /// or the circuit is open
+    Failed,
+    /// The [`CircuitBreaker`] changed state.
+    ///
+    /// Once the cooldown has passed the circuit is half open
+    /// (see [`Bridge::circuit_state`]), but this is only sent for it
+    /// when the next connection attempt is let through.
+    Circuit(CircuitState),
+}
+
+type OnEvent = Box<dyn Fn(BridgeEvent) + Send>;
+
+/// Commands for a running [`Bridge`], sent through [`Bridge::control`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BridgeControl {
+    /// Drop the current connection and connect again,
+    /// e.g after the address of the server now resolves to a different host.
+    ///
+    /// This resets the [`Reconnect::Exponential`] backoff to where it started,
+    /// but not the [`CircuitBreaker`]: the bridge still fails fast
+    /// while the circuit is open.
+    ForceReconnect,
+    /// Stop forwarding messages over the connection (and stop reconnecting).
+    /// Messages received while paused are held on to and forwarded,
+    /// in order, once the bridge is resumed.
+    /// At most [`Bridge::with_max_pending`] are held on to,
+    /// past that the oldest are dropped (see [`Bridge::dropped`]).
+    Pause,
+    /// Start forwarding messages again
+    Resume,
+}
+
 pub struct Bridge<'addr, A: ToAddress> {
     agent: Agent<BridgeMessageOut, A>,
     addr: &'addr str,
     reconnect: Reconnect,
-    heartbeat: Option<Duration>,
-    connection: Option<(ClientSender, ClientReceiver)>,
+    initial_reconnect: Reconnect,
+    heartbeat: Option<Heartbeat>,
+    connection: Option<(ClientSender, ClientReceiver, Arc<ConnectionState>)>,
     retry: Retry,
+    circuit: Option<Circuit>,
+    connect_timeout: Duration,
+    control: (Sender<BridgeControl>, Receiver<BridgeControl>),
+    paused: bool,
+    pending: VecDeque<FramedMessage>,
+    max_pending: usize,
+    dropped: usize,
+    transform: Option<FrameTransform>,
+    jitter: f64,
+    sink: Option<A>,
+    host: Option<ConnectionAddr>,
+    on_event: Option<OnEvent>,
+    last_disconnect: Option<DisconnectReason>,
 }
 
 impl<'addr, A: ToAddress> Bridge<'addr, A> {
@@ -172,44 +326,363 @@ impl<'addr, A: ToAddress> Bridge<'addr, A> {
         addr: &'addr str,
         reconnect: Reconnect,
         retry: Retry,
-        heartbeat: Option<Duration>,
+        heartbeat: Option<Heartbeat>,
     ) -> Self {
-        Self { agent, addr, reconnect, heartbeat, retry, connection: None }
+        Self {
+            agent,
+            addr,
+            initial_reconnect: reconnect.clone(),
+            reconnect,
+            heartbeat,
+            retry,
+            connection: None,
+            circuit: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            control: flume::unbounded(),
+            paused: false,
+            pending: VecDeque::new(),
+            max_pending: DEFAULT_MAX_PENDING,
+            dropped: 0,
+            transform: None,
+            jitter: 0.0,
+            sink: None,
+            host: None,
+            on_event: None,
+            last_disconnect: None,
+        }
+    }
+
+    /// A sender for controlling the bridge from elsewhere, see [`BridgeControl`].
+    ///
+    /// Control messages are handled by [`Bridge::exec`], ahead of anything else.
+    ///
+    /// ```
+    /// use tinyroute::bridge::{Bridge, BridgeControl};
+    /// # async fn run<A: tinyroute::ToAddress>(mut bridge: Bridge<'_, A>) {
+    /// let control = bridge.control();
+    /// tokio::spawn(async move {
+    ///     // e.g on SIGHUP
+    ///     control.send(BridgeControl::ForceReconnect).unwrap();
+    /// });
+    ///
+    /// while let Ok(_) = bridge.exec().await {}
+    /// # }
+    /// ```
+    pub fn control(&self) -> Sender<BridgeControl> {
+        self.control.0.clone()
+    }
+
+    fn handle_control(&mut self, control: BridgeControl) {
+        info!("Bridge control: {:?}", control);
+        match control {
+            BridgeControl::ForceReconnect => {
+                // The reader holds on to the writer, so dropping the sender
+                // is not enough to close the connection
+                if let Some((bridge_output_tx, _, _)) = self.connection.take() {
+                    let _ = bridge_output_tx.send(ClientMessage::Quit);
+                    self.disconnected(DisconnectReason::Closed);
+                }
+                self.reconnect = self.initial_reconnect.clone();
+            }
+            BridgeControl::Pause => self.paused = true,
+            BridgeControl::Resume => self.paused = false,
+        }
+    }
+
+    async fn deliver(&self, bytes: Vec<u8>) -> Result<Option<Message<BridgeMessageOut, A>>> {
+        if let (Some(sink), Some(host)) = (&self.sink, &self.host) {
+            self.agent
+                .router_tx
+                .send(RouterMessage::RemoteMessage {
+                    recipient: sink.clone(),
+                    sender: self.agent.address().clone(),
+                    bytes: bytes.into(),
+                    host: host.clone(),
+                })
+                .await?;
+        }
+        Ok(None)
+    }
+
+    fn forward(&mut self, framed_message: FramedMessage) -> Result<Option<Message<BridgeMessageOut, A>>> {
+        let (bridge_output_tx, _, _) = self.connection.as_ref().expect("only called with a connection");
+        match bridge_output_tx.send(ClientMessage::Payload(framed_message)) {
+            Err(_e) => Err(BridgeError::Connection.into()),
+            Ok(()) => Ok(None),
+        }
+    }
+
+    /// Give up on a connection attempt after `connect_timeout`
+    /// (defaults to [`DEFAULT_CONNECT_TIMEOUT`]),
+    /// rather than waiting for the OS to time out the connection.
+    ///
+    /// An attempt that times out is a failed attempt like any other,
+    /// and is retried according to the [`Retry`] policy.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Gate reconnecting behind a [`CircuitBreaker`].
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.circuit = Some(Circuit::new(breaker));
+        self
+    }
+
+    /// Hold on to at most `max_pending` messages while paused
+    /// (defaults to [`DEFAULT_MAX_PENDING`]).
+    /// Once there are `max_pending` messages held on to,
+    /// the oldest one is dropped to make room for the next.
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// The number of messages dropped while paused, to stay under [`Bridge::with_max_pending`].
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Move every wait between connection attempts by a random fraction of itself,
+    /// up to `jitter_frac` either way (e.g `0.2` waits between 80% and 120% of the time
+    /// given by the [`Reconnect`] policy), so bridges that lost their connection
+    /// at the same time don't all reconnect at the same time.
+    ///
+    /// `jitter_frac` is clamped to `0.0..=1.0`, and defaults to zero (no jitter).
+    pub fn with_reconnect_jitter(mut self, jitter_frac: f64) -> Self {
+        self.jitter = jitter_frac.clamp(0.0, 1.0);
+        self
     }
 
-    async fn reconnect(&mut self) -> Result<(ClientSender, ClientReceiver)> {
-        connect_to(
-            self.addr,
-            &mut self.reconnect,
-            &mut self.heartbeat,
-            self.retry,
-        )
-        .await
+    /// Pass the content of every message frame through the `transform`,
+    /// on every connection the bridge makes. See [`FrameTransform`].
+    pub fn with_frame_transform(mut self, transform: FrameTransform) -> Self {
+        self.transform = Some(transform);
+        self
     }
 
+    /// Deliver the messages the server sends over the connection to the agent at `sink`,
+    /// as a `Message::RemoteMessage` from the bridge agent.
+    ///
+    /// The bytes are the content of the message frame as is, so if the server
+    /// prefixes them with the sender they can be split with [`BridgeMessageIn::decode`].
+    ///
+    /// Without a sink, anything the server sends is discarded.
+    pub fn with_sink(mut self, sink: A) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+
+    /// Call `f` whenever the bridge connects, disconnects, retries or gives up,
+    /// and when the state of its circuit breaker changes.
+    /// See [`BridgeEvent`].
+    ///
+    /// This is called from [`Bridge::exec`], so `f` should return quickly.
+    ///
+    /// ```
+    /// # use tinyroute::bridge::{Bridge, BridgeEvent};
+    /// # fn run<A: tinyroute::ToAddress>(bridge: Bridge<'_, A>) {
+    /// let bridge = bridge.on_event(|event| match event {
+    ///     BridgeEvent::Failed => eprintln!("The bridge is down"),
+    ///     event => println!("{:?}", event),
+    /// });
+    /// # }
+    /// ```
+    pub fn on_event(mut self, f: impl Fn(BridgeEvent) + Send + 'static) -> Self {
+        self.on_event = Some(Box::new(f));
+        self
+    }
+
+    fn event(&self, event: BridgeEvent) {
+        if let Some(on_event) = &self.on_event {
+            on_event(event);
+        }
+    }
+
+    fn disconnected(&mut self, reason: DisconnectReason) {
+        info!("Bridge disconnected: {:?}", reason);
+        self.last_disconnect = Some(reason);
+        self.event(BridgeEvent::Disconnected(reason));
+    }
+
+    /// Why the connection closed the last time it did,
+    /// or `None` if it never has.
+    ///
+    /// This includes a connection that closed since [`Bridge::exec`]
+    /// was last called, as long as it hasn't been reestablished.
+    pub fn last_disconnect_reason(&self) -> Option<DisconnectReason> {
+        match self.connection {
+            Some((_, _, ref state)) => state.disconnect_reason().or(self.last_disconnect),
+            None => self.last_disconnect,
+        }
+    }
+
+    /// The state of the circuit breaker, if the bridge has one.
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        self.circuit.as_ref().map(Circuit::state)
+    }
+
+    /// `true` if the bridge has a connection and neither the reading nor the
+    /// writing half of it has closed.
+    ///
+    /// A closed connection is only reestablished the next time
+    /// [`Bridge::exec`] is called.
+    pub fn is_connected(&self) -> bool {
+        match self.connection {
+            Some((_, _, ref state)) => state.is_connected(),
+            None => false,
+        }
+    }
+
+    async fn reconnect(&mut self) -> Result<(ClientSender, ClientReceiver, Arc<ConnectionState>)> {
+        let connection = self.connect().await;
+        match connection {
+            Ok(_) => self.event(BridgeEvent::Connected),
+            Err(_) => self.event(BridgeEvent::Failed),
+        }
+        connection
+    }
+
+    // Call `f` with the circuit, if there is one,
+    // and send a `BridgeEvent::Circuit` if its state changed
+    fn update_circuit<R>(&mut self, f: impl FnOnce(&mut Circuit) -> R) -> Option<R> {
+        let circuit = self.circuit.as_mut()?;
+        let before = circuit.state;
+        let res = f(circuit);
+        let after = circuit.state;
+        if before != after {
+            self.event(BridgeEvent::Circuit(after));
+        }
+        Some(res)
+    }
+
+    async fn connect(&mut self) -> Result<(ClientSender, ClientReceiver, Arc<ConnectionState>)> {
+        let mut retry = self.retry;
+        let mut attempts = 0;
+        loop {
+            if self.update_circuit(Circuit::allow_attempt) == Some(false) {
+                break Err(BridgeError::CircuitOpen.into());
+            }
+
+            attempts += 1;
+            let attempt = match timeout(self.connect_timeout, TcpClient::connect(self.addr)).await {
+                Ok(attempt) => attempt,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connection attempt timed out").into()),
+            };
+
+            match attempt {
+                Ok(c) => {
+                    info!("Bridge connected");
+                    self.host = c.inner.peer_addr().ok().map(ConnectionAddr::Tcp);
+                    self.update_circuit(Circuit::success);
+                    break connect_with_state(c, self.heartbeat.clone(), None, self.transform.clone());
+                }
+                Err(e) => {
+                    error!("failed to connect. reason: {}", e);
+                    let state = self.update_circuit(|circuit| {
+                        circuit.failure();
+                        circuit.state
+                    });
+                    if state == Some(CircuitState::Open) {
+                        break Err(BridgeError::CircuitOpen.into());
+                    }
+
+                    let sleep_time = jittered(self.reconnect.next_sleep(), self.jitter);
+                    match retry {
+                        Retry::Count(0) | Retry::Never => {
+                            error!("Giving up connecting to {} after {} attempts", self.addr, attempts);
+                            break Err(BridgeError::Exhausted { addr: self.addr.to_string(), attempts }.into());
+                        }
+                        Retry::Count(ref mut n) => *n -= 1,
+                        Retry::Forever => {}
+                    }
+                    self.event(BridgeEvent::Reconnecting { attempt: attempts });
+                    tokio::time::sleep(sleep_time).await;
+                    info!("retrying...");
+                }
+            }
+        }
+    }
+
+    /// Forward the next message for the bridge agent over the connection,
+    /// connecting first if need be.
+    ///
+    /// Returns `Message::Connected` once the connection is established
+    /// (or reestablished), before forwarding anything else over it.
+    /// A message from the server is delivered to the sink, if there is one
+    /// (see [`Bridge::with_sink`]).
+    /// Any other message that isn't a value is returned as is.
     pub async fn exec(&mut self) -> Result<Option<Message<BridgeMessageOut, A>>> {
-        // Rx here is the incoming data from the network connection.
-        // This should never do anything but return `None` once the connection is closed.
+        if let Ok(control) = self.control.1.try_recv() {
+            self.handle_control(control);
+            return Ok(None);
+        }
+
+        // Hold on to the values until resumed
+        if self.paused {
+            let message = tokio::select! {
+                biased;
+
+                control = self.control.1.recv_async() => {
+                    if let Ok(control) = control {
+                        self.handle_control(control);
+                    }
+                    return Ok(None);
+                }
+                msg = self.agent.recv() => msg?,
+            };
+
+            return match message {
+                Message::Value(BridgeMessageOut(framed_message), _) => {
+                    if self.pending.len() >= self.max_pending {
+                        warn!("Bridge paused with {} pending messages, dropping the oldest", self.pending.len());
+                        self.dropped += 1;
+                        self.pending.pop_front();
+                    }
+                    if self.max_pending > 0 {
+                        self.pending.push_back(framed_message);
+                    }
+                    Ok(None)
+                }
+                message => Ok(Some(message)),
+            };
+        }
+
         if self.connection.is_none() {
             self.connection = Some(self.reconnect().await?);
+            return Ok(Some(Message::Connected(self.agent.address().clone())));
         }
 
-        let (bridge_output_tx, rx_client_closed) = self.connection.as_mut().expect("This is okay, because we check the connection above");
+        if let Some(framed_message) = self.pending.pop_front() {
+            return self.forward(framed_message);
+        }
+
+        // Rx here is the incoming data from the network connection.
+        let (_, rx_client, _) = self.connection.as_mut().expect("This is okay, because we check the connection above");
 
         // If the `rx_client` is closed, then reconnect.
+        // If there is data on the `rx_client`, deliver it to the sink.
         // If the message from the `agent` is invalid, continue and try the next one
         // If the message is okay then return that
         let message = tokio::select! {
-            is_closed = rx_client_closed.recv_async() => {
-                let is_closed = is_closed.is_err();
-                match is_closed {
-                    true => {
+            // Notice a closed connection before writing anything else to it
+            biased;
+
+            control = self.control.1.recv_async() => {
+                if let Ok(control) = control {
+                    self.handle_control(control);
+                }
+                return Ok(None);
+            }
+            data = rx_client.recv_async() => {
+                match data {
+                    Err(_) => {
+                        let reason = self.connection.take().and_then(|(_, _, state)| state.disconnect_reason());
+                        self.disconnected(reason.unwrap_or(DisconnectReason::Closed));
                         self.connection = Some(self.reconnect().await?);
-                        return Ok(None);
+                        return Ok(Some(Message::Connected(self.agent.address().clone())));
                     }
-                    false => return Ok(None), // got a message on the connection rx,
-                                              // and we shouldn't be getting anything on there for
-                                              // now.
+                    Ok(bytes) => return self.deliver(bytes).await,
                 }
             },
             msg = self.agent.recv() => msg?,
@@ -217,12 +690,52 @@ impl<'addr, A: ToAddress> Bridge<'addr, A> {
 
         if let Message::Value(BridgeMessageOut(framed_message), _) = message {
             // if you can read this, know that you are wonderful
-            match bridge_output_tx.send(ClientMessage::Payload(framed_message)) {
-                Err(_e) => Err(BridgeError::Connection.into()),
-                Ok(()) => Ok(None),
-            }
+            self.forward(framed_message)
         } else {
             Ok(Some(message))
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff_plateaus_at_max() {
+        let mut reconnect = Reconnect::Exponential { seconds: 1, max: Some(10) };
+        let sleeps = (0..8).map(|_| reconnect.next_sleep().as_secs()).collect::<Vec<_>>();
+        assert_eq!(vec![1, 2, 4, 8, 10, 10, 10, 10], sleeps);
+    }
+
+    #[test]
+    fn exponential_backoff_without_max_does_not_overflow() {
+        let mut reconnect = Reconnect::Exponential { seconds: u64::MAX / 2, max: None };
+        for _ in 0..4 {
+            let sleep = reconnect.next_sleep();
+            assert!(sleep >= Duration::from_secs(u64::MAX / 2));
+        }
+        assert!(matches!(reconnect, Reconnect::Exponential { seconds: u64::MAX, .. }));
+    }
+
+    #[test]
+    fn jittered_backoff() {
+        let sleep = Duration::from_secs(10);
+        let first = jittered(sleep, 0.5);
+        let second = jittered(sleep, 0.5);
+        assert_ne!(first, second);
+        for jittered in [first, second] {
+            assert!(jittered >= Duration::from_secs(5) && jittered <= Duration::from_secs(15));
+        }
+
+        assert_eq!(sleep, jittered(sleep, 0.0));
+    }
+
+    #[test]
+    fn constant_backoff() {
+        let mut reconnect = Reconnect::Constant(Duration::from_millis(10));
+        for _ in 0..3 {
+            assert_eq!(Duration::from_millis(10), reconnect.next_sleep());
+        }
+    }
+}
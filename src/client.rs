@@ -3,15 +3,15 @@
 //! use tokio::io::{AsyncReadExt, AsyncWriteExt};
 //! use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 //! use tokio::sync::mpsc;
-//! use tinyroute::client::{ClientSender, ClientReceiver, ClientMessage, connect, Client, TcpClient};
+//! use tinyroute::client::{ClientSender, ClientReceiver, ClientMessage, connect, Client, Heartbeat, TcpClient};
 //! use tinyroute::frame::Frame;
 //!
 //! #[tokio::main]
 //! async fn main() {
 //!     # let _ = async move {
 //!     let client = TcpClient::connect("127.0.0.1:5000").await.unwrap();
-//!     let heartbeat = std::time::Duration::from_secs(30);
-//!     let (send, rec) = connect(client, Some(heartbeat));
+//!     let heartbeat = Heartbeat::new(std::time::Duration::from_secs(30));
+//!     let (send, rec) = connect(client, Some(heartbeat)).unwrap();
 //!     let (tx, rx) = mpsc::channel(10);
 //!
 //!     tokio::spawn(receiver(rec, tx));
@@ -34,10 +34,15 @@
 //!     }
 //! }
 //! ```
+use std::io;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
 use std::time::Duration;
 
-use log::{error, info};
+use bytes::Bytes;
+use fxhash::FxHashMap;
+use log::{error, info, warn};
 use rand::prelude::*;
 use tokio::net::{TcpStream, UnixStream};
 
@@ -47,7 +52,7 @@ use tokio::spawn;
 use tokio::time::sleep;
 
 use crate::errors::{Error, Result};
-use crate::frame::{Frame, FrameOutput, FramedMessage};
+use crate::frame::{Direction, Frame, FrameOutput, FrameTap, FrameTransform, FramedMessage};
 use crate::ADDRESS_SEP;
 use flume::{Receiver, Sender};
 
@@ -65,10 +70,68 @@ pub enum ClientMessage {
     /// Bunch of delicious bytes
     Payload(FramedMessage),
     Raw(Vec<u8>),
-    /// Heartbeats
+    /// Heartbeats, see [`Heartbeat`]
     Heartbeat,
 }
 
+/// The interval of a [`Heartbeat`] created with `Heartbeat::default()`.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What a client writes to keep the connection alive, and how often.
+///
+/// The payload is written as is, so it has to be a complete frame as far
+/// as the server is concerned. It defaults to the single byte heartbeat frame
+/// every tinyroute server understands (see [`crate::frame::Header::Heartbeat`]).
+///
+/// The interval has to be longer than a second.
+/// Every heartbeat is sent up to two seconds early, so heartbeats from many clients
+/// don't all arrive at once.
+///
+/// ```
+/// use std::time::Duration;
+/// use tinyroute::Bytes;
+/// use tinyroute::client::Heartbeat;
+///
+/// let ping = Heartbeat::new(Duration::from_secs(10)).with_payload(Bytes::from_static(b"PING\n"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub payload: Bytes,
+}
+
+impl Heartbeat {
+    /// Send the heartbeat frame every `interval`
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, payload: Bytes::from_static(&[crate::frame::Header::Heartbeat as u8]) }
+    }
+
+    /// Send `payload` rather than the heartbeat frame
+    pub fn with_payload(mut self, payload: Bytes) -> Self {
+        self.payload = payload;
+        self
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new(DEFAULT_HEARTBEAT_INTERVAL)
+    }
+}
+
+impl From<Duration> for Heartbeat {
+    fn from(interval: Duration) -> Self {
+        Self::new(interval)
+    }
+}
+
+impl From<FramedMessage> for ClientMessage {
+    /// Send an already framed message, without framing it again.
+    fn from(framed_message: FramedMessage) -> Self {
+        ClientMessage::Payload(framed_message)
+    }
+}
+
 impl ClientMessage {
     /// Create a `ClientMessage::Payload` from a channel and payload.
     ///
@@ -130,6 +193,15 @@ impl UdsClient {
     }
 }
 
+#[cfg(unix)]
+impl UdsClient {
+    /// Receive a file descriptor sent by the server.
+    /// See [`crate::fd`] for more information.
+    pub async fn recv_fd(&self) -> Result<(std::os::unix::io::OwnedFd, Vec<u8>)> {
+        crate::fd::recv_fd(&self.inner).await
+    }
+}
+
 impl Client for UdsClient {
     type Reader = tokio::net::unix::OwnedReadHalf;
     type Writer = tokio::net::unix::OwnedWriteHalf;
@@ -160,6 +232,24 @@ impl TcpClient {
 
         Ok(inst)
     }
+
+    /// Set socket options the client doesn't expose (e.g the TTL), once connected.
+    ///
+    /// `f` is given the stream for the duration of the call.
+    /// This is an escape hatch: reading from or writing to the stream,
+    /// or changing it to blocking mode, breaks the connection.
+    ///
+    /// ```
+    /// # use tinyroute::client::TcpClient;
+    /// # async fn run() {
+    /// let tcp_client = TcpClient::connect("127.0.0.1:5000").await.unwrap()
+    ///     .with_socket(|stream| stream.set_ttl(16).unwrap());
+    /// # }
+    /// ```
+    pub fn with_socket(self, f: impl FnOnce(&TcpStream)) -> Self {
+        f(&self.inner);
+        self
+    }
 }
 
 impl Client for TcpClient {
@@ -173,21 +263,134 @@ impl Client for TcpClient {
     }
 }
 
-/// Get a [`ClientSender`] and [`ClientReceiver`] pair
-pub fn connect(connection: impl Client, heartbeat: Option<Duration>) -> (ClientSender, ClientReceiver) {
+/// Why a connection closed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The other end closed the connection
+    Eof,
+    /// The connection was reset or aborted by the other end
+    Reset,
+    /// Reading or writing timed out
+    Timeout,
+    /// The other end sent something that isn't a valid frame
+    Decode,
+    /// Any other io error
+    Io(io::ErrorKind),
+    /// The connection was closed from this end
+    Closed,
+}
+
+impl DisconnectReason {
+    fn from_io(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe => Self::Reset,
+            io::ErrorKind::TimedOut => Self::Timeout,
+            io::ErrorKind::UnexpectedEof => Self::Eof,
+            kind => Self::Io(kind),
+        }
+    }
+
+    fn from_error(e: &Error) -> Self {
+        match e {
+            Error::Io(e) => Self::from_io(e),
+            Error::MalformedHeader => Self::Decode,
+            _ => Self::Closed,
+        }
+    }
+}
+
+/// The state of a connection, shared between the reader and writer tasks.
+#[derive(Debug)]
+pub(crate) struct ConnectionState {
+    connected: AtomicBool,
+    reason: Mutex<Option<DisconnectReason>>,
+}
+
+impl ConnectionState {
+    fn new() -> Self {
+        Self { connected: AtomicBool::new(true), reason: Mutex::new(None) }
+    }
+
+    /// `false` once either the reader or the writer has stopped.
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// Why the connection closed, if it has.
+    pub(crate) fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        *self.reason.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Only the first reason is kept, as the other half stops because of it
+    fn disconnected(&self, reason: DisconnectReason) {
+        self.reason.lock().unwrap_or_else(PoisonError::into_inner).get_or_insert(reason);
+        self.connected.store(false, Ordering::Release);
+    }
+}
+
+/// Get a [`ClientSender`] and [`ClientReceiver`] pair.
+///
+/// The heartbeat interval has to be longer than a second (see [`Heartbeat`]),
+/// otherwise [`Error::InvalidHeartbeat`] is returned.
+pub fn connect(connection: impl Client, heartbeat: Option<Heartbeat>) -> Result<(ClientSender, ClientReceiver)> {
+    let (writer_tx, reader_rx, _) = connect_with_state(connection, heartbeat, None, None)?;
+    Ok((writer_tx, reader_rx))
+}
+
+/// Get a [`ClientSender`] and [`ClientReceiver`] pair,
+/// passing every message frame to the `tap`. See [`FrameTap`].
+pub fn connect_with_tap(
+    connection: impl Client,
+    heartbeat: Option<Heartbeat>,
+    tap: FrameTap,
+) -> Result<(ClientSender, ClientReceiver)> {
+    let (writer_tx, reader_rx, _) = connect_with_state(connection, heartbeat, Some(tap), None)?;
+    Ok((writer_tx, reader_rx))
+}
+
+/// Get a [`ClientSender`] and [`ClientReceiver`] pair,
+/// passing the content of every message frame through the `transform`
+/// before it's written and after it's read. See [`FrameTransform`].
+pub fn connect_with_transform(
+    connection: impl Client,
+    heartbeat: Option<Heartbeat>,
+    transform: FrameTransform,
+) -> Result<(ClientSender, ClientReceiver)> {
+    let (writer_tx, reader_rx, _) = connect_with_state(connection, heartbeat, None, Some(transform))?;
+    Ok((writer_tx, reader_rx))
+}
+
+pub(crate) fn connect_with_state(
+    connection: impl Client,
+    heartbeat: Option<Heartbeat>,
+    tap: Option<FrameTap>,
+    transform: Option<FrameTransform>,
+) -> Result<(ClientSender, ClientReceiver, Arc<ConnectionState>)> {
+    validate_heartbeat(heartbeat.as_ref().map(|h| h.interval))?;
+
     let (writer_tx, writer_rx) = flume::unbounded();
     let (reader_tx, reader_rx) = flume::unbounded();
+    let state = Arc::new(ConnectionState::new());
 
     let (reader, writer) = connection.split();
 
-    let _read_handle = spawn(use_reader(reader, reader_tx, writer_tx.clone()));
-    let _write_handle = spawn(use_writer(writer, writer_rx));
+    let _read_handle = spawn(use_reader(reader, reader_tx, writer_tx.clone(), state.clone(), tap.clone(), transform.clone()));
+    let beat = heartbeat.clone().unwrap_or_default().payload;
+    let _write_handle = spawn(use_writer(writer, writer_rx, state.clone(), tap, transform, beat));
 
-    if let Some(freq) = heartbeat {
-        let _beat_handle = spawn(run_heartbeat(freq, writer_tx.clone()));
+    if let Some(heartbeat) = heartbeat {
+        let _beat_handle = spawn(run_heartbeat(heartbeat.interval, writer_tx.clone()));
     }
 
-    (writer_tx, reader_rx)
+    Ok((writer_tx, reader_rx, state))
+}
+
+// A heartbeat of a second or less would flood the connection
+pub(crate) fn validate_heartbeat(heartbeat: Option<Duration>) -> Result<()> {
+    match heartbeat {
+        Some(freq) if freq.as_millis() <= 1000 => Err(Error::InvalidHeartbeat),
+        _ => Ok(()),
+    }
 }
 
 pub async fn run_heartbeat(freq: Duration, writer_tx: Sender<ClientMessage>) {
@@ -196,7 +399,7 @@ pub async fn run_heartbeat(freq: Duration, writer_tx: Sender<ClientMessage>) {
     assert!(freq.as_millis() > 1000, "Heart beat should never be less than a second");
 
     loop {
-        sleep(freq - jitter()).await;
+        sleep(freq.saturating_sub(jitter())).await;
         if let Err(e) = writer_tx.send(ClientMessage::Heartbeat) {
             error!("Failed to send heartbeat to writer: {}", e);
             break;
@@ -215,37 +418,49 @@ async fn use_reader(
     mut reader: impl AsyncRead + Unpin + Send + 'static,
     output_tx: Sender<Vec<u8>>,
     writer_tx: Sender<ClientMessage>,
+    state: Arc<ConnectionState>,
+    tap: Option<FrameTap>,
+    transform: Option<FrameTransform>,
 ) {
     let mut frame = Frame::empty();
 
-    'read: loop {
+    let reason = 'read: loop {
         let res = frame.read_async(&mut reader).await;
 
         'msg: loop {
             match res {
-                Ok(0) => break 'read,
+                Ok(0) => break 'read DisconnectReason::Eof,
                 Ok(_) => match frame.try_msg() {
                     Ok(None) => break 'msg,
-                    Ok(Some(FrameOutput::Heartbeat)) => error!("received a heartbeat on the reader"),
+                    // Heartbeats only keep the connection alive
+                    Ok(Some(FrameOutput::Heartbeat)) => continue,
                     Ok(Some(FrameOutput::Message(payload))) => {
+                        if let Some(tap) = &tap {
+                            tap(Direction::Inbound, &Bytes::copy_from_slice(&payload));
+                        }
+                        let payload = match &transform {
+                            Some(transform) => transform(Direction::Inbound, Bytes::from(payload)).to_vec(),
+                            None => payload,
+                        };
                         if let Err(e) = output_tx.send_async(payload).await {
                             error!("Failed to send client message: {}", e);
                         }
                     }
                     Err(Error::MalformedHeader) => {
                         log::error!("Malformed header");
-                        break 'read;
+                        break 'read DisconnectReason::Decode;
                     }
                     Err(_) => unreachable!(),
                 },
-                Err(e) => {
+                Err(ref e) => {
                     error!("Connection closed: {}", e);
-                    break 'read;
+                    break 'read DisconnectReason::from_error(e);
                 }
             }
         }
-    }
+    };
 
+    state.disconnected(reason);
     let _ = writer_tx.send(ClientMessage::Quit);
     info!("Client closed (reader)");
 }
@@ -253,31 +468,203 @@ async fn use_reader(
 async fn use_writer(
     mut writer: impl AsyncWrite + Unpin + Send + 'static,
     rx: Receiver<ClientMessage>,
+    state: Arc<ConnectionState>,
+    tap: Option<FrameTap>,
+    transform: Option<FrameTransform>,
+    beat: Bytes,
 ) -> Result<()> {
-    loop {
-        let msg = rx.recv_async().await.map_err(|_| Error::ChannelClosed)?;
+    let reason = loop {
+        let msg = match rx.recv_async().await {
+            Ok(msg) => msg,
+            Err(_) => {
+                state.disconnected(DisconnectReason::Closed);
+                return Err(Error::ChannelClosed);
+            }
+        };
         match msg {
-            ClientMessage::Quit => break,
+            ClientMessage::Quit => break DisconnectReason::Closed,
             ClientMessage::Heartbeat => {
-                let beat = &[crate::frame::Header::Heartbeat as u8];
-                if let Err(e) = writer.write_all(beat).await {
+                if let Err(e) = writer.write_all(&beat).await {
                     error!("Failed to write heartbeat: {}", e);
-                    break;
+                    break DisconnectReason::from_io(&e);
                 }
             }
             ClientMessage::Payload(payload) => {
-                if let Err(e) = writer.write_all(&payload.0).await {
+                let payload = match &transform {
+                    Some(transform) => Frame::frame_message(&transform(Direction::Outbound, payload.content())),
+                    None => payload,
+                };
+                if let Some(tap) = &tap {
+                    tap(Direction::Outbound, payload.as_bytes());
+                }
+                if let Err(e) = writer.write_all(payload.as_bytes()).await {
                     error!("Failed to write payload: {}", e);
-                    break;
+                    break DisconnectReason::from_io(&e);
                 }
             }
             ClientMessage::Raw(_) => {
                 error!("Raw message sent to client. This should not happen. Raw messages are for third party libraries that have their own framing");
-                break;
+                break DisconnectReason::Closed;
             }
         }
-    }
+    };
 
+    state.disconnected(reason);
     info!("Client closed (writer)");
     Ok(())
 }
+
+// -----------------------------------------------------------------------------
+//     - Shared client -
+// -----------------------------------------------------------------------------
+const STREAM_ID_LEN: usize = std::mem::size_of::<u32>();
+
+/// Share a single connection between multiple senders.
+///
+/// Each sender opens a [`SharedStream`] with a unique id.
+/// Every payload sent on a stream is prefixed with the stream id
+/// (a big endian `u32`), and the server is expected to prefix its replies
+/// with the same id. Replies are passed on to the stream with a matching id,
+/// and replies for an unknown id are dropped.
+///
+/// Ids are handed out in increasing order starting at zero, skipping any id
+/// that is still in use, and wrapping around after `u32::MAX`.
+/// A stream releases its id when dropped, so a reply that arrives after that
+/// is dropped (or worse: received by a newer stream once the ids wrap around).
+///
+/// The connection is closed once the `SharedClient` and all its streams are dropped.
+/// If the connection is closed by the server, receiving on any stream returns an error.
+///
+/// ```
+/// # use tinyroute::client::{SharedClient, TcpClient};
+/// # async fn run() {
+/// let client = TcpClient::connect("127.0.0.1:5000").await.unwrap();
+/// let shared_client = SharedClient::new(client, None).unwrap();
+///
+/// let stream = shared_client.stream();
+/// stream.send(b"hello world").unwrap();
+/// let reply = stream.recv_async().await.unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SharedClient {
+    inner: Arc<SharedInner>,
+}
+
+struct SharedInner {
+    writer_tx: ClientSender,
+    streams: Mutex<Streams>,
+}
+
+struct Streams {
+    next_id: u32,
+    senders: FxHashMap<u32, Sender<Vec<u8>>>,
+}
+
+impl SharedInner {
+    fn streams(&self) -> MutexGuard<'_, Streams> {
+        self.streams.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for SharedInner {
+    fn drop(&mut self) {
+        let _ = self.writer_tx.send(ClientMessage::Quit);
+    }
+}
+
+impl SharedClient {
+    /// Create a shared client from a connection.
+    /// See [`connect`] for the heartbeat.
+    pub fn new(connection: impl Client, heartbeat: Option<Heartbeat>) -> Result<Self> {
+        let (writer_tx, reader_rx) = connect(connection, heartbeat)?;
+        let streams = Streams { next_id: 0, senders: FxHashMap::default() };
+        let inner = Arc::new(SharedInner { writer_tx, streams: Mutex::new(streams) });
+        spawn(route_streams(reader_rx, Arc::downgrade(&inner)));
+        Ok(Self { inner })
+    }
+
+    /// Open a new stream on the connection.
+    pub fn stream(&self) -> SharedStream {
+        let (tx, rx) = flume::unbounded();
+        let mut streams = self.inner.streams();
+
+        let mut id = streams.next_id;
+        while streams.senders.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        streams.next_id = id.wrapping_add(1);
+        streams.senders.insert(id, tx);
+
+        SharedStream { id, inner: self.inner.clone(), rx }
+    }
+}
+
+/// A stream on a [`SharedClient`].
+pub struct SharedStream {
+    id: u32,
+    inner: Arc<SharedInner>,
+    rx: ClientReceiver,
+}
+
+impl SharedStream {
+    /// The id prefixing every payload on this stream
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Send a payload to the server, prefixed with the stream id.
+    pub fn send(&self, payload: &[u8]) -> Result<()> {
+        let mut buf = Vec::with_capacity(STREAM_ID_LEN + payload.len());
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(payload);
+        let framed_message = Frame::frame_message(&buf);
+        self.inner
+            .writer_tx
+            .send(ClientMessage::Payload(framed_message))
+            .map_err(|_| Error::ChannelClosed)
+    }
+
+    /// Receive a reply for this stream, without the stream id.
+    pub async fn recv_async(&self) -> Result<Vec<u8>> {
+        Ok(self.rx.recv_async().await?)
+    }
+}
+
+impl Drop for SharedStream {
+    fn drop(&mut self) {
+        self.inner.streams().senders.remove(&self.id);
+    }
+}
+
+// Pass each payload on to the stream with the id at the start of the payload.
+async fn route_streams(reader_rx: ClientReceiver, inner: Weak<SharedInner>) {
+    while let Ok(payload) = reader_rx.recv_async().await {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        if payload.len() < STREAM_ID_LEN {
+            warn!("Payload is missing a stream id");
+            continue;
+        }
+
+        let mut id = [0u8; STREAM_ID_LEN];
+        id.copy_from_slice(&payload[..STREAM_ID_LEN]);
+        let id = u32::from_be_bytes(id);
+
+        let tx = inner.streams().senders.get(&id).cloned();
+        match tx {
+            Some(tx) => {
+                let _ = tx.send_async(payload[STREAM_ID_LEN..].to_vec()).await;
+            }
+            None => warn!("No stream with id {}", id),
+        }
+    }
+
+    // The connection is closed
+    if let Some(inner) = inner.upgrade() {
+        inner.streams().senders.clear();
+    }
+}
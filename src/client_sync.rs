@@ -3,15 +3,15 @@
 //! use tokio::io::{AsyncReadExt, AsyncWriteExt};
 //! use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 //! use tokio::sync::mpsc;
-//! use tinyroute::client::{ClientSender, ClientReceiver, ClientMessage, connect, Client, TcpClient};
+//! use tinyroute::client::{ClientSender, ClientReceiver, ClientMessage, connect, Client, Heartbeat, TcpClient};
 //! use tinyroute::frame::Frame;
 //!
 //! #[tokio::main]
 //! async fn main() {
 //!     # let _ = async move {
 //!     let client = TcpClient::connect("127.0.0.1:5000").await.unwrap();
-//!     let heartbeat = std::time::Duration::from_secs(30);
-//!     let (send, rec) = connect(client, Some(heartbeat));
+//!     let heartbeat = Heartbeat::new(std::time::Duration::from_secs(30));
+//!     let (send, rec) = connect(client, Some(heartbeat)).unwrap();
 //!     let (tx, rx) = mpsc::channel(10);
 //!
 //!     tokio::spawn(receiver(rec, tx));
@@ -45,7 +45,7 @@ use log::{error, info};
 use crate::errors::{Error, Result};
 use crate::frame::{Frame, FrameOutput, FramedMessage};
 use crate::ADDRESS_SEP;
-use crate::client::jitter;
+use crate::client::{jitter, validate_heartbeat};
 use flume::{Receiver, Sender};
 
 /// Type alias for `tokio::mpsc::Receiver<Vec<u8>>`
@@ -168,8 +168,13 @@ impl Client for TcpClient {
     }
 }
 
-/// Get a [`ClientSender`] and [`ClientReceiver`] pair
+/// Get a [`ClientSender`] and [`ClientReceiver`] pair.
+///
+/// The heartbeat has to be longer than a second,
+/// otherwise [`crate::errors::Error::InvalidHeartbeat`] is returned.
 pub fn connect(connection: impl Client, heartbeat: Option<Duration>) -> Result<(ClientSender, ClientReceiver)> {
+    validate_heartbeat(heartbeat)?;
+
     let (writer_tx, writer_rx) = flume::unbounded();
     let (reader_tx, reader_rx) = flume::unbounded();
 
@@ -193,7 +198,7 @@ pub fn run_heartbeat(freq: Duration, writer_tx: Sender<ClientMessage>) {
     assert!(freq.as_millis() > 1000, "Heart beat should never be less than a second");
 
     loop {
-        thread::sleep(freq - jitter());
+        thread::sleep(freq.saturating_sub(jitter()));
         if let Err(e) = writer_tx.send(ClientMessage::Heartbeat) {
             error!("Failed to send heartbeat to writer: {}", e);
             break;
@@ -253,7 +258,7 @@ fn use_writer(mut writer: impl std::io::Write + Send + 'static, rx: Receiver<Cli
                 }
             }
             ClientMessage::Payload(payload) => {
-                if let Err(e) = writer.write_all(&payload.0) {
+                if let Err(e) = writer.write_all(payload.as_bytes()) {
                     error!("Failed to write payload: {}", e);
                     break;
                 }
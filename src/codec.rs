@@ -0,0 +1,186 @@
+//! A compact, versioned byte encoding for addresses.
+//!
+//! Rather than hand writing [`crate::ToAddress::from_bytes`],
+//! derive [`AddressCodec`] with `#[derive(ToAddressCodec)]` (requires the `derive` feature)
+//! and decode the address with [`AddressCodec::decode`]:
+//!
+//! ```
+//! # #[cfg(feature = "derive")]
+//! # {
+//! use tinyroute::codec::{AddressCodec, ToAddressCodec};
+//! use tinyroute::ToAddress;
+//!
+//! #[derive(Debug, Clone, PartialEq, Eq, Hash, ToAddressCodec)]
+//! enum Address {
+//!     Log,
+//!     Connection(u64),
+//!     Worker { pool: String, id: u16 },
+//! }
+//!
+//! impl ToAddress for Address {
+//!     fn from_bytes(bytes: &[u8]) -> Option<Self> {
+//!         Self::decode(bytes)
+//!     }
+//! }
+//!
+//! let address = Address::Worker { pool: "io".into(), id: 3 };
+//! assert_eq!(Some(address.clone()), Address::from_bytes(&address.encode()));
+//! # }
+//! ```
+//!
+//! An encoded address starts with [`CODEC_VERSION`], followed by the value.
+//! Integers are encoded big endian, a `String` is prefixed with its length as a `u32`,
+//! and an `Option` with a byte: zero for `None`, one for `Some`.
+//!
+//! As the recipient of a message on the wire ends at the first [`crate::ADDRESS_SEP`],
+//! an encoded address never contains it: [`ADDRESS_SEP`] is written as
+//! [`ESCAPE`] followed by the byte xor `0x20`, and so is [`ESCAPE`] itself.
+
+use crate::ADDRESS_SEP;
+
+/// The version of the encoding, written as the first byte of every encoded address.
+pub const CODEC_VERSION: u8 = 1;
+
+/// Escapes [`ADDRESS_SEP`] (and itself) in an encoded address.
+pub const ESCAPE: u8 = 0x7D;
+
+fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(bytes.len());
+    for &b in bytes {
+        match b {
+            ADDRESS_SEP | ESCAPE => buf.extend_from_slice(&[ESCAPE, b ^ 0x20]),
+            _ => buf.push(b),
+        }
+    }
+    buf
+}
+
+fn unescape(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut buf = Vec::with_capacity(bytes.len());
+    let mut bytes = bytes.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            ADDRESS_SEP => return None,
+            ESCAPE => match bytes.next()? ^ 0x20 {
+                b @ (ADDRESS_SEP | ESCAPE) => buf.push(b),
+                _ => return None,
+            },
+            _ => buf.push(b),
+        }
+    }
+    Some(buf)
+}
+
+#[cfg(feature = "derive")]
+pub use tinyroute_derive::ToAddressCodec;
+
+/// Encode and decode a value as part of an address.
+pub trait AddressCodec: Sized {
+    /// Append the value to `buf`
+    fn encode_into(&self, buf: &mut Vec<u8>);
+
+    /// Decode a value from the front of `bytes`, advancing `bytes` past it.
+    /// Returns `None` if the bytes are not a valid value.
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self>;
+
+    /// Encode the value as an address, prefixed with the [`CODEC_VERSION`]
+    /// and with any [`ADDRESS_SEP`] escaped.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![CODEC_VERSION];
+        self.encode_into(&mut buf);
+        escape(&buf)
+    }
+
+    /// Decode an address encoded with [`AddressCodec::encode`].
+    ///
+    /// Returns `None` if the version doesn't match, the bytes are not a valid
+    /// address (e.g an unknown variant), or there are bytes left over.
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes = unescape(bytes)?;
+        let (&version, mut bytes) = bytes.split_first()?;
+        if version != CODEC_VERSION {
+            return None;
+        }
+
+        let value = Self::decode_from(&mut bytes)?;
+        match bytes.is_empty() {
+            true => Some(value),
+            false => None,
+        }
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if bytes.len() < len {
+        return None;
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Some(head)
+}
+
+macro_rules! int_codec {
+    ($($int:ty),*) => {
+        $(
+            impl AddressCodec for $int {
+                fn encode_into(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+                    let int_bytes = take(bytes, std::mem::size_of::<$int>())?;
+                    Some(<$int>::from_be_bytes(int_bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+int_codec!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl AddressCodec for bool {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(bytes)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl AddressCodec for String {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        (self.len() as u32).encode_into(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        let len = u32::decode_from(bytes)? as usize;
+        let string_bytes = take(bytes, len)?;
+        String::from_utf8(string_bytes.to_vec()).ok()
+    }
+}
+
+impl<T: AddressCodec> AddressCodec for Option<T> {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            None => buf.push(0),
+            Some(value) => {
+                buf.push(1);
+                value.encode_into(buf);
+            }
+        }
+    }
+
+    fn decode_from(bytes: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(bytes)? {
+            0 => Some(None),
+            1 => Some(Some(T::decode_from(bytes)?)),
+            _ => None,
+        }
+    }
+}
@@ -26,9 +26,18 @@ pub enum Error {
     #[error("Failed to register agent")]
     RegisterAgentFailed,
 
+    #[error("The router is shut down, or shutting down")]
+    RouterGone,
+
     #[error("Failed to deliver the message to the router")]
     RouterUnrecoverableError,
 
+    #[error("The router channel is full")]
+    RouterBusy,
+
+    #[error("Too many messages are queued across all agents")]
+    QueuesFull,
+
     #[error("Failed to send message to another channel")]
     GenericChannelSendError,
 
@@ -44,6 +53,27 @@ pub enum Error {
     #[error("Address already registered")]
     AddressRegistered,
 
+    #[error("The heartbeat has to be longer than a second")]
+    InvalidHeartbeat,
+
+    #[error("The agent is tracking too many addresses")]
+    TooManyTracks,
+
+    #[error("The agent has too many timers pending")]
+    TooManyTimers,
+
+    #[error("Expected a file descriptor with the message")]
+    MissingFileDescriptor,
+
+    #[error("Invalid capacity: it has to be at least one")]
+    InvalidCapacity,
+
+    #[error("Timed out")]
+    Timeout,
+
+    #[error("Failed to deliver the message to: {}", .0.join(", "))]
+    Undelivered(Vec<String>),
+
     #[error("Bridgemalarkey")]
     Bridge(#[from] crate::bridge::BridgeError),
 }
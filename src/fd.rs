@@ -0,0 +1,174 @@
+//! Pass file descriptors over a unix domain socket (`SCM_RIGHTS`).
+//!
+//! The payload is framed the same way as any other message,
+//! and the file descriptor is attached to the first byte of the frame.
+//!
+//! A frame carrying a file descriptor has to be read with [`recv_fd`],
+//! as the file descriptor is lost if the frame is read as regular bytes.
+//!
+//! ```
+//! use std::os::unix::io::AsRawFd;
+//! # use tinyroute::server::UnixStream;
+//! # async fn run(stream: UnixStream, file: std::fs::File) {
+//! tinyroute::fd::send_fd(&stream, file.as_raw_fd(), b"a file").await.unwrap();
+//! # }
+//! ```
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use tokio::io::Interest;
+use tokio::net::UnixStream;
+
+use crate::errors::{Error, Result};
+use crate::frame::{Frame, Header};
+
+/// Send a file descriptor and a payload.
+///
+/// The file descriptor stays open for the sender,
+/// the receiver gets its own copy.
+pub async fn send_fd(stream: &UnixStream, fd: RawFd, payload: &[u8]) -> Result<()> {
+    let framed_message = Frame::frame_message(payload);
+    let bytes = framed_message.into_bytes();
+
+    let mut sent = stream
+        .async_io(Interest::WRITABLE, || sendmsg(stream.as_raw_fd(), &bytes, fd))
+        .await?;
+
+    while sent < bytes.len() {
+        sent += stream
+            .async_io(Interest::WRITABLE, || stream.try_write(&bytes[sent..]))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Receive a file descriptor and the payload sent with it.
+pub async fn recv_fd(stream: &UnixStream) -> Result<(OwnedFd, Vec<u8>)> {
+    let mut header = [0u8; 1];
+    let (read, fd) = stream
+        .async_io(Interest::READABLE, || recvmsg(stream.as_raw_fd(), &mut header))
+        .await?;
+
+    if read == 0 {
+        return Err(Error::ChannelClosed);
+    }
+
+    let fd = fd.ok_or(Error::MissingFileDescriptor)?;
+
+    let len = match Header::from_u8(header[0]) {
+        Some(Header::Small) => {
+            let mut len = [0u8; size_of::<u8>()];
+            read_exact(stream, &mut len).await?;
+            len[0] as usize
+        }
+        Some(Header::Large) => {
+            let mut len = [0u8; size_of::<u32>()];
+            read_exact(stream, &mut len).await?;
+            u32::from_be_bytes(len) as usize
+        }
+        _ => return Err(Error::MalformedHeader),
+    };
+
+    let mut payload = vec![0u8; len];
+    read_exact(stream, &mut payload).await?;
+
+    Ok((fd, payload))
+}
+
+async fn read_exact(stream: &UnixStream, buf: &mut [u8]) -> Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        let n = stream
+            .async_io(Interest::READABLE, || stream.try_read(&mut buf[read..]))
+            .await?;
+        if n == 0 {
+            return Err(Error::ChannelClosed);
+        }
+        read += n;
+    }
+    Ok(())
+}
+
+// Room for the control message header and a single file descriptor,
+// as `u64`s to keep the buffer aligned for `cmsghdr`.
+const CMSG_BUF_LEN: usize = 8;
+
+// Received file descriptors are closed on exec, so they don't leak into child processes.
+// Where the flag isn't supported it's set on each file descriptor once it's received
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+const RECV_FLAGS: libc::c_int = 0;
+
+fn sendmsg(socket: RawFd, bytes: &[u8], fd: RawFd) -> io::Result<usize> {
+    let mut cmsg_buf = [0u64; CMSG_BUF_LEN];
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut libc::c_void,
+        iov_len: bytes.len(),
+    };
+
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(size_of::<RawFd>() as u32) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+        match libc::sendmsg(socket, &msg, 0) {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
+        }
+    }
+}
+
+fn recvmsg(socket: RawFd, buf: &mut [u8]) -> io::Result<(usize, Option<OwnedFd>)> {
+    let mut cmsg_buf = [0u64; CMSG_BUF_LEN];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&cmsg_buf) as _;
+
+        let read = match libc::recvmsg(socket, &mut msg, RECV_FLAGS) {
+            n if n < 0 => return Err(io::Error::last_os_error()),
+            n => n as usize,
+        };
+
+        // Only the first file descriptor is returned,
+        // any others sent along with it are closed as they're dropped
+        let mut fd = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+                for i in 0..count {
+                    let received = OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i)));
+                    if RECV_FLAGS == 0 {
+                        libc::fcntl(received.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC);
+                    }
+                    if fd.is_none() {
+                        fd = Some(received);
+                    }
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        Ok((read, fd))
+    }
+}
@@ -2,6 +2,7 @@
 use std::convert::TryInto;
 use std::mem::size_of;
 use std::ops::Range;
+use std::sync::Arc;
 
 use bytes::{Bytes, BufMut, BytesMut};
 
@@ -18,16 +19,139 @@ const HEADER_SIZE: usize = 1;
 pub enum FrameOutput {
     /// Framed message (excluding the content length and header)
     Message(Vec<u8>),
-    /// A heartbeat
+    /// A heartbeat.
+    /// The server and client readers consume these, so they never reach an agent.
     Heartbeat,
 }
 
 /// A message that has a header byte and a content length
 /// prefixed to it.
+///
+/// The client writer and the bridge write a `FramedMessage` as is,
+/// so keep framed bytes in a `FramedMessage` rather than passing them
+/// to [`Frame::frame_message`] again, which would prefix them twice.
+///
+/// A `FramedMessage` can only be made by framing the bytes, or with
+/// [`FramedMessage::from_framed`] which checks they are already framed,
+/// so unframed bytes can't be written as is:
+///
+/// ```compile_fail,E0423
+/// use tinyroute::frame::FramedMessage;
+///
+/// let unframed = FramedMessage(tinyroute::Bytes::from_static(b"hello world"));
+/// ```
 #[derive(Debug, Clone)]
-pub struct FramedMessage(pub Bytes);
+pub struct FramedMessage(Bytes);
+
+impl FramedMessage {
+    /// Use bytes that are already framed as a `FramedMessage`,
+    /// rather than framing them again.
+    ///
+    /// Returns [`Error::MalformedHeader`] unless `bytes`
+    /// is exactly one message frame.
+    ///
+    /// ```
+    /// use tinyroute::frame::{Frame, FramedMessage};
+    ///
+    /// let frame = Frame::frame_message(b"hello world").into_bytes();
+    /// assert!(FramedMessage::from_framed(frame.clone()).is_ok());
+    /// assert!(FramedMessage::from_framed(frame.slice(1..)).is_err());
+    /// ```
+    pub fn from_framed(bytes: Bytes) -> Result<Self> {
+        Self::content_offset(&bytes)?;
+        Ok(Self(bytes))
+    }
+
+    /// The framed bytes: the header, the content length and the content
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+
+    /// The framed bytes, see [`FramedMessage::as_bytes`]
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+
+    /// The content, without the header and content length
+    pub(crate) fn content(&self) -> Bytes {
+        match Self::content_offset(&self.0) {
+            Ok(offset) => self.0.slice(offset..),
+            Err(_) => self.0.clone(),
+        }
+    }
+
+    // Where the content starts, if `bytes` is exactly one message frame
+    fn content_offset(bytes: &Bytes) -> Result<usize> {
+        let (offset, content_len) = match bytes.first().copied().and_then(Header::from_u8) {
+            Some(Header::Small) if bytes.len() > size_of::<u8>() => {
+                (HEADER_SIZE + size_of::<u8>(), bytes[HEADER_SIZE] as usize)
+            }
+            Some(Header::Large) if bytes.len() >= HEADER_SIZE + size_of::<u32>() => {
+                let mut len = [0u8; size_of::<u32>()];
+                len.copy_from_slice(&bytes[HEADER_SIZE..HEADER_SIZE + size_of::<u32>()]);
+                (HEADER_SIZE + size_of::<u32>(), u32::from_be_bytes(len) as usize)
+            }
+            _ => return Err(Error::MalformedHeader),
+        };
+
+        match offset + content_len == bytes.len() {
+            true => Ok(offset),
+            false => Err(Error::MalformedHeader),
+        }
+    }
+}
 
 
+/// The direction of a frame seen by a [`FrameTap`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A frame read from the connection
+    Inbound,
+    /// A frame written to the connection
+    Outbound,
+}
+
+/// Observe every message frame on a connection, e.g to debug a protocol.
+///
+/// Outbound frames are seen as they are written, including the header and content length.
+/// Inbound frames are seen once decoded, so only the content.
+/// Heartbeats are not passed to the tap.
+///
+/// ```
+/// use std::sync::Arc;
+/// use tinyroute::frame::{Direction, FrameTap};
+///
+/// let tap: FrameTap = Arc::new(|direction: Direction, bytes: &tinyroute::Bytes| {
+///     eprintln!("{:?} {:?}", direction, bytes);
+/// });
+/// ```
+pub type FrameTap = Arc<dyn Fn(Direction, &Bytes) + Send + Sync>;
+
+/// Rewrite the content of every message frame on a connection,
+/// e.g to wrap and unwrap an envelope when bridging to a slightly different protocol.
+///
+/// Unlike a [`FrameTap`] the transform sees only the content, never the header and content length:
+/// * Outbound content is transformed first, then framed and written.
+///   A [`FrameTap`] sees the transformed frame, as written.
+/// * Inbound frames are decoded first, then the content is transformed.
+///   A [`FrameTap`] sees the content as read, before it's transformed.
+///
+/// tinyroute doesn't compress frames, so a transform that compresses
+/// has to do so itself, and the peer has to undo it.
+/// Heartbeats are not passed to the transform.
+///
+/// ```
+/// use std::sync::Arc;
+/// use tinyroute::frame::{Direction, FrameTransform};
+/// use tinyroute::Bytes;
+///
+/// let transform: FrameTransform = Arc::new(|direction: Direction, bytes: Bytes| match direction {
+///     Direction::Outbound => [b"env|".as_ref(), &bytes].concat().into(),
+///     Direction::Inbound => bytes.slice(4.min(bytes.len())..),
+/// });
+/// ```
+pub type FrameTransform = Arc<dyn Fn(Direction, Bytes) -> Bytes + Send + Sync>;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 #[non_exhaustive]
@@ -39,7 +163,7 @@ pub enum Header {
 }
 
 impl Header {
-    const fn from_u8(val: u8) -> Option<Self> {
+    pub(crate) const fn from_u8(val: u8) -> Option<Self> {
         match val {
             0 => Some(Header::Unset),
             1 => Some(Header::Small),
@@ -168,24 +292,31 @@ impl Frame {
     /// # }
     /// ```
     pub fn frame_message(data: &[u8]) -> FramedMessage {
-        let (header, size) = match data.len() as u64 {
+        let mut payload = Self::frame_header(data.len(), data.len());
+        payload.put(data);
+
+        FramedMessage(payload.freeze())
+    }
+
+    // The header and content length of a frame of `len` bytes,
+    // with room for `capacity` more bytes
+    pub(crate) fn frame_header(len: usize, capacity: usize) -> BytesMut {
+        let (header, size) = match len as u64 {
             i if i <= u8::MAX as u64 => (Header::Small, size_of::<u8>()),
             i if i <= u32::MAX as u64 => (Header::Large, size_of::<u32>()),
             _ => panic!("Invalid content length"),
         };
 
-        let mut payload = BytesMut::with_capacity(data.len() + size + size_of::<Header>());
+        let mut payload = BytesMut::with_capacity(capacity + size + size_of::<Header>());
         payload.put_u8(header as u8);
 
         match header {
-            Header::Small => payload.put_u8(data.len() as u8),
-            Header::Large => payload.put_u32(data.len() as u32),
+            Header::Small => payload.put_u8(len as u8),
+            Header::Large => payload.put_u32(len as u32),
             Header::Unset | Header::Heartbeat => unreachable!(),
         }
 
-        payload.put(data);
-
-        FramedMessage(payload.freeze())
+        payload
     }
 
     /// Try to produce a message.
@@ -198,10 +329,9 @@ impl Frame {
 
         let header = match Header::from_u8(self.buffer[0]) {
             Some(Header::Heartbeat) => {
-                // Since it's a heartbeat, just back the bytes_read up by one,
-                // so it's overwritten next time, as a `read` and `async_read` will 
-                // read into `self.available_slice_mut()`.
-                self.bytes_read -= 1;
+                // A heartbeat is a single byte: drop it from the front of the
+                // buffer so any message read along with it is left intact.
+                self.shift_down(1);
                 return Ok(Some(FrameOutput::Heartbeat));
             }
             Some(h) => h,
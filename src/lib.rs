@@ -1,21 +1,32 @@
 pub const ADDRESS_SEP: u8 = b'|';
 
+mod queues;
 mod router;
 
 pub mod agent;
 pub mod bridge;
 pub mod client;
 pub mod client_sync;
+pub mod codec;
 pub mod errors;
+#[cfg(unix)]
+pub mod fd;
 pub mod frame;
+pub mod sequence;
 pub mod server;
+#[cfg(feature = "testkit")]
+pub mod testkit;
 
 // -----------------------------------------------------------------------------
 //     - Reexportes -
 // -----------------------------------------------------------------------------
-pub use agent::{Agent, Message};
+pub use agent::{Agent, AgentSender, AgentStream, Cap, CapPolicy, Message, RemovalReason, DEFAULT_MAX_TIMERS};
 pub use bytes::Bytes;
-pub use router::{Router, RouterTx, ToAddress};
+#[cfg(feature = "debug-queues")]
+pub use queues::QueuedMeta;
+pub use router::{DropReason, FromAddressStr, DeliveryOrder, Router, RouterTx, ToAddress, DEFAULT_MAX_TRACKS, SHUTDOWN_TIMEOUT};
+#[cfg(feature = "derive")]
+pub use tinyroute_derive::ToAddress;
 
 pub mod channels {
     pub use flume::{bounded, unbounded, Receiver, Sender};
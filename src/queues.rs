@@ -0,0 +1,146 @@
+//! Metadata about the messages queued for each agent,
+//! to help figure out why an agent isn't making progress.
+//!
+//! Nothing is recorded unless the `debug-queues` feature is enabled
+//! (the `timing` feature enables it as well).
+use std::marker::PhantomData;
+
+#[cfg(feature = "debug-queues")]
+use std::collections::VecDeque;
+#[cfg(feature = "debug-queues")]
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+#[cfg(feature = "debug-queues")]
+use fxhash::FxHashMap;
+
+use crate::ToAddress;
+
+/// A message waiting to be received by an agent.
+#[cfg(feature = "debug-queues")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuedMeta<A> {
+    /// The address of the sender.
+    /// `None` for fetch requests and anything sent by the router itself,
+    /// such as `Message::AgentRemoved` and `Message::Shutdown`.
+    pub sender: Option<A>,
+    /// When the router handed the message to the agent
+    pub arrived_at: Instant,
+}
+
+// -----------------------------------------------------------------------------
+//     - Queue log -
+//     The metadata of the messages in a single agents channel,
+//     shared between the router and the agent.
+// -----------------------------------------------------------------------------
+#[derive(Clone)]
+pub(crate) struct QueueLog<A> {
+    #[cfg(feature = "debug-queues")]
+    inner: Arc<Mutex<VecDeque<QueuedMeta<A>>>>,
+    _p: PhantomData<fn() -> A>,
+}
+
+impl<A: ToAddress> QueueLog<A> {
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(feature = "debug-queues")]
+            inner: Arc::new(Mutex::new(VecDeque::new())),
+            _p: PhantomData,
+        }
+    }
+
+    // The agent received the oldest message.
+    // Returns when the router handed it to the agent, if that was recorded
+    #[cfg(feature = "debug-queues")]
+    pub(crate) fn pop(&self) -> Option<Instant> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).pop_front().map(|meta| meta.arrived_at)
+    }
+
+    #[cfg(not(feature = "debug-queues"))]
+    pub(crate) fn pop(&self) -> Option<Instant> {
+        None
+    }
+
+    #[cfg(feature = "debug-queues")]
+    fn push(&self, sender: Option<&A>) {
+        let meta = QueuedMeta { sender: sender.cloned(), arrived_at: Instant::now() };
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).push_back(meta);
+    }
+
+    #[cfg(feature = "debug-queues")]
+    fn snapshot(&self) -> Vec<QueuedMeta<A>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).iter().cloned().collect()
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Queue logs -
+//     Queue logs for all registered addresses.
+//     This is shared so the queues can be inspected while the router is running.
+// -----------------------------------------------------------------------------
+#[derive(Clone)]
+pub(crate) struct QueueLogs<A> {
+    #[cfg(feature = "debug-queues")]
+    inner: Arc<Mutex<FxHashMap<A, QueueLog<A>>>>,
+    _p: PhantomData<fn() -> A>,
+}
+
+impl<A: ToAddress> QueueLogs<A> {
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(feature = "debug-queues")]
+            inner: Arc::new(Mutex::new(FxHashMap::default())),
+            _p: PhantomData,
+        }
+    }
+
+    #[allow(unused_variables)]
+    pub(crate) fn insert(&self, address: A, log: QueueLog<A>) {
+        #[cfg(feature = "debug-queues")]
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).insert(address, log);
+    }
+
+    // Register the log at `from` at `to` as well
+    #[allow(unused_variables)]
+    pub(crate) fn alias(&self, from: &A, to: A) {
+        #[cfg(feature = "debug-queues")]
+        {
+            let mut logs = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(log) = logs.get(from).cloned() {
+                logs.insert(to, log);
+            }
+        }
+    }
+
+    #[allow(unused_variables)]
+    pub(crate) fn remove(&self, address: &A) {
+        #[cfg(feature = "debug-queues")]
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner).remove(address);
+    }
+
+    // A message is about to be sent to `recipient`
+    #[allow(unused_variables)]
+    pub(crate) fn push(&self, recipient: &A, sender: Option<&A>) {
+        #[cfg(feature = "debug-queues")]
+        if let Some(log) = self.inner.lock().unwrap_or_else(PoisonError::into_inner).get(recipient) {
+            log.push(sender);
+        }
+    }
+
+    // The oldest message queued for `address` was dropped
+    #[allow(unused_variables)]
+    pub(crate) fn pop(&self, address: &A) {
+        #[cfg(feature = "debug-queues")]
+        if let Some(log) = self.inner.lock().unwrap_or_else(PoisonError::into_inner).get(address) {
+            let _ = log.pop();
+        }
+    }
+
+    #[cfg(feature = "debug-queues")]
+    pub(crate) fn inspect(&self, address: &A) -> Vec<QueuedMeta<A>> {
+        match self.inner.lock().unwrap_or_else(PoisonError::into_inner).get(address) {
+            Some(log) => log.snapshot(),
+            None => Vec::new(),
+        }
+    }
+}
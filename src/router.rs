@@ -1,14 +1,22 @@
+use std::collections::VecDeque;
+use std::future::Future;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::Bytes;
 use log::{error, info, warn};
-use flume::{bounded, Receiver, Sender};
+use flume::{bounded, Receiver, Sender, TryRecvError, TrySendError};
 use fxhash::FxHashMap;
 
-use crate::agent::{Agent, AgentMsg, AnyMessage};
+use crate::agent::{Agent, AgentMsg, AgentSender, AnyMessage, Message, RemovalReason};
 use crate::errors::{Error, Result};
+#[cfg(feature = "debug-queues")]
+use crate::queues::QueuedMeta;
+use crate::queues::{QueueLog, QueueLogs};
 use crate::server::ConnectionAddr;
-use tokio::spawn;
+use tokio::time::{timeout_at, Instant};
 
 // -----------------------------------------------------------------------------
 //     - Request -
@@ -69,20 +77,80 @@ impl<T: Send + 'static> Response<T> {
     }
 }
 
+// -----------------------------------------------------------------------------
+//     - Loop lag -
+//     The longest the router took to handle a message since the lag was last read
+// -----------------------------------------------------------------------------
+#[derive(Debug, Default)]
+pub(crate) struct LoopLag(AtomicU64);
+
+impl LoopLag {
+    fn record(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.0.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> Duration {
+        Duration::from_nanos(self.0.swap(0, Ordering::Relaxed))
+    }
+}
+
 // -----------------------------------------------------------------------------
 //     - Router TX -
 // -----------------------------------------------------------------------------
 #[derive(Clone)]
-pub struct RouterTx<A: ToAddress>(pub(crate) Sender<RouterMessage<A>>);
+pub struct RouterTx<A: ToAddress>(
+    pub(crate) Sender<RouterMessage<A>>,
+    pub(crate) QueueLogs<A>,
+    pub(crate) Arc<QueueBudget>,
+    pub(crate) Arc<LoopLag>,
+);
 
 impl<A: ToAddress> RouterTx<A> {
-    pub(crate) async fn register_agent(&self, address: A, tx: Sender<AgentMsg<A>>) -> Result<()> {
-        let (success_tx, success_rx) = bounded(0);
-        self.0.send_async(RouterMessage::Register(address, tx, success_tx)).await.map_err(|_| Error::RegisterAgentFailed)?;
+    pub(crate) fn queued(&self) -> Result<Option<Queued>> {
+        self.2.acquire()
+    }
+
+    pub(crate) async fn register_agent(
+        &self,
+        address: A,
+        tx: Sender<AgentMsg<A>>,
+        queue: QueueLog<A>,
+        lossy: Option<Lossy<A>>,
+    ) -> Result<()> {
+        let (success_tx, success_rx) = bounded(1);
+        self.0.send_async(RouterMessage::Register(address, tx, queue, lossy, success_tx)).await.map_err(|_| Error::RouterGone)?;
+        match success_rx.recv_async().await {
+            Ok(res) => res,
+            Err(_) if self.0.is_disconnected() => Err(Error::RouterGone),
+            Err(_) => Err(Error::RegisterAgentFailed),
+        }
+    }
+
+    // Register the channel at `from` at `to` as well
+    pub(crate) async fn register_alias(&self, from: A, to: A) -> Result<()> {
+        let (success_tx, success_rx) = bounded(1);
+        self.0.send_async(RouterMessage::RegisterAlias { from, to, success_tx }).await.map_err(|_| Error::RegisterAgentFailed)?;
         success_rx.recv_async().await.map_err(|_| Error::RegisterAgentFailed)?;
         Ok(())
     }
 
+    pub(crate) async fn track(&self, from: A, to: A) -> Result<()> {
+        let (success_tx, success_rx) = bounded(1);
+        self.send(RouterMessage::Track { from, to, success_tx }).await?;
+        match success_rx.recv_async().await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(Error::TooManyTracks),
+            Err(_) => Err(Error::RouterUnrecoverableError),
+        }
+    }
+
+    pub(crate) async fn arrive_barrier(&self, name: String, addr: A, expected: usize) -> Result<()> {
+        let (release_tx, release_rx) = bounded(1);
+        self.send(RouterMessage::ArriveBarrier { name, addr, expected, release_tx }).await?;
+        release_rx.recv_async().await.map_err(|_| Error::RouterGone)
+    }
+
     pub(crate) async fn send(&self, msg: RouterMessage<A>) -> Result<()> {
         match self.0.send_async(msg).await {
             Ok(()) => Ok(()),
@@ -90,6 +158,23 @@ impl<A: ToAddress> RouterTx<A> {
         }
     }
 
+    pub(crate) fn try_send(&self, msg: RouterMessage<A>) -> Result<()> {
+        match self.0.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Error::RouterBusy),
+            Err(TrySendError::Disconnected(_)) => Err(Error::RouterUnrecoverableError),
+        }
+    }
+
+    // Like `try_send`, but a message that didn't fit is returned rather than dropped
+    pub(crate) fn try_send_or_return(&self, msg: RouterMessage<A>) -> Result<Option<RouterMessage<A>>> {
+        match self.0.try_send(msg) {
+            Ok(()) => Ok(None),
+            Err(TrySendError::Full(msg)) => Ok(Some(msg)),
+            Err(TrySendError::Disconnected(_)) => Err(Error::RouterUnrecoverableError),
+        }
+    }
+
     pub(crate) fn send_sync(&self, msg: RouterMessage<A>) -> Result<()> {
         match self.0.send(msg) {
             Ok(()) => Ok(()),
@@ -97,6 +182,47 @@ impl<A: ToAddress> RouterTx<A> {
         }
     }
 
+    /// Send a message from somewhere that can't wait for the router (e.g `Drop`).
+    /// If the router channel is full the message is sent from a new task,
+    /// and only if there is no runtime will this block.
+    pub(crate) fn send_detached(&self, msg: RouterMessage<A>) -> Result<()> {
+        match self.0.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Disconnected(_)) => Err(Error::RouterUnrecoverableError),
+            Err(TrySendError::Full(msg)) => match tokio::runtime::Handle::try_current() {
+                Ok(handle) => {
+                    let tx = self.0.clone();
+                    handle.spawn(async move {
+                        let _ = tx.send_async(msg).await;
+                    });
+                    Ok(())
+                }
+                Err(_) => self.send_sync(msg),
+            },
+        }
+    }
+
+    /// The messages queued for the agent at `address`, oldest first.
+    /// See [`Router::inspect_queue`].
+    #[cfg(feature = "debug-queues")]
+    pub fn inspect_queue(&self, address: &A) -> Vec<QueuedMeta<A>> {
+        self.1.inspect(address)
+    }
+
+    /// The longest the router took to handle a single message since this was last called.
+    /// See [`Router::loop_lag`].
+    pub fn loop_lag(&self) -> Duration {
+        self.3.take()
+    }
+
+    /// The number of queued messages and the capacity of each agent.
+    /// See [`Router::queue_utilization`].
+    pub async fn queue_utilization(&self) -> Result<Vec<(A, usize, usize)>> {
+        let (reply_tx, reply_rx) = bounded(1);
+        self.send(RouterMessage::QueueUtilization(reply_tx)).await?;
+        reply_rx.recv_async().await.map_err(|_| Error::RouterUnrecoverableError)
+    }
+
     /// Request data from another agent. There is no requirement 
     /// that the agent in question belongs to the same router.
     /// TODO: add example for `fetch`
@@ -115,6 +241,39 @@ impl<A: ToAddress> RouterTx<A> {
 }
 
 /// Convert bytes into an address, get a string representation of an address.
+///
+/// With the `derive` feature, `#[derive(ToAddress)]` implements this for an enum of
+/// unit variants, encoding each variant as a single tag byte: its index in the enum
+/// (so reordering or removing variants changes the tags).
+///
+/// ```
+/// # #[cfg(feature = "derive")]
+/// # {
+/// use tinyroute::ToAddress;
+///
+/// #[derive(Debug, Clone, PartialEq, Eq, Hash, ToAddress)]
+/// enum Address {
+///     Logger,
+///     Bridge,
+/// }
+///
+/// assert_eq!(vec![1], Address::Bridge.to_bytes());
+/// assert_eq!(Some(Address::Bridge), Address::from_bytes(&[1]));
+/// assert_eq!("Bridge", Address::Bridge.to_string());
+/// # }
+/// ```
+///
+/// Variants with fields can't be derived (use [`crate::codec`] for those):
+///
+#[cfg_attr(feature = "derive", doc = "```compile_fail")]
+#[cfg_attr(not(feature = "derive"), doc = "```ignore")]
+/// use tinyroute::ToAddress;
+///
+/// #[derive(Debug, Clone, PartialEq, Eq, Hash, ToAddress)]
+/// enum Address {
+///     Connection(u64),
+/// }
+/// ```
 pub trait ToAddress: Send + Clone + Eq + std::hash::Hash + 'static {
     fn from_bytes(_: &[u8]) -> Option<Self> {
         None
@@ -123,6 +282,59 @@ pub trait ToAddress: Send + Clone + Eq + std::hash::Hash + 'static {
     fn to_string(&self) -> String {
         "[not implemented for this address]".into()
     }
+
+    /// Encode the address to be sent over the wire, the reverse of [`ToAddress::from_bytes`].
+    ///
+    /// Defaults to the bytes of [`ToAddress::to_string`],
+    /// override it together with `from_bytes` for a more compact encoding.
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    /// An opaque address for the anonymous agent `id`, used by
+    /// [`Router::next_anonymous_address`].
+    /// Return `None` (the default) if the address can't represent one.
+    fn anonymous(_id: u64) -> Option<Self> {
+        None
+    }
+}
+
+/// Parse an address typed by a human, e.g `logger` or `id:42`.
+///
+/// This is separate from [`ToAddress::from_bytes`], which decodes
+/// addresses as they are sent over the wire.
+///
+/// ```
+/// use tinyroute::FromAddressStr;
+///
+/// #[derive(Debug, PartialEq)]
+/// enum Address {
+///     Logger,
+///     Id(usize),
+/// }
+///
+/// impl FromAddressStr for Address {
+///     fn from_str_addr(s: &str) -> Option<Self> {
+///         match s.split_once(':') {
+///             Some(("id", id)) => id.parse().ok().map(Address::Id),
+///             None if s == "logger" => Some(Address::Logger),
+///             _ => None,
+///         }
+///     }
+/// }
+///
+/// assert_eq!(Some((Address::Id(42), "hello")), Address::from_line("id:42|hello"));
+/// ```
+pub trait FromAddressStr: Sized {
+    fn from_str_addr(s: &str) -> Option<Self>;
+
+    /// Split a line such as `logger|hello` into the address and the rest of the line,
+    /// prefixed the same way as a message on the wire (see [`crate::ADDRESS_SEP`]).
+    /// White space around the address is ignored.
+    fn from_line(line: &str) -> Option<(Self, &str)> {
+        let (address, rest) = line.split_once(crate::ADDRESS_SEP as char)?;
+        Some((Self::from_str_addr(address.trim())?, rest))
+    }
 }
 
 // -----------------------------------------------------------------------------
@@ -130,21 +342,165 @@ pub trait ToAddress: Send + Clone + Eq + std::hash::Hash + 'static {
 // -----------------------------------------------------------------------------
 pub(crate) enum RouterMessage<A: ToAddress> {
     Message { recipient: A, sender: A, msg: AnyMessage },
+    MessageOnFail { recipient: A, sender: A, msg: AnyMessage, on_fail: OnFail },
+    Classified { sender: A, opcode: u32, msg: AnyMessage },
+    SendAll { sender: A, messages: Vec<(A, AnyMessage)>, failed_tx: Sender<Vec<A>> },
     Fetch(A, Request),
     // The only thing that should be sending these remote messages
     // are the reader halves of a socket!
     RemoteMessage { recipient: A, sender: A, bytes: Bytes, host: ConnectionAddr },
-    Register(A, Sender<AgentMsg<A>>, Sender<()>),
-    Track { from: A, to: A },
-    Unregister(A),
+    Register(A, Sender<AgentMsg<A>>, QueueLog<A>, Option<Lossy<A>>, Sender<Result<()>>),
+    RegisterAlias { from: A, to: A, success_tx: Sender<()> },
+    Track { from: A, to: A, success_tx: Sender<bool> },
+    Untrack { from: A, to: A },
+    Subscribe { topic: String, subscriber: A },
+    Unsubscribe { topic: String, subscriber: A },
+    Subscribers { topic: String, reply_tx: Sender<Vec<A>> },
+    ArriveBarrier { name: String, addr: A, expected: usize, release_tx: Sender<()> },
+    Unregister(A, RemovalReason),
+    Close(A, Sender<()>),
     Shutdown(A),
     PrintChannels,
+    QueueUtilization(Sender<Vec<(A, usize, usize)>>),
     ShutdownRouter,
+    ShutdownRouterGraceful(Duration),
+}
+
+// -----------------------------------------------------------------------------
+//     - Lossy -
+//     Rather than waiting for room in a full agent channel,
+//     the router evicts the oldest message.
+// -----------------------------------------------------------------------------
+#[derive(Clone)]
+pub(crate) struct Lossy<A> {
+    rx: Receiver<AgentMsg<A>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl<A> Lossy<A> {
+    pub(crate) fn new(rx: Receiver<AgentMsg<A>>, dropped: Arc<AtomicUsize>) -> Self {
+        Self { rx, dropped }
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Queue budget -
+//     Counts the messages sent between agents that are yet to be received,
+//     across all agents.
+// -----------------------------------------------------------------------------
+pub(crate) struct QueueBudget {
+    queued: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl QueueBudget {
+    fn new() -> Self {
+        Self { queued: AtomicUsize::new(0), max: AtomicUsize::new(usize::MAX) }
+    }
+
+    fn acquire(self: &Arc<Self>) -> Result<Option<Queued>> {
+        let max = self.max.load(Ordering::Relaxed);
+        if max == usize::MAX {
+            return Ok(None);
+        }
+
+        self.queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                (queued < max).then(|| queued + 1)
+            })
+            .map_err(|_| Error::QueuesFull)?;
+
+        Ok(Some(Queued(self.clone())))
+    }
+}
+
+// Held by a queued message, and released when the message
+// is received or dropped.
+pub(crate) struct Queued(Arc<QueueBudget>);
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Drop reason -
+// -----------------------------------------------------------------------------
+/// Why the router dropped a message. See [`Router::on_drop`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DropReason {
+    /// There is no agent registered at the recipient address
+    NoRecipient,
+    /// The recipient was dropped before the message could be delivered
+    RecipientGone,
+    /// The message was queued for a lossy agent
+    /// and was evicted to make room for a newer message
+    /// (or there was no room for it, and nothing to evict)
+    Evicted,
+    /// The message was sent with [`Agent::send_classified`] and the classifier
+    /// returned no recipient, or there is no classifier.
+    /// The address passed to [`Router::on_drop`] is the sender.
+    Unclassified,
+}
+
+/// Whether the router keeps messages to an agent in the order they were sent.
+/// See [`Router::ordering`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DeliveryOrder {
+    /// Messages from one sender to one recipient are delivered in the order they were sent.
+    ///
+    /// When the channel of a recipient is full the router waits for room,
+    /// holding up every other message behind it.
+    #[default]
+    Fifo,
+    /// When the channel of a recipient is full the message is handed to a task
+    /// that waits for room, and the router moves on to the next message.
+    ///
+    /// One slow agent no longer holds up messages to every other agent, but:
+    /// * Messages to an agent with a full channel can be delivered in any order,
+    ///   including messages from the same sender.
+    /// * A message waiting for room counts as delivered: it's dropped without calling
+    ///   [`Router::on_drop`] if the agent goes away before there is room for it.
+    /// * Every waiting message holds on to a task until there is room.
+    ///
+    /// Agents with room in their channel (and lossy agents, which never wait)
+    /// see messages in order, the same as with `Fifo`.
+    Relaxed,
 }
 
 // -----------------------------------------------------------------------------
 //     - Router -
 // -----------------------------------------------------------------------------
+/// The number of addresses an agent can track at once,
+/// unless changed with [`Router::max_tracks`].
+pub const DEFAULT_MAX_TRACKS: usize = 10_000;
+
+/// How long, in total, the router waits for room in full agent channels
+/// to send every agent a `Message::Shutdown` when shutting down
+/// (see [`Agent::shutdown_router`]).
+/// An agent that still has no room sees the channel closed instead.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+type OnDrop<A> = Box<dyn Fn(&DropReason, &A) + Send + Sync>;
+pub(crate) type OnFail = Box<dyn FnOnce(DropReason) + Send>;
+type Classifier<A> = Box<dyn Fn(&A, u32) -> Option<A> + Send + Sync>;
+
+// Dispatch messages in batches rather than one at a time
+#[derive(Clone, Copy)]
+struct Batching {
+    max_batch: usize,
+    max_delay: Duration,
+}
+
+// Agents waiting at a barrier (see `Agent::arrive_barrier`),
+// released once `expected` agents have arrived
+struct Barrier<A> {
+    expected: usize,
+    arrived: Vec<(A, Sender<()>)>,
+}
+
 /// The `Router` is in charge of routing messages
 /// between agents.
 ///
@@ -178,17 +534,111 @@ pub(crate) enum RouterMessage<A: ToAddress> {
 /// let val = agent_b.recv().await;
 /// # }
 /// ```
+///
+/// # Lifecycle
+///
+/// Agents can be created as soon as the router is, but nothing is routed
+/// until [`Router::run`] is awaited: until then messages queue up in the router channel,
+/// and are routed in the order they were sent once the router runs.
+/// Nothing sent before then is lost: once a router created with [`Router::with_capacity`]
+/// is full, [`Agent::send`] waits for the router to start and make room,
+/// and [`Agent::try_send`] returns [`Error::RouterBusy`].
+///
+/// Every [`Agent`] and [`AgentSender`] holds on to a [`RouterTx`], and the router
+/// keeps running for as long as any `RouterTx` exists.
+/// The router stops once it's shut down (see [`Agent::shutdown_router`]),
+/// or once every `RouterTx` has been dropped and the messages already sent have been routed.
+/// Once the router has stopped, sending to it fails.
 pub struct Router<A: ToAddress> {
+    batching: Option<Batching>,
     rx: Receiver<RouterMessage<A>>,
     tx: Sender<RouterMessage<A>>,
     channels: FxHashMap<A, Sender<AgentMsg<A>>>,
     subscriptions: FxHashMap<A, Vec<A>>,
+    topics: FxHashMap<String, Vec<A>>,
+    track_counts: FxHashMap<A, usize>,
+    max_tracks: usize,
+    barriers: FxHashMap<String, Barrier<A>>,
+    queues: QueueLogs<A>,
+    budget: Arc<QueueBudget>,
+    lossy: FxHashMap<A, Lossy<A>>,
+    graceful_timeout: Option<Duration>,
+    shutting_down: bool,
+    on_drop: Option<OnDrop<A>>,
+    classifier: Option<Classifier<A>>,
+    ordering: DeliveryOrder,
+    next_anonymous: u64,
+    lag: Arc<LoopLag>,
 }
 
 impl<A: ToAddress + Clone> Router<A> {
+    /// Create a router with an unbounded channel.
+    /// Sending a message to the router never has to wait.
     pub fn new() -> Self {
         let (tx, rx) = flume::unbounded();
-        Self { tx, rx, channels: FxHashMap::default(), subscriptions: FxHashMap::default() }
+        Self::from_channel(tx, rx)
+    }
+
+    /// Create a router where at most `cap` messages can be queued
+    /// before the router gets to them.
+    ///
+    /// Once the channel is full [`Agent::send`] waits for room, and
+    /// [`Agent::try_send`] returns [`Error::RouterBusy`].
+    ///
+    /// Returns [`Error::InvalidCapacity`] if `cap` is zero,
+    /// as every message would have to wait for the router to be ready for it.
+    pub fn with_capacity(cap: usize) -> Result<Self> {
+        if cap == 0 {
+            return Err(Error::InvalidCapacity);
+        }
+        let (tx, rx) = flume::bounded(cap);
+        Ok(Self::from_channel(tx, rx))
+    }
+
+    /// Create a router like [`Router::with_capacity`], that receives up to
+    /// `max_batch` messages before dispatching them.
+    ///
+    /// Once a message arrives the router takes any messages that are
+    /// already queued, and waits at most `max_delay` for the rest of the batch.
+    /// This means the router wakes up less often under load, at the cost
+    /// of holding on to a message for up to `max_delay` when traffic is light.
+    /// A `max_delay` of zero only batches messages that are already queued,
+    /// and adds no latency.
+    ///
+    /// Messages are dispatched in the order they were sent.
+    ///
+    /// Returns [`Error::InvalidCapacity`] if either `cap` or `max_batch` is zero.
+    pub fn new_batched(cap: usize, max_batch: usize, max_delay: Duration) -> Result<Self> {
+        if max_batch == 0 {
+            return Err(Error::InvalidCapacity);
+        }
+        let mut router = Self::with_capacity(cap)?;
+        router.batching = Some(Batching { max_batch, max_delay });
+        Ok(router)
+    }
+
+    fn from_channel(tx: Sender<RouterMessage<A>>, rx: Receiver<RouterMessage<A>>) -> Self {
+        Self {
+            batching: None,
+            tx,
+            rx,
+            channels: FxHashMap::default(),
+            subscriptions: FxHashMap::default(),
+            topics: FxHashMap::default(),
+            track_counts: FxHashMap::default(),
+            max_tracks: DEFAULT_MAX_TRACKS,
+            barriers: FxHashMap::default(),
+            queues: QueueLogs::new(),
+            budget: Arc::new(QueueBudget::new()),
+            lossy: FxHashMap::default(),
+            graceful_timeout: None,
+            shutting_down: false,
+            on_drop: None,
+            classifier: None,
+            ordering: DeliveryOrder::Fifo,
+            next_anonymous: 0,
+            lag: Arc::new(LoopLag::default()),
+        }
     }
 
     pub fn new_agent<T: Send + 'static>(&mut self, cap: Option<usize>, address: A) -> Result<Agent<T, A>> {
@@ -202,18 +652,285 @@ impl<A: ToAddress + Clone> Router<A> {
             None => flume::unbounded(),
         };
         let agent = Agent::new(self.router_tx(), address.clone(), transport_rx);
+        self.queues.insert(address.clone(), agent.queue.clone());
         self.channels.insert(address, tx);
         Ok(agent)
     }
 
+    /// Mint a unique address for a temporary agent (e.g a reply address),
+    /// from [`ToAddress::anonymous`].
+    ///
+    /// Every call returns a new address, skipping any address
+    /// that already has an agent registered.
+    /// Returns `None` if the address type has no anonymous addresses.
+    ///
+    /// ```
+    /// # use tinyroute::{Router, ToAddress};
+    /// #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    /// enum Address {
+    ///     Server,
+    ///     Anonymous(u64),
+    /// }
+    ///
+    /// impl ToAddress for Address {
+    ///     fn anonymous(id: u64) -> Option<Self> {
+    ///         Some(Address::Anonymous(id))
+    ///     }
+    /// }
+    ///
+    /// let mut router = Router::<Address>::new();
+    /// let reply_address = router.next_anonymous_address().unwrap();
+    /// let reply_agent = router.new_agent::<String>(None, reply_address).unwrap();
+    /// ```
+    pub fn next_anonymous_address(&mut self) -> Option<A> {
+        loop {
+            let address = A::anonymous(self.next_anonymous)?;
+            self.next_anonymous += 1;
+            if !self.channels.contains_key(&address) {
+                break Some(address);
+            }
+        }
+    }
+
+    /// Create an agent and spawn a task calling `handler` with every message
+    /// the agent receives, until it receives `Message::Shutdown`
+    /// (which is passed to the handler as well), or the router is gone.
+    ///
+    /// Messages are handled one at a time, in the order they arrive.
+    /// An error returned by the handler is logged and the agent
+    /// carries on with the next message.
+    ///
+    /// Returns the [`AgentSender`] of the new agent, or an error if an agent
+    /// is already registered at `address`.
+    ///
+    /// This has to be called from within a tokio runtime.
+    ///
+    /// ```
+    /// # use tinyroute::{Message, Router, ToAddress};
+    /// # #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    /// # enum Address { Echo, Client }
+    /// # impl ToAddress for Address {}
+    /// # async fn run() {
+    /// let mut router = Router::new();
+    /// let mut client = router.new_agent::<String>(None, Address::Client).unwrap();
+    /// router.spawn_agent(None, Address::Echo, |msg: Message<String, _>, ctx| async move {
+    ///     if let Message::Value(text, sender) = msg {
+    ///         ctx.send(sender, text).await?;
+    ///     }
+    ///     Ok(())
+    /// }).unwrap();
+    /// tokio::spawn(router.run());
+    ///
+    /// client.send(Address::Echo, "hello".to_string()).await.unwrap();
+    /// let reply = client.recv().await.unwrap();
+    /// # }
+    /// ```
+    pub fn spawn_agent<T, F, Fut>(&mut self, cap: Option<usize>, address: A, mut handler: F) -> Result<AgentSender<A>>
+    where
+        T: Send + 'static,
+        F: FnMut(Message<T, A>, AgentSender<A>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send,
+    {
+        let mut agent = self.new_agent::<T>(cap, address)?;
+        let ctx = agent.sender();
+
+        let handler_ctx = ctx.clone();
+        tokio::spawn(async move {
+            while let Ok(msg) = agent.recv().await {
+                let shutdown = matches!(msg, Message::Shutdown);
+                if let Err(e) = handler(msg, handler_ctx.clone()).await {
+                    error!("Agent \"{}\" failed to handle a message: {}", agent.address().to_string(), e);
+                }
+                if shutdown {
+                    break;
+                }
+            }
+        });
+
+        Ok(ctx)
+    }
+
+    /// Create an agent that keeps the newest `cap` messages.
+    ///
+    /// When a message is sent to the agent while its channel is full,
+    /// the oldest message in the channel is dropped to make room, and
+    /// [`Agent::dropped`] is incremented.
+    ///
+    /// Only values and remote messages evict other messages.
+    /// Fetch requests, `Message::AgentRemoved` and `Message::Shutdown` wait for room
+    /// like they would for any other agent, but can be evicted by later messages.
+    ///
+    /// Returns [`Error::InvalidCapacity`] if `cap` is zero, as there would never be
+    /// a message to keep.
+    pub fn new_lossy_agent<T: Send + 'static>(&mut self, cap: usize, address: A) -> Result<Agent<T, A>> {
+        if cap == 0 {
+            return Err(Error::InvalidCapacity);
+        }
+        let mut agent = self.new_agent(Some(cap), address.clone())?;
+        let dropped = Arc::new(AtomicUsize::new(0));
+        self.lossy.insert(address, Lossy::new(agent.rx.clone(), dropped.clone()));
+        agent.dropped = Some(dropped);
+        Ok(agent)
+    }
+
     pub fn router_tx(&self) -> RouterTx<A> {
-        RouterTx(self.tx.clone())
+        RouterTx(self.tx.clone(), self.queues.clone(), self.budget.clone(), self.lag.clone())
     }
 
-    async fn unregister(&mut self, address: A) {
+    /// Call `f` with the reason and the intended recipient
+    /// whenever the router drops a message (values, remote messages and fetch requests).
+    ///
+    /// This is called from the router loop, so `f` should return quickly.
+    ///
+    /// ```
+    /// # use tinyroute::{Router, ToAddress};
+    /// # fn run<A: ToAddress>(mut router: Router<A>) {
+    /// router.on_drop(|reason, recipient| {
+    ///     eprintln!("Dropped a message to {}: {:?}", recipient.to_string(), reason);
+    /// });
+    /// # }
+    /// ```
+    pub fn on_drop(&mut self, f: impl Fn(&DropReason, &A) + Send + Sync + 'static) {
+        self.on_drop = Some(Box::new(f));
+    }
+
+    /// Limit the number of messages sent between agents that are yet to be
+    /// received, across all agents (there is no limit by default).
+    ///
+    /// Each agent channel may have a capacity of its own, but with enough agents
+    /// the sum of them can be more than the process can hold.
+    /// Once `max` messages are queued, [`Agent::send`] and [`Agent::try_send`]
+    /// return [`Error::QueuesFull`] until the recipients catch up.
+    /// Messages are counted, not their size.
+    ///
+    /// This applies to agents that are already created as well.
+    pub fn max_queued(&mut self, max: usize) {
+        self.budget.max.store(max, Ordering::Relaxed);
+    }
+
+    /// Set how many addresses a single agent can track at once
+    /// (defaults to [`DEFAULT_MAX_TRACKS`]).
+    ///
+    /// Once an agent is tracking `max` addresses, [`Agent::track`]
+    /// returns [`Error::TooManyTracks`] until one of them is removed.
+    /// This stops a runaway agent from growing the routers tracking table
+    /// without bounds.
+    pub fn max_tracks(&mut self, max: usize) {
+        self.max_tracks = max;
+    }
+
+    /// Give up the order of messages to an agent with a full channel, so the router
+    /// doesn't wait for it (defaults to [`DeliveryOrder::Fifo`]).
+    /// See [`DeliveryOrder::Relaxed`] for what is given up.
+    ///
+    /// This only pays off when a slow agent holds up the others:
+    /// handing a message to a task costs more than waiting a little while for room
+    /// (see `cargo bench --bench ordering`).
+    pub fn ordering(&mut self, ordering: DeliveryOrder) {
+        self.ordering = ordering;
+    }
+
+    /// Pick the recipient of messages sent with [`Agent::send_classified`],
+    /// given the sender and the opcode of the message.
+    ///
+    /// The payload itself is opaque to the router, so any routing decision
+    /// has to be made on the sender and the opcode.
+    /// If `f` returns `None` the message is dropped, and the [`Router::on_drop`]
+    /// callback is called with [`DropReason::Unclassified`].
+    ///
+    /// ```
+    /// # use tinyroute::{Router, ToAddress};
+    /// # #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    /// # enum Address { Orders, Payments }
+    /// # impl ToAddress for Address {}
+    /// # fn run(mut router: Router<Address>) {
+    /// const ORDER: u32 = 1;
+    /// const PAYMENT: u32 = 2;
+    ///
+    /// router.set_classifier(|_sender, opcode| match opcode {
+    ///     ORDER => Some(Address::Orders),
+    ///     PAYMENT => Some(Address::Payments),
+    ///     _ => None,
+    /// });
+    /// # }
+    /// ```
+    pub fn set_classifier(&mut self, f: impl Fn(&A, u32) -> Option<A> + Send + Sync + 'static) {
+        self.classifier = Some(Box::new(f));
+    }
+
+    fn dropped(&self, reason: DropReason, recipient: &A) {
+        if let Some(on_drop) = &self.on_drop {
+            on_drop(&reason, recipient);
+        }
+    }
+
+    /// The messages queued for the agent at `address`, oldest first.
+    ///
+    /// This only records the sender and arrival time of each message,
+    /// the messages themselves are left untouched.
+    /// Use [`RouterTx::inspect_queue`] once the router is running.
+    ///
+    /// Requires the `debug-queues` feature.
+    #[cfg(feature = "debug-queues")]
+    pub fn inspect_queue(&self, address: &A) -> Vec<QueuedMeta<A>> {
+        self.queues.inspect(address)
+    }
+
+    /// The longest the router took to handle a single message
+    /// (from taking it off the router channel until it's ready for the next one)
+    /// since this was last called, or zero if it hasn't handled any.
+    ///
+    /// Every message is timed, and reading the lag resets it, so calling this
+    /// at a regular interval gives the worst lag in each interval.
+    /// A lag that keeps growing means something in the router loop
+    /// (e.g a slow classifier or `on_drop` callback, or an agent with a full channel
+    /// the router is waiting on) holds up every message behind it.
+    /// Use [`RouterTx::loop_lag`] once the router is running.
+    ///
+    /// ```
+    /// # use std::time::Duration;
+    /// # use tinyroute::{Router, ToAddress};
+    /// # async fn run<A: ToAddress + Sync>(router: Router<A>) {
+    /// let router_tx = router.router_tx();
+    /// tokio::spawn(router.run());
+    /// loop {
+    ///     tokio::time::sleep(Duration::from_secs(10)).await;
+    ///     let lag = router_tx.loop_lag();
+    ///     if lag > Duration::from_millis(100) {
+    ///         eprintln!("The router is falling behind: {:?}", lag);
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn loop_lag(&self) -> Duration {
+        self.lag.take()
+    }
+
+    /// The number of messages queued for each agent, and the capacity the
+    /// agent was created with, as `(address, len, capacity)`.
+    /// The capacity of an unbounded agent is `usize::MAX`.
+    ///
+    /// The length is a snapshot: agents keep receiving messages
+    /// while this is read, so treat it as a gauge rather than an exact count.
+    /// Use [`RouterTx::queue_utilization`] once the router is running.
+    pub fn queue_utilization(&self) -> Vec<(A, usize, usize)> {
+        self.channels
+            .iter()
+            .map(|(address, tx)| (address.clone(), tx.len(), tx.capacity().unwrap_or(usize::MAX)))
+            .collect()
+    }
+
+    async fn unregister(&mut self, address: A, reason: RemovalReason) {
         if self.channels.remove(&address).is_none() {
             return;
         }
+        self.queues.remove(&address);
+        self.lossy.remove(&address);
+        self.track_counts.remove(&address);
+        self.topics.retain(|_, subscribers| {
+            subscribers.retain(|s| s != &address);
+            !subscribers.is_empty()
+        });
 
         let subs = match self.subscriptions.remove(&address) {
             None => return,
@@ -224,112 +941,420 @@ impl<A: ToAddress + Clone> Router<A> {
             if let Some(relations) = self.subscriptions.get_mut(&s) {
                 relations.retain(|p| p != &address);
             }
+            if let Some(count) = self.track_counts.get_mut(&s) {
+                *count = count.saturating_sub(1);
+            }
 
             let address = address.clone();
             if let Some(tx) = self.channels.get(&s) {
-                let _ = tx.send_async(AgentMsg::AgentRemoved(address)).await;
+                self.queues.push(&s, None);
+                let _ = tx.send_async(AgentMsg::AgentRemoved(address, reason)).await;
             }
         }
     }
 
+    /// Dispatch messages until the router is shut down,
+    /// or every [`RouterTx`] is dropped.
+    ///
+    /// The router runs entirely on the task awaiting `run`, and never spawns
+    /// a task of its own (except with [`DeliveryOrder::Relaxed`]), so it works on a `current_thread` runtime.
+    /// Other parts of the crate do spawn tasks: the reader, writer and heartbeat
+    /// of a connection, [`Router::spawn_agent`], [`Agent::rename`] (to unregister
+    /// the old address after the grace period), and dropping an agent while
+    /// the router channel is full.
     pub async fn run(mut self) {
-        while let Ok(msg) = self.rx.recv_async().await {
-            match msg {
-                RouterMessage::ShutdownRouter => {
-                    let drain = self.channels.drain().map(|(_, tx)| tx);
-                    for tx in drain {
-                        spawn(async move {
-                            let _ = tx.send_async(AgentMsg::Shutdown).await;
-                        });
-                    }
+        // The router only holds on to a sender to hand out `RouterTx`s.
+        // Let go of it, so the channel disconnects once every `RouterTx` is dropped.
+        drop(std::mem::replace(&mut self.tx, bounded(1).0));
 
-                    info!("Shutting down router");
-                    break;
+        let mut batch = VecDeque::new();
+        'run: while self.recv_batch(&mut batch).await {
+            while let Some(msg) = batch.pop_front() {
+                let start = Instant::now();
+                let keep_running = self.handle(msg).await;
+                self.lag.record(start.elapsed());
+                if !keep_running {
+                    break 'run;
                 }
-                RouterMessage::PrintChannels => {
-                    for k in self.channels.keys() {
-                        println!("Chan: {}", k.to_string());
+            }
+        }
+
+        self.shutting_down = true;
+        if let Some(timeout) = self.graceful_timeout.take() {
+            let deadline = Instant::now() + timeout;
+            if timeout_at(deadline, self.wait_for_agents(&mut batch)).await.is_err() {
+                warn!("Timed out waiting for {} agent(s) to shut down", self.channels.len());
+            }
+        }
+
+        self.refuse_registrations(batch);
+        info!("Router shutdown successful");
+    }
+
+    // Let anyone waiting to register an agent know the router is gone,
+    // rather than leaving them waiting on a router that will never reply.
+    fn refuse_registrations(&self, batch: VecDeque<RouterMessage<A>>) {
+        for msg in batch.into_iter().chain(self.rx.drain()) {
+            if let RouterMessage::Register(_, _, _, _, success_tx) = msg {
+                let _ = success_tx.try_send(Err(Error::RouterGone));
+            }
+        }
+    }
+
+    // Tell every agent to shut down, and keep routing messages
+    // until every agent has unregistered
+    async fn wait_for_agents(&mut self, batch: &mut VecDeque<RouterMessage<A>>) {
+        for (address, tx) in &self.channels {
+            self.queues.push(address, None);
+            let _ = tx.send_async(AgentMsg::Shutdown).await;
+        }
+
+        loop {
+            while let Some(msg) = batch.pop_front() {
+                self.handle(msg).await;
+            }
+
+            if self.channels.is_empty() || !self.recv_batch(batch).await {
+                break;
+            }
+        }
+    }
+
+    // Receive the next message, and with batching enabled any messages
+    // that are ready or arrive before the batch delay is up.
+    // Returns false once every sender is gone.
+    async fn recv_batch(&self, batch: &mut VecDeque<RouterMessage<A>>) -> bool {
+        match self.rx.recv_async().await {
+            Ok(msg) => batch.push_back(msg),
+            Err(_) => return false,
+        }
+
+        let batching = match self.batching {
+            Some(batching) => batching,
+            None => return true,
+        };
+
+        let deadline = Instant::now() + batching.max_delay;
+        while batch.len() < batching.max_batch {
+            match self.rx.try_recv() {
+                Ok(msg) => batch.push_back(msg),
+                Err(TryRecvError::Disconnected) => break,
+                Err(TryRecvError::Empty) => match timeout_at(deadline, self.rx.recv_async()).await {
+                    Ok(Ok(msg)) => batch.push_back(msg),
+                    Ok(Err(_)) | Err(_) => break,
+                },
+            }
+        }
+
+        true
+    }
+
+    // Returns false if the message could not be delivered
+    async fn send_value(&mut self, recipient: A, sender: A, msg: AnyMessage) -> bool {
+        self.try_send_value(recipient, sender, msg).await.is_ok()
+    }
+
+    async fn try_send_value(&mut self, recipient: A, sender: A, msg: AnyMessage) -> std::result::Result<(), DropReason> {
+        let tx = match self.channels.get(&recipient) {
+            Some(val) => val,
+            None => {
+                info!("No channel registered at \"{}\"", recipient.to_string());
+                self.dropped(DropReason::NoRecipient, &recipient);
+                return Err(DropReason::NoRecipient);
+            }
+        };
+
+        self.queues.push(&recipient, Some(&sender));
+        if !self.send_message(&recipient, tx, AgentMsg::Message(msg, sender)).await {
+            error!("Failed to send a message to \"{}\"", recipient.to_string());
+            self.dropped(DropReason::RecipientGone, &recipient);
+            self.unregister(recipient, RemovalReason::Dropped).await;
+            return Err(DropReason::RecipientGone);
+        }
+
+        Ok(())
+    }
+
+    // Send a message to an agent, making room for it first if the agent is lossy.
+    // Returns false if the agent is gone.
+    async fn send_message(&self, recipient: &A, tx: &Sender<AgentMsg<A>>, msg: AgentMsg<A>) -> bool {
+        let lossy = match self.lossy.get(recipient) {
+            Some(lossy) => lossy,
+            None => return self.send_ordered(tx, msg).await,
+        };
+
+        let mut msg = msg;
+        let mut evicted = true;
+        loop {
+            match tx.try_send(msg) {
+                Ok(()) => return true,
+                Err(TrySendError::Disconnected(_)) => return false,
+                Err(TrySendError::Full(m)) => {
+                    // Nothing could be evicted last time round either,
+                    // so drop the message itself rather than spin
+                    if !evicted {
+                        self.evicted(lossy, recipient);
+                        return true;
+                    }
+
+                    msg = m;
+                    evicted = lossy.rx.try_recv().is_ok();
+                    if evicted {
+                        self.evicted(lossy, recipient);
                     }
                 }
-                RouterMessage::Message { sender, recipient, msg } => {
-                    let tx = match self.channels.get(&recipient) {
-                        Some(val) => val,
-                        None => {
-                            info!("No channel registered at \"{}\"", recipient.to_string());
-                            continue;
-                        }
-                    };
+            }
+        }
+    }
+
+    // A message to a lossy agent was dropped to make room
+    fn evicted(&self, lossy: &Lossy<A>, recipient: &A) {
+        lossy.dropped.fetch_add(1, Ordering::Relaxed);
+        self.dropped(DropReason::Evicted, recipient);
+        self.queues.pop(recipient);
+    }
 
-                    if tx.send_async(AgentMsg::Message(msg, sender)).await.is_err() {
-                        error!("Failed to send a message to \"{}\"", recipient.to_string());
-                        self.unregister(recipient).await;
+    async fn send_ordered(&self, tx: &Sender<AgentMsg<A>>, msg: AgentMsg<A>) -> bool {
+        match self.ordering {
+            DeliveryOrder::Fifo => tx.send_async(msg).await.is_ok(),
+            DeliveryOrder::Relaxed => match tx.try_send(msg) {
+                Ok(()) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+                Err(TrySendError::Full(msg)) => {
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        let _ = tx.send_async(msg).await;
+                    });
+                    true
+                }
+            },
+        }
+    }
+
+    // Returns false if the router should shut down
+    async fn handle(&mut self, msg: RouterMessage<A>) -> bool {
+        match msg {
+            RouterMessage::ShutdownRouter => {
+                // Agents with a channel that is still full at the deadline see
+                // the channel closed once the router is gone, rather than a shutdown message
+                let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+                for (address, tx) in self.channels.drain() {
+                    self.queues.push(&address, None);
+                    if !matches!(timeout_at(deadline, tx.send_async(AgentMsg::Shutdown)).await, Ok(Ok(()))) {
+                        self.queues.pop(&address);
                     }
                 }
-                RouterMessage::RemoteMessage { recipient, sender, bytes, host } => {
-                    let tx = match self.channels.get(&recipient) {
-                        Some(tx) => tx,
-                        None => {
-                            info!("No channel registered at \"{}\"", recipient.to_string());
-                            continue;
-                        }
-                    };
 
-                    if tx.send_async(AgentMsg::RemoteMessage(bytes, sender, host)).await.is_err() {
-                        error!("Failed to send a message to \"{}\"", recipient.to_string());
-                        self.unregister(recipient).await;
+                info!("Shutting down router");
+                return false;
+            }
+            RouterMessage::ShutdownRouterGraceful(timeout) => {
+                info!("Shutting down router, waiting for agents to unregister");
+                self.graceful_timeout = Some(timeout);
+                return false;
+            }
+            RouterMessage::PrintChannels => {
+                for k in self.channels.keys() {
+                    println!("Chan: {}", k.to_string());
+                }
+            }
+            RouterMessage::QueueUtilization(reply_tx) => {
+                let _ = reply_tx.try_send(self.queue_utilization());
+            }
+            RouterMessage::Message { sender, recipient, msg } => {
+                self.send_value(recipient, sender, msg).await;
+            }
+            RouterMessage::MessageOnFail { sender, recipient, msg, on_fail } => {
+                if let Err(reason) = self.try_send_value(recipient, sender, msg).await {
+                    on_fail(reason);
+                }
+            }
+            RouterMessage::SendAll { sender, messages, failed_tx } => {
+                let mut failed = Vec::new();
+                for (recipient, msg) in messages {
+                    if !self.send_value(recipient.clone(), sender.clone(), msg).await {
+                        failed.push(recipient);
                     }
                 }
-                RouterMessage::Register(address, tx, success_tx) => {
-                    if self.channels.contains_key(&address) {
-                        warn!("There is already an agent registered at \"{}\"", address.to_string());
-                        continue;
+                let _ = failed_tx.try_send(failed);
+            }
+            RouterMessage::Classified { sender, opcode, msg } => {
+                match self.classifier.as_ref().and_then(|classify| classify(&sender, opcode)) {
+                    Some(recipient) => {
+                        self.send_value(recipient, sender, msg).await;
                     }
-                    let address_str = address.to_string();
-                    self.channels.insert(address, tx);
-                    info!("Registered \"{}\"", address_str);
-                    if let Err(e) = success_tx.send(()) {
-                        error!("Failed to reply when registering a new agent: {}", e);
+                    None => {
+                        info!("No recipient for opcode {} from \"{}\"", opcode, sender.to_string());
+                        self.dropped(DropReason::Unclassified, &sender);
                     }
                 }
-                RouterMessage::Track { from, to } => {
-                    let tracked = self.subscriptions.entry(to).or_insert_with(Vec::new);
-
-                    if tracked.contains(&from) {
-                        continue;
+            }
+            RouterMessage::RemoteMessage { recipient, sender, bytes, host } => {
+                let tx = match self.channels.get(&recipient) {
+                    Some(tx) => tx,
+                    None => {
+                        info!("No channel registered at \"{}\"", recipient.to_string());
+                        self.dropped(DropReason::NoRecipient, &recipient);
+                        return true;
                     }
+                };
 
-                    tracked.push(from);
+                self.queues.push(&recipient, Some(&sender));
+                if !self.send_message(&recipient, tx, AgentMsg::RemoteMessage(bytes, sender, host)).await {
+                    error!("Failed to send a message to \"{}\"", recipient.to_string());
+                    self.dropped(DropReason::RecipientGone, &recipient);
+                    self.unregister(recipient, RemovalReason::Dropped).await;
+                }
+            }
+            RouterMessage::Register(address, tx, queue, lossy, success_tx) => {
+                if self.shutting_down {
+                    warn!("Not registering \"{}\", the router is shutting down", address.to_string());
+                    let _ = success_tx.try_send(Err(Error::RouterGone));
+                    return true;
+                }
+                if self.channels.contains_key(&address) {
+                    warn!("There is already an agent registered at \"{}\"", address.to_string());
+                    return true;
                 }
-                RouterMessage::Unregister(address) => self.unregister(address).await,
-                RouterMessage::Shutdown(sender) => {
-                    let tx = match self.channels.get(&sender) {
-                        Some(val) => val,
-                        None => {
-                            info!("No channel registered at \"{}\"", sender.to_string());
-                            continue;
+                let address_str = address.to_string();
+                self.queues.insert(address.clone(), queue);
+                if let Some(lossy) = lossy {
+                    self.lossy.insert(address.clone(), lossy);
+                }
+                self.channels.insert(address, tx);
+                info!("Registered \"{}\"", address_str);
+                if let Err(e) = success_tx.try_send(Ok(())) {
+                    error!("Failed to reply when registering a new agent: {}", e);
+                }
+            }
+            RouterMessage::RegisterAlias { from, to, success_tx } => {
+                if self.channels.contains_key(&to) {
+                    warn!("There is already an agent registered at \"{}\"", to.to_string());
+                    return true;
+                }
+                let tx = match self.channels.get(&from) {
+                    Some(tx) => tx.clone(),
+                    None => {
+                        info!("No channel registered at \"{}\"", from.to_string());
+                        return true;
+                    }
+                };
+                info!("Registered \"{}\" as \"{}\"", from.to_string(), to.to_string());
+                self.queues.alias(&from, to.clone());
+                if let Some(lossy) = self.lossy.get(&from).cloned() {
+                    self.lossy.insert(to.clone(), lossy);
+                }
+                self.channels.insert(to, tx);
+                if let Err(e) = success_tx.try_send(()) {
+                    error!("Failed to reply when registering an alias: {}", e);
+                }
+            }
+            RouterMessage::ArriveBarrier { name, addr, expected, release_tx } => {
+                let barrier = self.barriers.entry(name.clone()).or_insert_with(|| Barrier { expected, arrived: Vec::new() });
+                if barrier.expected != expected {
+                    warn!("\"{}\" expects {} agents at barrier \"{}\", not {}", addr.to_string(), expected, name, barrier.expected);
+                }
+
+                // Arriving twice doesn't count twice
+                barrier.arrived.retain(|(a, _)| a != &addr);
+                barrier.arrived.push((addr, release_tx));
+
+                if barrier.arrived.len() >= barrier.expected {
+                    info!("Releasing barrier \"{}\"", name);
+                    if let Some(barrier) = self.barriers.remove(&name) {
+                        for (_, release_tx) in barrier.arrived {
+                            let _ = release_tx.try_send(());
                         }
-                    };
-                    let _ = tx.send_async(AgentMsg::Shutdown).await;
-                    self.unregister(sender).await;
+                    }
                 }
-                RouterMessage::Fetch(address, request) => {
-                    let tx = match self.channels.get(&address) {
-                        Some(val) => val,
-                        None => {
-                            info!("No channel registered at \"{}\"", address.to_string());
-                            continue;
+            }
+            RouterMessage::Track { from, to, success_tx } => {
+                let tracked = self.subscriptions.entry(to).or_default();
+
+                let accepted = match tracked.contains(&from) {
+                    true => true,
+                    false => {
+                        let count = self.track_counts.entry(from.clone()).or_default();
+                        if *count < self.max_tracks {
+                            *count += 1;
+                            tracked.push(from);
+                            true
+                        } else {
+                            false
                         }
-                    };
+                    }
+                };
 
-                    if tx.send_async(AgentMsg::Fetch(request)).await.is_err() {
-                        error!("Failed to send a message to \"{}\"", address.to_string());
-                        self.unregister(address).await;
+                let _ = success_tx.try_send(accepted);
+            }
+            RouterMessage::Untrack { from, to } => {
+                if let Some(tracked) = self.subscriptions.get_mut(&to) {
+                    let before = tracked.len();
+                    tracked.retain(|address| address != &from);
+                    if tracked.len() < before {
+                        if let Some(count) = self.track_counts.get_mut(&from) {
+                            *count = count.saturating_sub(1);
+                        }
+                    }
+                }
+            }
+            RouterMessage::Subscribe { topic, subscriber } => {
+                let subscribers = self.topics.entry(topic).or_default();
+                if !subscribers.contains(&subscriber) {
+                    subscribers.push(subscriber);
+                }
+            }
+            RouterMessage::Unsubscribe { topic, subscriber } => {
+                if let Some(subscribers) = self.topics.get_mut(&topic) {
+                    subscribers.retain(|s| s != &subscriber);
+                    if subscribers.is_empty() {
+                        self.topics.remove(&topic);
+                    }
+                }
+            }
+            RouterMessage::Subscribers { topic, reply_tx } => {
+                let subscribers = self.topics.get(&topic).cloned().unwrap_or_default();
+                let _ = reply_tx.try_send(subscribers);
+            }
+            RouterMessage::Unregister(address, reason) => self.unregister(address, reason).await,
+            RouterMessage::Close(address, success_tx) => {
+                self.unregister(address, RemovalReason::Unregistered).await;
+                if let Err(e) = success_tx.try_send(()) {
+                    error!("Failed to reply when closing an agent: {}", e);
+                }
+            }
+            RouterMessage::Shutdown(sender) => {
+                let tx = match self.channels.get(&sender) {
+                    Some(val) => val,
+                    None => {
+                        info!("No channel registered at \"{}\"", sender.to_string());
+                        return true;
                     }
+                };
+                self.queues.push(&sender, None);
+                let _ = tx.send_async(AgentMsg::Shutdown).await;
+                self.unregister(sender, RemovalReason::Shutdown).await;
+            }
+            RouterMessage::Fetch(address, request) => {
+                let tx = match self.channels.get(&address) {
+                    Some(val) => val,
+                    None => {
+                        info!("No channel registered at \"{}\"", address.to_string());
+                        self.dropped(DropReason::NoRecipient, &address);
+                        return true;
+                    }
+                };
+
+                self.queues.push(&address, None);
+                if tx.send_async(AgentMsg::Fetch(request)).await.is_err() {
+                    error!("Failed to send a message to \"{}\"", address.to_string());
+                    self.dropped(DropReason::RecipientGone, &address);
+                    self.unregister(address, RemovalReason::Dropped).await;
                 }
             }
         }
 
-        info!("Router shutdown successful");
+        true
     }
 }
 
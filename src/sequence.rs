@@ -0,0 +1,142 @@
+//! Keep messages in the order they were sent by their origin,
+//! across any number of forwarding hops.
+//!
+//! The router delivers messages from one sender in the order they were sent,
+//! but once an agent in the middle of a pipeline handles messages concurrently
+//! (e.g one task per message) a later message can overtake an earlier one.
+//!
+//! The origin tags each message with a [`Sequencer`], agents in the middle
+//! forward the [`Sequenced`] message as is (or [`Sequenced::map`] it),
+//! and the sink puts them back in order with [`Reorder`].
+//!
+//! ```
+//! use tinyroute::sequence::{Reorder, Sequencer};
+//! # #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+//! # enum Address { Origin }
+//! # impl tinyroute::ToAddress for Address {}
+//!
+//! let mut sequencer = Sequencer::new(Address::Origin);
+//! let first = sequencer.tag("first");
+//! let second = sequencer.tag("second");
+//!
+//! let mut reorder = Reorder::new();
+//! assert!(reorder.push(second).is_empty());
+//! assert_eq!(vec!["first", "second"], reorder.push(first));
+//! ```
+use std::collections::BTreeMap;
+
+use fxhash::FxHashMap;
+use log::warn;
+
+use crate::ToAddress;
+
+/// The default number of out of order messages held on to, per origin,
+/// by a [`Reorder`] created with [`Reorder::new`].
+pub const DEFAULT_MAX_BUFFERED: usize = 1024;
+
+/// A value tagged with the address it originated from,
+/// and its position in the sequence of values from that address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sequenced<A, T> {
+    /// The address the value originated from
+    pub origin: A,
+    /// The position of the value, starting at zero
+    pub seq: u64,
+    /// The value itself
+    pub value: T,
+}
+
+impl<A, T> Sequenced<A, T> {
+    /// Transform the value, keeping the origin and the sequence number,
+    /// for agents that do more than forward the value.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Sequenced<A, U> {
+        Sequenced { origin: self.origin, seq: self.seq, value: f(self.value) }
+    }
+}
+
+/// Tags values with consecutive sequence numbers.
+/// There should be one `Sequencer` per origin.
+#[derive(Debug)]
+pub struct Sequencer<A> {
+    origin: A,
+    next: u64,
+}
+
+impl<A: Clone> Sequencer<A> {
+    /// Tag values from `origin`, starting at zero
+    pub fn new(origin: A) -> Self {
+        Self { origin, next: 0 }
+    }
+
+    /// Tag the next value
+    pub fn tag<T>(&mut self, value: T) -> Sequenced<A, T> {
+        let seq = self.next;
+        self.next += 1;
+        Sequenced { origin: self.origin.clone(), seq, value }
+    }
+}
+
+/// Release [`Sequenced`] values in sequence order, per origin.
+///
+/// A value that arrives ahead of its turn is held on to until the values
+/// before it have arrived.
+/// At most `max_buffered` values are held on to per origin: once that's exceeded
+/// the missing values are assumed lost, and the values held on to
+/// are released from the lowest sequence number onwards.
+/// A value that arrives after that (or any value that was already released) is dropped.
+#[derive(Debug)]
+pub struct Reorder<A: ToAddress, T> {
+    next: FxHashMap<A, u64>,
+    buffered: FxHashMap<A, BTreeMap<u64, T>>,
+    max_buffered: usize,
+}
+
+impl<A: ToAddress, T> Reorder<A, T> {
+    /// Hold on to at most [`DEFAULT_MAX_BUFFERED`] values per origin.
+    pub fn new() -> Self {
+        Self::with_max_buffered(DEFAULT_MAX_BUFFERED)
+    }
+
+    /// Hold on to at most `max_buffered` values per origin.
+    pub fn with_max_buffered(max_buffered: usize) -> Self {
+        Self { next: FxHashMap::default(), buffered: FxHashMap::default(), max_buffered }
+    }
+
+    /// Add a value, returning the values (if any) that are now in order.
+    pub fn push(&mut self, sequenced: Sequenced<A, T>) -> Vec<T> {
+        let Sequenced { origin, seq, value } = sequenced;
+        let next = self.next.entry(origin.clone()).or_default();
+        if seq < *next {
+            warn!("Dropping message {} from \"{}\", it's too late", seq, origin.to_string());
+            return Vec::new();
+        }
+
+        let buffered = self.buffered.entry(origin.clone()).or_default();
+        buffered.insert(seq, value);
+
+        if buffered.len() > self.max_buffered {
+            if let Some(&first) = buffered.keys().next() {
+                warn!("Skipping messages {}..{} from \"{}\"", next, first, origin.to_string());
+                *next = first;
+            }
+        }
+
+        let mut released = Vec::new();
+        while let Some(value) = buffered.remove(next) {
+            released.push(value);
+            *next += 1;
+        }
+
+        if buffered.is_empty() {
+            self.buffered.remove(&origin);
+        }
+
+        released
+    }
+}
+
+impl<A: ToAddress, T> Default for Reorder<A, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
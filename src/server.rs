@@ -24,34 +24,40 @@
 //! }
 //! # }
 //! ```
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::future::Future;
 use std::pin::Pin;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 use std::path::Path;
 
 use bytes::Bytes;
+use flume::{Receiver, Sender};
 // use futures::future::FutureExt;
-use log::error;
+use log::{error, info, warn};
 
 use crate::ADDRESS_SEP;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::spawn;
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 // TODO: remove commented out use statements
 // pub use crate::runtime::{TcpConnections, UdsConnections, TcpListener, UdsListener};
 // use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
-pub use tokio::net::{UnixListener, UnixStream, TcpListener, TcpStream};
+pub use tokio::net::{UnixDatagram, UnixListener, UnixStream, TcpListener, TcpStream};
 
 use crate::agent::{Agent, Message};
 use crate::errors::{Error, Result};
-use crate::frame::{Frame, FrameOutput, FramedMessage};
+use crate::frame::{Direction, Frame, FrameOutput, FrameTap, FramedMessage};
 
 use crate::router::{RouterMessage, RouterTx, ToAddress};
 
 /// A unix domain socket server
 pub struct UdsConnections {
     inner: UnixListener,
+    allowed_uids: Option<HashSet<u32>>,
 }
 
 impl UdsConnections {
@@ -67,24 +73,83 @@ impl UdsConnections {
 
         let inst = Self {
             inner,
+            allowed_uids: None,
         };
 
         Ok(inst)
     }
+
+    /// Only accept connections from processes running as one of the
+    /// given user ids, as reported by `SO_PEERCRED`.
+    /// Connections from any other user are closed straight after accept,
+    /// before a [`Connection`] is created.
+    ///
+    /// ```
+    /// # use std::collections::HashSet;
+    /// # use tinyroute::server::UdsConnections;
+    /// # async fn run() {
+    /// let listener = UdsConnections::bind("/tmp/my-file.sock")
+    ///     .await
+    ///     .expect("failed to create socket")
+    ///     .with_allowed_uids(HashSet::from([1000]));
+    /// # }
+    /// ```
+    pub fn with_allowed_uids(mut self, uids: HashSet<u32>) -> Self {
+        self.allowed_uids = Some(uids);
+        self
+    }
+
+    fn is_allowed(&self, socket: &UnixStream) -> bool {
+        let allowed = match &self.allowed_uids {
+            Some(allowed) => allowed,
+            None => return true,
+        };
+
+        match socket.peer_cred() {
+            Ok(cred) if allowed.contains(&cred.uid()) => true,
+            Ok(cred) => {
+                warn!("rejected uds connection from uid {}", cred.uid());
+                false
+            }
+            Err(e) => {
+                warn!("rejected uds connection, no peer credentials: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Create a uds server from an already bound listener,
+    /// e.g one passed to the process by systemd (socket activation).
+    ///
+    /// This has to be called from within a tokio runtime.
+    ///
+    /// ```
+    /// # use tinyroute::server::UdsConnections;
+    /// # async fn run() {
+    /// let listener = std::os::unix::net::UnixListener::bind("/tmp/my-file.sock").unwrap();
+    /// let listener = UdsConnections::from_std(listener).expect("failed to create socket");
+    /// # }
+    /// ```
+    pub fn from_std(listener: std::os::unix::net::UnixListener) -> Result<Self> {
+        listener.set_nonblocking(true)?;
+        let inner = UnixListener::from_std(listener)?;
+        Ok(Self { inner, allowed_uids: None })
+    }
 }
 
 impl Connections for UdsConnections {
     type Reader = tokio::net::unix::OwnedReadHalf;
     type Writer = tokio::net::unix::OwnedWriteHalf;
 
-    fn accept(&mut self) -> ServerFuture<'_, Self::Reader, Self::Writer> {
-        let future = async move {
+    async fn accept(&mut self) -> Result<(Self::Reader, Self::Writer, ConnectionAddr)> {
+        loop {
             let (socket, _) = self.inner.accept().await?;
+            if !self.is_allowed(&socket) {
+                continue;
+            }
             let (reader, writer) = socket.into_split();
-            Ok((reader, writer, ConnectionAddr::Uds))
-        };
-
-        Box::pin(future)
+            break Ok((reader, writer, ConnectionAddr::Uds));
+        }
     }
 }
 
@@ -94,8 +159,11 @@ impl Connections for UdsConnections {
 /// Connections should be used together with an agent and a [`crate::server::Server`]
 pub struct TcpConnections {
     inner: TcpListener,
+    configure: Option<ConfigureSocket>,
 }
 
+type ConfigureSocket = Box<dyn Fn(&TcpStream) + Send + Sync>;
+
 impl TcpConnections {
     /// Create a new tcp server given an address
     ///
@@ -109,24 +177,237 @@ impl TcpConnections {
 
         let inst = Self {
             inner,
+            configure: None,
         };
 
         Ok(inst)
     }
+
+    /// Set socket options the server doesn't expose (e.g `TCP_NODELAY`)
+    /// on every accepted connection, before anything is read from it.
+    ///
+    /// `f` is given the stream for the duration of the call.
+    /// This is an escape hatch: reading from or writing to the stream,
+    /// or changing it to blocking mode, breaks the connection.
+    /// Options that have to be set on the listener (e.g `SO_REUSEPORT`)
+    /// can be set on a `std::net::TcpListener` passed to [`TcpConnections::from_std`].
+    pub fn with_socket(mut self, f: impl Fn(&TcpStream) + Send + Sync + 'static) -> Self {
+        self.configure = Some(Box::new(f));
+        self
+    }
+
+    /// Create a tcp server from an already bound listener,
+    /// e.g one passed to the process by systemd (socket activation).
+    ///
+    /// This has to be called from within a tokio runtime.
+    ///
+    /// ```
+    /// # use tinyroute::server::TcpConnections;
+    /// # async fn run() {
+    /// let listener = std::net::TcpListener::bind("127.0.0.1:5000").unwrap();
+    /// let listener = TcpConnections::from_std(listener).expect("fail");
+    /// # }
+    /// ```
+    pub fn from_std(listener: std::net::TcpListener) -> Result<Self> {
+        listener.set_nonblocking(true)?;
+        let inner = TcpListener::from_std(listener)?;
+        Ok(Self { inner, configure: None })
+    }
 }
 
 impl Connections for TcpConnections {
     type Reader = tokio::net::tcp::OwnedReadHalf;
     type Writer = tokio::net::tcp::OwnedWriteHalf;
 
-    fn accept(&mut self) -> ServerFuture<'_, Self::Reader, Self::Writer> {
-        let future = async move {
-            let (socket, addr) = self.inner.accept().await?;
-            let (reader, writer) = socket.into_split();
-            Ok((reader, writer, ConnectionAddr::Tcp(addr)))
-        };
+    async fn accept(&mut self) -> Result<(Self::Reader, Self::Writer, ConnectionAddr)> {
+        let (socket, addr) = self.inner.accept().await?;
+        if let Some(configure) = &self.configure {
+            configure(&socket);
+        }
+        let (reader, writer) = socket.into_split();
+        Ok((reader, writer, ConnectionAddr::Tcp(addr)))
+    }
+}
+
+type Accepted = (BoxedReader, BoxedWriter, ConnectionAddr);
+type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// A named collection of listeners, possibly of different kinds,
+/// e.g a tcp listener for external clients and a unix socket for local admin tools.
+///
+/// Each listener accepts connections in its own task,
+/// and a single listener can be stopped with [`Server::stop_listener`]
+/// while the others keep accepting.
+///
+/// ```
+/// use tinyroute::server::{Listeners, Server, TcpConnections, UdsConnections};
+///
+/// # #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+/// # struct Address(usize);
+/// # impl tinyroute::ToAddress for Address {
+/// #   fn from_bytes(_: &[u8]) -> Option<Self> { None }
+/// # }
+/// # async fn run(mut router: tinyroute::Router<Address>) {
+/// let listeners = Listeners::new()
+///     .with_listener("external", TcpConnections::bind("0.0.0.0:5000").await.unwrap())
+///     .with_listener("admin", UdsConnections::bind("/tmp/admin.sock").await.unwrap());
+///
+/// let server_agent = router.new_agent(None, Address(0)).unwrap();
+/// let mut server = Server::new(listeners, server_agent);
+///
+/// // Drain external clients, but keep the admin socket open
+/// server.stop_listener("external").await;
+/// # }
+/// ```
+pub struct Listeners {
+    tx: Sender<Result<Accepted>>,
+    rx: Receiver<Result<Accepted>>,
+    listeners: HashMap<String, JoinHandle<()>>,
+}
+
+impl Listeners {
+    /// Create an empty collection of listeners
+    pub fn new() -> Self {
+        let (tx, rx) = flume::bounded(1);
+        Self { tx, rx, listeners: HashMap::new() }
+    }
+
+    /// Start accepting connections on `connections`.
+    /// If there is already a listener called `name` it's stopped and replaced.
+    ///
+    /// This has to be called from within a tokio runtime.
+    pub fn with_listener<C>(mut self, name: impl Into<String>, mut connections: C) -> Self
+        where C: Connections + Send + 'static
+    {
+        let tx = self.tx.clone();
+        let handle = spawn(async move {
+            loop {
+                let accepted = connections.accept().await.map(|(reader, writer, addr)| {
+                    (Box::new(reader) as BoxedReader, Box::new(writer) as BoxedWriter, addr)
+                });
+                let failed = accepted.is_err();
+                if tx.send_async(accepted).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        if let Some(previous) = self.listeners.insert(name.into(), handle) {
+            previous.abort();
+        }
+
+        self
+    }
+
+    // Stop the listener and wait for it to be dropped,
+    // so nothing can connect to it once this returns.
+    async fn stop(&mut self, name: &str) -> bool {
+        match self.listeners.remove(name) {
+            Some(handle) => {
+                handle.abort();
+                let _ = handle.await;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for Listeners {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Listeners {
+    fn drop(&mut self) {
+        self.listeners.values().for_each(JoinHandle::abort);
+    }
+}
+
+impl Connections for Listeners {
+    type Reader = BoxedReader;
+    type Writer = BoxedWriter;
+
+    async fn accept(&mut self) -> Result<(Self::Reader, Self::Writer, ConnectionAddr)> {
+        self.rx.recv_async().await.map_err(|_| Error::ChannelClosed)?
+    }
+}
+
+/// The default max length of a datagram received by [`UdsDatagrams`]
+pub const MAX_DATAGRAM_LEN: usize = 64 * 1024;
+
+/// A unix domain datagram socket (`SOCK_DGRAM`).
+///
+/// Unlike [`UdsConnections`] there are no connections to accept, and no framing:
+/// each datagram is one message, `recipient|payload`, passed on to the router
+/// as a [`Message::RemoteMessage`].
+///
+/// A datagram is either received in full or not at all,
+/// however the size of a datagram is limited by the socket buffer of the sender
+/// (see `net.core.wmem_default` on Linux, usually around 200 KiB),
+/// and anything larger than the max length (by default [`MAX_DATAGRAM_LEN`])
+/// is dropped when received.
+///
+/// ```
+/// # use tinyroute::server::UdsDatagrams;
+/// # use tinyroute::{Agent, ToAddress};
+/// # async fn run<A: ToAddress + Sync>(server_agent: Agent<(), A>, sender: A) {
+/// let datagrams = UdsDatagrams::bind("/tmp/my-datagram.sock").expect("failed to create socket");
+/// datagrams.run(server_agent, sender).await;
+/// # }
+/// ```
+pub struct UdsDatagrams {
+    inner: UnixDatagram,
+    max_len: usize,
+}
+
+impl UdsDatagrams {
+    /// Create a new uds datagram socket given a path.
+    ///
+    /// This has to be called from within a tokio runtime.
+    pub fn bind(addr: impl AsRef<Path>) -> Result<Self> {
+        let inner = UnixDatagram::bind(addr.as_ref())?;
+        Ok(Self { inner, max_len: MAX_DATAGRAM_LEN })
+    }
+
+    /// Set the max length of a received datagram
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Receive datagrams and pass them on to the router
+    /// until the server agent is shut down.
+    ///
+    /// All messages are sent from `sender`.
+    pub async fn run<A: ToAddress + Sync>(self, mut server_agent: Agent<(), A>, sender: A) {
+        // One extra byte to tell a datagram that fits from one that was truncated
+        let mut buf = vec![0u8; self.max_len + 1];
+
+        loop {
+            let len = tokio::select! {
+                _ = server_agent.recv() => break,
+                res = self.inner.recv(&mut buf) => match res {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("failed to read from the socket. reason: {:?}", e);
+                        break;
+                    }
+                },
+            };
+
+            if len > self.max_len {
+                warn!("Datagram exceeds the max length of {} bytes, dropping it", self.max_len);
+                continue;
+            }
 
-        Box::pin(future)
+            let bytes = buf[..len].to_vec();
+            if !handle_payload(bytes, &server_agent.router_tx, ConnectionAddr::Uds, sender.clone()).await {
+                break;
+            }
+        }
     }
 }
 
@@ -157,21 +438,26 @@ pub trait Connections: Sync {
     /// The writing half of the connection
     type Writer: AsyncWrite + Unpin + Send + 'static;
 
-    // Accepts &self as arg
-    // Returns a pinned boxed future, where
+    // Returns a future (unboxed, the concrete type is up to the implementation) where
     // * any reference has to live for at least as long as &self,
     // * and it has to be valid to send this across thread boundaries
     //
     // We need the `Send` part because tokio::spawn might put this on another thread.
     // We need the life time because the thing we return can not hold a reference to
     // anything on &self that might be dropped before self.
+    //
+    // Implementations can simply use `async fn accept(&mut self)`.
     /// Accept incoming connections.
     /// If the `Timeout` is set, this means that the server will close and remove
     /// the connection if no message has been received within the given duration.
-    fn accept(&mut self) -> ServerFuture<'_, Self::Reader, Self::Writer>;
+    fn accept(&mut self) -> impl Future<Output = Result<(Self::Reader, Self::Writer, ConnectionAddr)>> + Send + '_;
 }
 
-/// Because writing this entire trait malarkey is messy!
+/// The boxed future [`Connections::accept`] used to return.
+///
+/// An implementation returning this still compiles (with a `refining_impl_trait` warning),
+/// but there is no need to box the future any more: use `async fn accept(&mut self)` instead.
+#[deprecated(note = "`Connections::accept` returns `impl Future`, implement it with `async fn accept`")]
 pub type ServerFuture<'a, T, U> = Pin<Box<dyn Future<Output = Result<(T, U, ConnectionAddr)>> + Send + 'a>>;
 
 /// Accept incoming connections and provide agents as an abstraction.
@@ -203,11 +489,29 @@ pub type ServerFuture<'a, T, U> = Pin<Box<dyn Future<Output = Result<(T, U, Conn
 pub struct Server<C: Connections, A: Sync + ToAddress> {
     server: C,
     server_agent: Agent<(), A>,
+    tap: Option<FrameTap>,
+    rate_limit: Option<RateLimit>,
+    // The address of every connection produced by `next`,
+    // until the connection is dropped or a broadcast finds it gone
+    connections: Arc<Mutex<HashSet<A>>>,
 }
 
 impl<C: Connections, A: Sync + ToAddress> Server<C, A> {
     pub fn new(server: C, server_agent: Agent<(), A>) -> Self {
-        Self { server, server_agent }
+        Self { server, server_agent, tap: None, rate_limit: None, connections: Default::default() }
+    }
+
+    /// Limit how fast each connection can send messages. See [`RateLimit`].
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Pass every message frame on every connection to the `tap`.
+    /// See [`FrameTap`].
+    pub fn with_frame_tap(mut self, tap: FrameTap) -> Self {
+        self.tap = Some(tap);
+        self
     }
 
     /// Produce a [`Connection`]
@@ -223,19 +527,56 @@ impl<C: Connections, A: Sync + ToAddress> Server<C, A> {
         };
 
         let agent = self.server_agent.new_agent(cap, connection_address.clone()).await?;
+        let stats = ConnectionStats::default();
 
         // Spawn the reader
         let _reader_handle = spawn(
             spawn_reader(
                 reader,
-                connection_address,
+                connection_address.clone(),
                 socket_addr,
                 self.server_agent.router_tx.clone(),
-                timeout
+                timeout,
+                self.tap.clone(),
+                Throttle::new(self.rate_limit, stats.clone()),
             )
         ); 
 
-        Ok(Connection::new(agent, writer))
+        let mut connection = Connection::new(agent, writer);
+        connection.tap = self.tap.clone();
+        connection.stats = stats;
+        self.connections.lock().unwrap_or_else(PoisonError::into_inner).insert(connection_address.clone());
+        connection.broadcast = Some(Broadcast { address: connection_address, connections: self.connections.clone() });
+        Ok(connection)
+    }
+
+    /// The number of connections produced by [`Server::next`] that
+    /// [`Server::broadcast_remote`] sends to: the ones that haven't been dropped
+    /// (or found gone by a broadcast).
+    pub fn connection_count(&self) -> usize {
+        self.connections.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    /// Send `bytes` to the client of every connection produced by [`Server::next`].
+    /// The message is framed once, and a copy is queued for each connection.
+    ///
+    /// Delivery is best effort: a connection that is gone is skipped (and logged)
+    /// without affecting the others, and isn't broadcast to again.
+    /// A connection that hasn't noticed its client is gone yet still has the message queued,
+    /// and a message that is queued can still be lost if the connection closes before writing it.
+    ///
+    /// Returns the number of connections the message was queued for.
+    pub async fn broadcast_remote(&mut self, bytes: Bytes) -> Result<usize> {
+        let framed_message = Frame::frame_message(&bytes);
+        let recipients = self.connections.lock().unwrap_or_else(PoisonError::into_inner).iter().cloned().collect::<Vec<_>>();
+        let failed = self.server_agent.send_all_undelivered(recipients, framed_message).await?;
+        for address in &failed {
+            warn!("Failed to broadcast to connection \"{}\"", address.to_string());
+        }
+
+        let mut connections = self.connections.lock().unwrap_or_else(PoisonError::into_inner);
+        connections.retain(|address| !failed.contains(address));
+        Ok(connections.len())
     }
 
     /// Consume the [`Server]` and listening for new connections.
@@ -267,6 +608,113 @@ impl<C: Connections, A: Sync + ToAddress> Server<C, A> {
     }
 }
 
+impl<A: Sync + ToAddress> Server<Listeners, A> {
+    /// Stop accepting connections on the listener called `name`.
+    /// Other listeners, and connections already accepted by this one, are not affected.
+    ///
+    /// Returns false if there is no listener called `name`.
+    pub async fn stop_listener(&mut self, name: &str) -> bool {
+        self.server.stop(name).await
+    }
+}
+
+/// Limit the rate at which a single connection can send messages.
+///
+/// Each limit is a token bucket holding up to one second worth of tokens,
+/// so a connection can send a burst of up to one seconds worth at once.
+/// Once a connection goes over its rate, the connection is not read from
+/// until it's back under, which makes the client wait rather than
+/// having the server buffer the messages.
+///
+/// A rate of zero is the same as `None`: no limit.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RateLimit {
+    /// Maximum number of payload bytes per second, zero for unlimited
+    pub bytes_per_second: Option<u64>,
+    /// Maximum number of messages per second, zero for unlimited
+    pub messages_per_second: Option<u64>,
+}
+
+/// The number of bytes and messages read from a connection.
+/// See [`Connection::stats`].
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    bytes: Arc<AtomicU64>,
+    messages: Arc<AtomicU64>,
+}
+
+impl ConnectionStats {
+    /// Bytes read, excluding the framing but including the recipient address
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Messages read, excluding heartbeats
+    pub fn messages_read(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Token bucket -
+// -----------------------------------------------------------------------------
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    // A rate of zero means no limit, so there is no bucket
+    fn new(rate: u64) -> Option<Self> {
+        let rate = (rate > 0).then_some(rate as f64)?;
+        Some(Self { rate, tokens: rate, last: Instant::now() })
+    }
+
+    // Take `amount` tokens, returning how long to wait
+    // for the bucket to be out of debt
+    fn take(&mut self, amount: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.tokens -= amount as f64;
+
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+// Counts what the reader reads, and works out how long
+// to pause reading for if the connection has a rate limit
+struct Throttle {
+    stats: ConnectionStats,
+    bytes: Option<TokenBucket>,
+    messages: Option<TokenBucket>,
+}
+
+impl Throttle {
+    fn new(rate_limit: Option<RateLimit>, stats: ConnectionStats) -> Self {
+        let rate_limit = rate_limit.unwrap_or_default();
+        Self {
+            stats,
+            bytes: rate_limit.bytes_per_second.and_then(TokenBucket::new),
+            messages: rate_limit.messages_per_second.and_then(TokenBucket::new),
+        }
+    }
+
+    fn read(&mut self, len: usize) -> Duration {
+        self.stats.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.stats.messages.fetch_add(1, Ordering::Relaxed);
+
+        let bytes = self.bytes.as_mut().map(|b| b.take(len as u64)).unwrap_or_default();
+        let messages = self.messages.as_mut().map(|b| b.take(1)).unwrap_or_default();
+        bytes.max(messages)
+    }
+}
+
 pub async fn handle_payload<A: ToAddress>(
     bytes: Vec<u8>,
     router_tx: &RouterTx<A>,
@@ -313,40 +761,63 @@ async fn spawn_reader<A, R>(
     socket_addr: ConnectionAddr,
     router_tx: RouterTx<A>,
     timeout: Option<Duration>,
+    tap: Option<FrameTap>,
+    mut throttle: Throttle,
 ) where
     R: AsyncRead + Unpin,
     A: ToAddress,
 {
     let mut frame = Frame::empty();
     loop {
-        let read = async {
-            let res = frame.read_async(&mut reader).await;
+        // The timeout only covers waiting for the client,
+        // not handing the messages to the router or being rate limited
+        let res = match timeout {
+            Some(timeout) => {
+                tokio::select! {
+                    _ = sleep(timeout) => break,
+                    res = frame.read_async(&mut reader) => res,
+                }
+            }
+            None => frame.read_async(&mut reader).await,
+        };
 
-            'msg: loop {
-                match res {
-                    Err(e) => {
-                        error!("failed to read from the socket. reason: {:?}", e);
-                        break 'msg false;
-                    }
-                    Ok(0) => break 'msg false,
-                    Ok(_) => {
-                        match frame.try_msg() {
-                            Ok(None) => break 'msg true,
-                            Err(e) => {
-                                error!("invalid payload. {}", e);
-                                break 'msg false;
+        let restart = 'msg: loop {
+            match res {
+                Err(e) => {
+                    error!("failed to read from the socket. reason: {:?}", e);
+                    break 'msg false;
+                }
+                Ok(0) => break 'msg false,
+                Ok(_) => {
+                    match frame.try_msg() {
+                        Ok(None) => break 'msg true,
+                        Err(e) => {
+                            error!("invalid payload. {}", e);
+                            break 'msg false;
+                        }
+                        // Heartbeats never reach the agent: reading one already
+                        // reset the idle timeout
+                        Ok(Some(FrameOutput::Heartbeat)) => continue,
+                        Ok(Some(FrameOutput::Message(msg))) => {
+                            if let Some(tap) = &tap {
+                                tap(Direction::Inbound, &Bytes::copy_from_slice(&msg));
                             }
-                            Ok(Some(FrameOutput::Heartbeat)) => continue,
-                            Ok(Some(FrameOutput::Message(msg))) => {
-                                match handle_payload(
-                                    msg,
-                                    &router_tx,
-                                    socket_addr.clone(),
-                                    sender.clone()
-                                ).await {
-                                    true => continue,
-                                    false => break 'msg false,
-                                }
+
+                            // Over the rate limit: nothing more is read from the
+                            // connection until it's back under
+                            let pause = throttle.read(msg.len());
+                            if !pause.is_zero() {
+                                sleep(pause).await;
+                            }
+
+                            match handle_payload(
+                                msg,
+                                &router_tx,
+                                socket_addr.clone(),
+                                sender.clone()
+                            ).await {
+                                true => continue,
+                                false => break 'msg false,
                             }
                         }
                     }
@@ -354,16 +825,6 @@ async fn spawn_reader<A, R>(
             }
         };
 
-        let restart = match timeout {
-            Some(timeout) => {
-                tokio::select! {
-                    _ = sleep(timeout) => false,
-                    restart = read =>  restart ,
-                }
-            }
-            None => read.await,
-        };
-
         if !restart {
             break;
         }
@@ -385,6 +846,22 @@ where
 {
     agent: Agent<FramedMessage, A>,
     writer: W,
+    tap: Option<FrameTap>,
+    stats: ConnectionStats,
+    broadcast: Option<Broadcast<A>>,
+}
+
+// Removes a connection from the broadcasts of the `Server`
+// that produced it, once the connection is dropped
+struct Broadcast<A: ToAddress> {
+    address: A,
+    connections: Arc<Mutex<HashSet<A>>>,
+}
+
+impl<A: ToAddress> Drop for Broadcast<A> {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.address);
+    }
 }
 
 impl<A, W> Connection<A, W>
@@ -393,14 +870,41 @@ where
     W: AsyncWrite + Unpin,
 {
     pub fn new(agent: Agent<FramedMessage, A>, writer: W) -> Self {
-        Self { agent, writer }
+        Self { agent, writer, tap: None, stats: ConnectionStats::default(), broadcast: None }
+    }
+
+    /// The number of bytes and messages read from this connection so far.
+    /// Only connections created by a [`Server`] are counted.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.clone()
     }
 
+    /// A handle to close this connection from elsewhere
+    pub fn handle(&self) -> ConnectionHandle<A> {
+        ConnectionHandle {
+            address: self.agent.address().clone(),
+            router_tx: self.agent.router_tx(),
+        }
+    }
+
+    /// Receive a message for the connection.
+    /// Framed messages are written to the connection and `Ok(None)` is returned,
+    /// any other message is returned as is.
+    ///
+    /// On `Message::Shutdown` the connection is closed for writing,
+    /// and the client will see the connection closed.
     pub async fn recv(&mut self) -> Result<Option<Message<FramedMessage, A>>> {
         let msg = self.agent.recv().await?;
         match msg {
+            Message::Shutdown => {
+                self.writer.shutdown().await?;
+                Ok(Some(msg))
+            }
             Message::Value(framed_message, _) => {
-                self.writer.write_all(&framed_message.0).await?;
+                if let Some(tap) = &self.tap {
+                    tap(Direction::Outbound, framed_message.as_bytes());
+                }
+                self.writer.write_all(framed_message.as_bytes()).await?;
                 Ok(None)
             }
             _ => Ok(Some(msg)),
@@ -408,6 +912,150 @@ where
     }
 }
 
+#[cfg(unix)]
+impl<A: ToAddress> Connection<A, tokio::net::unix::OwnedWriteHalf> {
+    /// Send a file descriptor to the client.
+    /// See [`crate::fd`] for more information.
+    pub async fn send_fd(&mut self, fd: std::os::unix::io::RawFd, payload: &[u8]) -> Result<()> {
+        crate::fd::send_fd(self.writer.as_ref(), fd, payload).await
+    }
+}
+
+// Send a file as the body of a single frame, with `sendfile` where available,
+// for connections over both tcp and unix sockets
+#[cfg(unix)]
+macro_rules! impl_send_file {
+    ($writer:ty) => {
+        impl<A: ToAddress> Connection<A, $writer> {
+            /// Send `len` bytes of a file, starting at `offset`, as a single frame.
+            ///
+            /// On Linux the file is copied to the socket by the kernel (`sendfile`),
+            /// without going through user space.
+            /// Elsewhere, or if the file doesn't support it, the file is read in chunks
+            /// and written like any other message.
+            ///
+            /// The file is not passed to the [`FrameTap`].
+            /// Returns an error if the file is shorter than `offset + len`,
+            /// or if `len` is more than a frame can hold (`u32::MAX`).
+            pub async fn send_file(&mut self, file: &std::fs::File, offset: u64, len: usize) -> Result<()> {
+                if len as u64 > u32::MAX as u64 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "file too large for a frame").into());
+                }
+
+                self.writer.write_all(&Frame::frame_header(len, 0)).await?;
+
+                #[allow(unused_mut)]
+                let mut sent = 0;
+
+                #[cfg(target_os = "linux")]
+                while sent < len {
+                    use std::os::unix::io::AsRawFd;
+
+                    let socket = self.writer.as_ref();
+                    let res = socket
+                        .async_io(tokio::io::Interest::WRITABLE, || {
+                            sendfile(socket.as_raw_fd(), file.as_raw_fd(), offset + sent as u64, len - sent)
+                        })
+                        .await;
+
+                    match res {
+                        Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                        Ok(n) => sent += n,
+                        // The file doesn't support `sendfile`, copy the rest instead
+                        Err(e) if e.raw_os_error() == Some(libc::EINVAL) || e.raw_os_error() == Some(libc::ENOSYS) => break,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+
+                copy_file(&mut self.writer, file, offset + sent as u64, len - sent).await
+            }
+        }
+    };
+}
+
+#[cfg(unix)]
+impl_send_file!(tokio::net::tcp::OwnedWriteHalf);
+#[cfg(unix)]
+impl_send_file!(tokio::net::unix::OwnedWriteHalf);
+
+#[cfg(target_os = "linux")]
+fn sendfile(socket: std::os::unix::io::RawFd, file: std::os::unix::io::RawFd, offset: u64, len: usize) -> std::io::Result<usize> {
+    let mut offset = offset as libc::off_t;
+    match unsafe { libc::sendfile(socket, file, &mut offset, len) } {
+        n if n < 0 => Err(std::io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+// The fallback for `send_file`: read the file in chunks and write them.
+// The reads block, so they run on the blocking pool with a copy of the file handle.
+#[cfg(unix)]
+async fn copy_file(writer: &mut (impl AsyncWrite + Unpin), file: &std::fs::File, mut offset: u64, mut len: usize) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut file = file.try_clone()?;
+    let mut buf = vec![0u8; len.min(64 * 1024)];
+    while len > 0 {
+        let chunk = len.min(buf.len());
+        let (read, f, b) = tokio::task::spawn_blocking(move || {
+            let read = file.read_at(&mut buf[..chunk], offset);
+            (read, file, buf)
+        })
+        .await
+        .map_err(std::io::Error::other)?;
+        (file, buf) = (f, b);
+
+        let read = read?;
+        if read == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        writer.write_all(&buf[..read]).await?;
+        offset += read as u64;
+        len -= read;
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+//     - Connection handle -
+// -----------------------------------------------------------------------------
+/// Close a [`Connection`] from the server side, e.g to kick a client.
+///
+/// ```
+/// # use tinyroute::server::Connection;
+/// # use tinyroute::ToAddress;
+/// # async fn run<A: ToAddress, W: tokio::io::AsyncWrite + Unpin>(connection: Connection<A, W>) {
+/// let handle = connection.handle();
+/// // ...
+/// handle.close("idle for too long").await.unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ConnectionHandle<A: ToAddress> {
+    address: A,
+    router_tx: RouterTx<A>,
+}
+
+impl<A: ToAddress> ConnectionHandle<A> {
+    /// The address of the connection agent
+    pub fn address(&self) -> &A {
+        &self.address
+    }
+
+    /// Close the connection.
+    ///
+    /// Messages already queued for the connection are written first,
+    /// then the connection receives a `Message::Shutdown`, closes the
+    /// connection and is unregistered.
+    ///
+    /// There is no close frame in the protocol, so the `reason` is only logged,
+    /// and the client sees the connection closed by the server.
+    pub async fn close(&self, reason: &str) -> Result<()> {
+        info!("Closing connection \"{}\": {}", self.address.to_string(), reason);
+        self.router_tx.send(RouterMessage::Shutdown(self.address.clone())).await
+    }
+}
+
 // -----------------------------------------------------------------------------
 //     - Connection adddress -
 // -----------------------------------------------------------------------------
@@ -0,0 +1,109 @@
+//! Helpers for testing agents.
+//!
+//! Requires the `testkit` feature.
+//!
+//! ```
+//! # use tinyroute::{Message, Router, ToAddress};
+//! # use tinyroute::testkit::assert_recv_sequence;
+//! # #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+//! # enum Address { A, B }
+//! # impl ToAddress for Address {}
+//! # async fn run() {
+//! let mut router = Router::new();
+//! let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+//! let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+//! tokio::spawn(router.run());
+//!
+//! agent_a.send(Address::B, 1u32).await.unwrap();
+//! agent_a.send(Address::B, 2u32).await.unwrap();
+//!
+//! assert_recv_sequence(&mut agent_b, &[1, 2], |msg, expected| {
+//!     matches!(msg, Message::Value(val, Address::A) if val == expected)
+//! })
+//! .await;
+//! # }
+//! ```
+use std::fmt::{Debug, Write};
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use crate::agent::{Agent, Message};
+use crate::ToAddress;
+
+/// How long [`assert_recv_sequence`] waits for each message.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Receive `expected.len()` messages and assert that each one matches
+/// the expected value at the same position, according to `matches`.
+///
+/// Waits at most [`DEFAULT_TIMEOUT`] for each message.
+///
+/// # Panics
+///
+/// Panics if a message does not match, the agent stops receiving,
+/// or a message does not arrive in time. The panic message lists
+/// every expected value next to what was received.
+pub async fn assert_recv_sequence<T, A, E, F>(
+    agent: &mut Agent<T, A>,
+    expected: &[E],
+    matches: F,
+) where
+    T: Debug + Send + 'static,
+    A: ToAddress,
+    E: Debug,
+    F: Fn(&Message<T, A>, &E) -> bool,
+{
+    assert_recv_sequence_within(agent, expected, matches, DEFAULT_TIMEOUT)
+        .await
+}
+
+/// Same as [`assert_recv_sequence`] but waits at most `wait`
+/// for each message.
+pub async fn assert_recv_sequence_within<T, A, E, F>(
+    agent: &mut Agent<T, A>,
+    expected: &[E],
+    matches: F,
+    wait: Duration,
+) where
+    T: Debug + Send + 'static,
+    A: ToAddress,
+    E: Debug,
+    F: Fn(&Message<T, A>, &E) -> bool,
+{
+    let mut received = Vec::with_capacity(expected.len());
+    for _ in expected {
+        match timeout(wait, agent.recv()).await {
+            Ok(Ok(msg)) => received.push(Ok(msg)),
+            Ok(Err(e)) => {
+                received.push(Err(format!("receive failed: {}", e)));
+                break;
+            }
+            Err(_) => {
+                received.push(Err(format!("timed out after {:?}", wait)));
+                break;
+            }
+        }
+    }
+
+    let mut failed = false;
+    let mut report = String::new();
+    for (index, expected) in expected.iter().enumerate() {
+        let (status, got) = match received.get(index) {
+            Some(Ok(msg)) if matches(msg, expected) => ("ok", format!("{:?}", msg)),
+            Some(Ok(msg)) => ("MISMATCH", format!("{:?}", msg)),
+            Some(Err(reason)) => ("MISSING", reason.clone()),
+            None => ("MISSING", "not received".to_string()),
+        };
+        failed |= status != "ok";
+        let _ = writeln!(
+            report,
+            "  #{:<3} {:<8} expected {:?}, received {}",
+            index, status, expected, got
+        );
+    }
+
+    if failed {
+        panic!("message sequence mismatch:\n{}", report);
+    }
+}
@@ -0,0 +1,119 @@
+use tinyroute::FromAddressStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Address {
+    Logger,
+    Id(u64),
+}
+
+impl FromAddressStr for Address {
+    fn from_str_addr(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            Some(("id", id)) => id.parse().ok().map(Address::Id),
+            None if s == "logger" => Some(Address::Logger),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn from_str_addr() {
+    assert_eq!(Some(Address::Logger), Address::from_str_addr("logger"));
+    assert_eq!(Some(Address::Id(42)), Address::from_str_addr("id:42"));
+    assert_eq!(None, Address::from_str_addr("id:forty-two"));
+    assert_eq!(None, Address::from_str_addr("printer"));
+}
+
+#[test]
+fn from_line() {
+    assert_eq!(Some((Address::Logger, "hello|world")), Address::from_line("logger|hello|world"));
+    assert_eq!(Some((Address::Id(42), "")), Address::from_line(" id:42 |"));
+    assert_eq!(None, Address::from_line("logger"));
+    assert_eq!(None, Address::from_line("printer|hello"));
+}
+
+mod anonymous {
+    use tinyroute::{Message, Router, ToAddress};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Address {
+        Server,
+        Anonymous(u64),
+    }
+
+    impl ToAddress for Address {
+        fn anonymous(id: u64) -> Option<Self> {
+            Some(Address::Anonymous(id))
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Named;
+
+    impl ToAddress for Named {}
+
+    #[tokio::test]
+    async fn next_anonymous_address() {
+        let mut router = Router::new();
+        let server = router.new_agent::<u32>(None, Address::Server).unwrap();
+        // Already registered, so it's skipped
+        let _taken = router.new_agent::<u32>(None, Address::Anonymous(1)).unwrap();
+
+        let mut agents = Vec::new();
+        for _ in 0..3 {
+            let address = router.next_anonymous_address().unwrap();
+            agents.push(router.new_agent::<u32>(None, address).unwrap());
+        }
+        let addresses = agents.iter().map(|a| a.address().clone()).collect::<Vec<_>>();
+        assert_eq!(vec![Address::Anonymous(0), Address::Anonymous(2), Address::Anonymous(3)], addresses);
+        tokio::spawn(router.run());
+
+        for (i, address) in addresses.into_iter().enumerate() {
+            server.send(address, i as u32).await.unwrap();
+        }
+        for (i, agent) in agents.iter_mut().enumerate() {
+            let msg = agent.recv().await.unwrap();
+            assert!(matches!(msg, Message::Value(v, Address::Server) if v == i as u32));
+        }
+    }
+
+    #[test]
+    fn no_anonymous_addresses() {
+        assert!(Router::<Named>::new().next_anonymous_address().is_none());
+    }
+}
+
+mod to_bytes {
+    use tinyroute::ToAddress;
+
+    // Only `to_string` and `from_bytes`, `to_bytes` is the default
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Address {
+        Logger,
+        Id(u64),
+    }
+
+    impl ToAddress for Address {
+        fn from_bytes(bytes: &[u8]) -> Option<Self> {
+            match std::str::from_utf8(bytes).ok()? {
+                "logger" => Some(Address::Logger),
+                s => s.strip_prefix("id:")?.parse().ok().map(Address::Id),
+            }
+        }
+
+        fn to_string(&self) -> String {
+            match self {
+                Address::Logger => "logger".into(),
+                Address::Id(id) => format!("id:{}", id),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for address in [Address::Logger, Address::Id(42)] {
+            assert_eq!(address.to_string().into_bytes(), address.to_bytes());
+            assert_eq!(Some(address.clone()), Address::from_bytes(&address.to_bytes()));
+        }
+    }
+}
@@ -1,17 +1,27 @@
-use tinyroute::{Agent, Message, Router, ToAddress};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use tinyroute::{Agent, CapPolicy, DropReason, Message, RemovalReason, Router, ToAddress};
+use tinyroute::agent::Unmatched;
 use tinyroute::errors::Error;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Address {
     A,
     B,
+    C,
+    D,
 }
 
 impl ToAddress for Address {
-    fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        match bytes {
-            _ => None,
-        }
+    fn from_bytes(_: &[u8]) -> Option<Self> {
+        None
+    }
+
+    fn to_string(&self) -> String {
+        format!("{:?}", self)
     }
 }
 
@@ -48,6 +58,24 @@ async fn shutdown_router() {
     handle.await.unwrap();
 }
 
+#[tokio::test]
+async fn shutdown_router_full_channel() {
+    let mut router = Router::new();
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(Some(1), Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    // Fill the channel of B, and only make room once the router is shutting down
+    agent_a.send(Address::B, "hello".to_string()).await.unwrap();
+    agent_a.router_tx().queue_utilization().await.unwrap();
+    agent_a.shutdown_router().await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(_, Address::A)));
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Shutdown));
+    handle.await.unwrap();
+}
+
 #[tokio::test]
 async fn shutdown_agent() {
     let (agent_a, mut agent_b, handle) = setup();
@@ -58,3 +86,1337 @@ async fn shutdown_agent() {
     agent_a.shutdown_router().await;
     handle.await.unwrap();
 }
+
+#[tokio::test]
+async fn try_send_to_busy_router() {
+    let mut router = Router::with_capacity(1).unwrap();
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let _agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+
+    // The router isn't running, so the first message fills the channel
+    agent_a.try_send(Address::B, "first".to_string()).unwrap();
+    let err = agent_a.try_send(Address::B, "second".to_string());
+    assert!(matches!(err, Err(Error::RouterBusy)));
+}
+
+#[tokio::test]
+async fn send_with_capacity() {
+    let mut router = Router::with_capacity(4).unwrap();
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_a.send(Address::B, "hello".to_string()).await.unwrap();
+    match agent_b.recv().await.unwrap() {
+        Message::Value(value, sender) => {
+            assert_eq!("hello", value);
+            assert_eq!(Address::A, sender);
+        }
+        _ => panic!("expected a value"),
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn send_before_run() {
+    let mut router = Router::with_capacity(4).unwrap();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+
+    for i in 0..4u32 {
+        agent_a.try_send(Address::B, i).unwrap();
+    }
+    assert!(matches!(agent_a.try_send(Address::B, 4u32), Err(Error::RouterBusy)));
+    let res = tokio::time::timeout(Duration::from_millis(20), agent_a.send(Address::B, 4u32)).await;
+    assert!(res.is_err(), "send didn't wait for room in the router");
+
+    let handle = tokio::spawn(router.run());
+    agent_a.send(Address::B, 4u32).await.unwrap();
+    for expected in 0..5u32 {
+        let msg = agent_b.recv().await.unwrap();
+        assert!(matches!(msg, Message::Value(v, Address::A) if v == expected));
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[test]
+fn invalid_capacity() {
+    assert!(matches!(Router::<Address>::with_capacity(0), Err(Error::InvalidCapacity)));
+    assert!(matches!(Router::<Address>::new_batched(0, 16, Duration::ZERO), Err(Error::InvalidCapacity)));
+    assert!(matches!(Router::<Address>::new_batched(16, 0, Duration::ZERO), Err(Error::InvalidCapacity)));
+}
+
+#[tokio::test]
+async fn lossy_agent_zero_capacity() {
+    let mut router = Router::<Address>::new();
+    assert!(matches!(router.new_lossy_agent::<()>(0, Address::B), Err(Error::InvalidCapacity)));
+    let agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let agent_b = agent_a.new_lossy_agent::<()>(0, Address::B).await;
+    assert!(matches!(agent_b, Err(Error::InvalidCapacity)));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn router_stops_without_router_tx() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    drop(agent_a);
+    tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn recv_with_backlog() {
+    let (agent_a, mut agent_b, handle) = setup();
+
+    for i in 0..5 {
+        agent_a.send(Address::B, i.to_string()).await.unwrap();
+    }
+
+    for expected in (0..5).rev() {
+        let (_, backlog) = agent_b.recv_with_backlog().await.unwrap();
+        assert_eq!(expected, backlog);
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn rename_agent() {
+    let (agent_a, mut agent_b, handle) = setup();
+    agent_b.rename(Address::C, Duration::from_millis(50)).await.unwrap();
+    assert_eq!(&Address::C, agent_b.address());
+
+    // Both addresses are registered during the grace period
+    agent_a.send(Address::B, "old".to_string()).await.unwrap();
+    agent_a.send(Address::C, "new".to_string()).await.unwrap();
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(msg, Address::A) if msg == "old"));
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(msg, Address::A) if msg == "new"));
+
+    // After the grace period the old address is gone
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    agent_a.send(Address::B, "old".to_string()).await.unwrap();
+    agent_a.send(Address::C, "new".to_string()).await.unwrap();
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(msg, Address::A) if msg == "new"));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn batched_router_preserves_order() {
+    let mut router = Router::new_batched(128, 16, Duration::from_millis(1)).unwrap();
+    let agent_a = router.new_agent::<usize>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<usize>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    for i in 0..1000usize {
+        agent_a.send(Address::B, i).await.unwrap();
+    }
+
+    for i in 0..1000 {
+        assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(val, Address::A) if val == i));
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn lossy_agent_drops_oldest() {
+    let mut router = Router::new();
+    let agent_a = router.new_agent::<usize>(None, Address::A).unwrap();
+    let mut agent_b = router.new_lossy_agent::<usize>(3, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    for i in 0..10usize {
+        agent_a.send(Address::B, i).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    for i in 7..10 {
+        assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(val, Address::A) if val == i));
+    }
+    assert_eq!(7, agent_b.dropped());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn graceful_shutdown_waits_for_agents() {
+    let (agent_a, mut agent_b, handle) = setup();
+    let done = Arc::new(AtomicBool::new(false));
+
+    let slow_agent = {
+        let done = done.clone();
+        tokio::spawn(async move {
+            assert!(matches!(agent_b.recv().await.unwrap(), Message::Shutdown));
+            // Finish up some work
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+
+    agent_a.shutdown_router_graceful(Duration::from_secs(5)).await;
+    drop(agent_a);
+    handle.await.unwrap();
+    assert!(done.load(Ordering::SeqCst));
+    slow_agent.await.unwrap();
+}
+
+#[tokio::test]
+async fn close_unregisters_before_returning() {
+    let (agent_a, agent_b, handle) = setup();
+
+    agent_b.close().await.unwrap();
+
+    // The address is free as soon as `close` returns
+    let mut agent_b = agent_a.new_agent::<String>(None, Address::B).await.unwrap();
+    agent_a.send(Address::B, "hello".to_string()).await.unwrap();
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(msg, Address::A) if msg == "hello"));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn on_drop_reasons() {
+    let mut router = Router::<Address>::new();
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let on_drop = drops.clone();
+    router.on_drop(move |reason, recipient| on_drop.lock().unwrap().push((*reason, recipient.clone())));
+
+    let agent_a = router.new_agent::<usize>(None, Address::A).unwrap();
+    let _agent_b = router.new_lossy_agent::<usize>(1, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    // No agent at C
+    agent_a.send(Address::C, 0usize).await.unwrap();
+    // The first message is evicted by the second one
+    agent_a.send(Address::B, 1usize).await.unwrap();
+    agent_a.send(Address::B, 2usize).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let expected = vec![(DropReason::NoRecipient, Address::C), (DropReason::Evicted, Address::B)];
+    assert_eq!(expected, *drops.lock().unwrap());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[test]
+fn router_on_current_thread() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async {
+        let mut router = Router::new();
+        let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+        let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+
+        // Drive the router on this task, no spawning
+        let agents = async move {
+            agent_a.send(Address::B, "hello".to_string()).await.unwrap();
+            let msg = agent_b.recv().await.unwrap();
+            assert!(matches!(msg, Message::Value(msg, Address::A) if msg == "hello"));
+            agent_a.shutdown_router().await;
+        };
+
+        tokio::join!(router.run(), agents);
+    });
+}
+
+#[tokio::test]
+async fn too_many_tracks() {
+    let mut router = Router::new();
+    router.max_tracks(2);
+    let mut agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let agent_b = router.new_agent::<()>(None, Address::B).unwrap();
+    let _agent_c = router.new_agent::<()>(None, Address::C).unwrap();
+    let _agent_d = router.new_agent::<()>(None, Address::D).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_a.track(Address::B).await.unwrap();
+    agent_a.track(Address::C).await.unwrap();
+    // Tracking the same address again doesn't count
+    agent_a.track(Address::B).await.unwrap();
+
+    let err = agent_a.track(Address::D).await;
+    assert!(matches!(err, Err(Error::TooManyTracks)));
+
+    // The existing tracks still work
+    drop(agent_b);
+    let msg = agent_a.recv().await.unwrap();
+    assert!(matches!(msg, Message::AgentRemoved(Address::B, RemovalReason::Dropped)));
+
+    // and removing one makes room for another
+    agent_a.track(Address::D).await.unwrap();
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn max_queued_across_agents() {
+    let mut router = Router::new();
+    router.max_queued(2);
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let mut agent_c = router.new_agent::<String>(None, Address::C).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    // Each agent has room, but together they hit the ceiling
+    agent_a.send(Address::B, "first".to_string()).await.unwrap();
+    agent_a.send(Address::C, "second".to_string()).await.unwrap();
+    let err = agent_a.send(Address::B, "third".to_string()).await;
+    assert!(matches!(err, Err(Error::QueuesFull)));
+    let err = agent_a.try_send(Address::C, "third".to_string());
+    assert!(matches!(err, Err(Error::QueuesFull)));
+
+    // Receiving a message makes room
+    agent_b.recv().await.unwrap();
+    agent_a.send(Address::B, "third".to_string()).await.unwrap();
+
+    let msg = agent_c.recv().await.unwrap();
+    assert!(matches!(msg, Message::Value(val, Address::A) if val == "second"));
+    let msg = agent_b.recv().await.unwrap();
+    assert!(matches!(msg, Message::Value(val, Address::A) if val == "third"));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn spawn_echo_agent() {
+    let mut router = Router::new();
+    let mut agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let echo = router
+        .spawn_agent(None, Address::B, |msg: Message<String, Address>, ctx| async move {
+            if let Message::Value(text, sender) = msg {
+                ctx.send(sender, text).await?;
+            }
+            Ok(())
+        })
+        .unwrap();
+    let handle = tokio::spawn(router.run());
+    assert_eq!(&Address::B, echo.address());
+
+    agent_a.send(Address::B, "hello".to_string()).await.unwrap();
+    let msg = agent_a.recv().await.unwrap();
+    assert!(matches!(msg, Message::Value(text, Address::B) if text == "hello"));
+
+    // Shutting the agent down unregisters it
+    agent_a.track(Address::B).await.unwrap();
+    echo.shutdown().await.unwrap();
+    let msg = agent_a.recv().await.unwrap();
+    assert!(matches!(msg, Message::AgentRemoved(Address::B, RemovalReason::Shutdown)));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn new_agent_after_shutdown() {
+    let (agent_a, _agent_b, handle) = setup();
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+
+    let res = agent_a.new_agent::<String>(None, Address::C).await;
+    assert!(matches!(res, Err(Error::RouterGone)));
+}
+
+#[tokio::test]
+async fn new_agent_during_graceful_shutdown() {
+    let (agent_a, mut agent_b, handle) = setup();
+    agent_a.shutdown_router_graceful(Duration::from_secs(1)).await;
+
+    // B is still around, keeping the router waiting
+    let msg = agent_b.recv().await.unwrap();
+    assert!(matches!(msg, Message::Shutdown));
+    let res = agent_b.new_agent::<String>(None, Address::C).await;
+    assert!(matches!(res, Err(Error::RouterGone)));
+
+    drop(agent_a);
+    drop(agent_b);
+    handle.await.unwrap();
+}
+
+#[derive(Debug)]
+struct Ping(u32);
+#[derive(Debug)]
+struct Pong(u32);
+
+#[tokio::test]
+async fn auto_reply_to_pings() {
+    let mut router = Router::new();
+    let mut agent_a = router.new_agent::<Pong>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_b.auto_reply_to(|Ping(n): Ping, _sender: &Address| Pong(n));
+
+    agent_a.send(Address::B, Ping(1)).await.unwrap();
+    agent_a.send(Address::B, "hello".to_string()).await.unwrap();
+    agent_a.send(Address::B, Ping(2)).await.unwrap();
+    agent_a.send(Address::B, "world".to_string()).await.unwrap();
+
+    // B only ever sees the strings
+    for expected in ["hello", "world"] {
+        let msg = agent_b.recv().await.unwrap();
+        assert!(matches!(msg, Message::Value(val, Address::A) if val == expected));
+    }
+
+    // and A gets the pongs
+    for expected in [1, 2] {
+        let msg = agent_a.recv().await.unwrap();
+        assert!(matches!(msg, Message::Value(Pong(n), Address::B) if n == expected));
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn auto_replies_are_queued() {
+    let mut router = Router::new();
+    router.max_queued(2);
+    let mut agent_a = router.new_agent::<Pong>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_b.auto_reply_to(|Ping(n): Ping, _sender: &Address| Pong(n));
+
+    // The pongs A hasn't received yet fill up the queue
+    for n in 0..2u32 {
+        agent_a.send(Address::B, Ping(n)).await.unwrap();
+        agent_a.router_tx().queue_utilization().await.unwrap();
+        assert!(agent_b.try_recv().unwrap().is_none());
+    }
+    let err = agent_a.send(Address::B, Ping(2)).await;
+    assert!(matches!(err, Err(Error::QueuesFull)));
+
+    // Receiving them makes room
+    for expected in 0..2u32 {
+        let msg = agent_a.recv().await.unwrap();
+        assert!(matches!(msg, Message::Value(Pong(n), Address::B) if n == expected));
+    }
+    agent_a.send(Address::B, Ping(2)).await.unwrap();
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn try_send_or_keep_queues_full() {
+    let mut router = Router::new();
+    router.max_queued(1);
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    assert!(agent_a.try_send_or_keep(Address::B, "first".to_string()).unwrap().is_none());
+
+    // The message is handed back rather than dropped
+    let kept = agent_a.try_send_or_keep(Address::B, "second".to_string()).unwrap();
+    assert_eq!(Some("second".to_string()), kept);
+
+    // and can be sent once there is room
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(s, Address::A) if s == "first"));
+    assert!(agent_a.try_send_or_keep(Address::B, kept.unwrap()).unwrap().is_none());
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(s, Address::A) if s == "second"));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn classified_by_opcode() {
+    const TO_B: u32 = 1;
+    const TO_C: u32 = 2;
+
+    let mut router = Router::<Address>::new();
+    router.set_classifier(|_sender, opcode| match opcode {
+        TO_B => Some(Address::B),
+        TO_C => Some(Address::C),
+        _ => None,
+    });
+    let drops = Arc::new(Mutex::new(Vec::new()));
+    let on_drop = drops.clone();
+    router.on_drop(move |reason, address| on_drop.lock().unwrap().push((*reason, address.clone())));
+
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let mut agent_c = router.new_agent::<String>(None, Address::C).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_a.send_classified(TO_C, "to c".to_string()).await.unwrap();
+    agent_a.send_classified(TO_B, "to b".to_string()).await.unwrap();
+    agent_a.send_classified(99, "to no one".to_string()).await.unwrap();
+
+    let msg = agent_b.recv().await.unwrap();
+    assert!(matches!(msg, Message::Value(val, Address::A) if val == "to b"));
+    let msg = agent_c.recv().await.unwrap();
+    assert!(matches!(msg, Message::Value(val, Address::A) if val == "to c"));
+
+    // Unclassified messages are dropped
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+    assert_eq!(vec![(DropReason::Unclassified, Address::A)], *drops.lock().unwrap());
+}
+
+#[tokio::test]
+async fn send_cap() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let _agent_c = router.new_agent::<String>(None, Address::C).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    // A can only be handed capabilities for B and D
+    let policy = CapPolicy::new(|address| matches!(address, Address::B | Address::D));
+    assert!(policy.mint(Address::C).is_none());
+    assert!(policy.mint(Address::A).is_none());
+
+    let cap = policy.mint(Address::B).unwrap();
+    assert_eq!(&Address::B, cap.address());
+    agent_a.send_cap(cap, "hello".to_string()).await.unwrap();
+
+    let msg = agent_b.recv().await.unwrap();
+    assert!(matches!(msg, Message::Value(val, Address::A) if val == "hello"));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn queue_utilization() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<()>(Some(10), Address::A).unwrap();
+    let _agent_b = router.new_agent::<()>(Some(5), Address::B).unwrap();
+    let _agent_c = router.new_agent::<()>(None, Address::C).unwrap();
+    let router_tx = router.router_tx();
+    let handle = tokio::spawn(router.run());
+
+    for _ in 0..3 {
+        agent_a.send(Address::B, ()).await.unwrap();
+    }
+    agent_a.send(Address::C, ()).await.unwrap();
+
+    let mut utilization = router_tx.queue_utilization().await.unwrap();
+    utilization.sort_by_key(|(address, _, _)| format!("{:?}", address));
+    let expected = vec![
+        (Address::A, 0, 10),
+        (Address::B, 3, 5),
+        (Address::C, 1, usize::MAX),
+    ];
+    assert_eq!(expected, utilization);
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn try_recv() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let router_tx = router.router_tx();
+    let handle = tokio::spawn(router.run());
+
+    assert!(agent_b.try_recv().unwrap().is_none());
+
+    agent_a.send(Address::B, "one".to_string()).await.unwrap();
+    agent_a.send(Address::B, "two".to_string()).await.unwrap();
+    // The router handles messages in order, so both are queued by now
+    router_tx.queue_utilization().await.unwrap();
+
+    for expected in ["one", "two"] {
+        let msg = agent_b.try_recv().unwrap();
+        assert!(matches!(msg, Some(Message::Value(val, Address::A)) if val == expected));
+    }
+    assert!(agent_b.try_recv().unwrap().is_none());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn mutual_send_makes_progress() {
+    const COUNT: usize = 200;
+
+    // Everything bounded, with barely any room
+    let mut router = Router::<Address>::with_capacity(1).unwrap();
+    let agent_a = router.new_agent::<usize>(Some(1), Address::A).unwrap();
+    let agent_b = router.new_agent::<usize>(Some(1), Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    async fn ping_pong(mut agent: Agent<usize, Address>, other: Address) -> Agent<usize, Address> {
+        let (mut sent, mut received) = (0, 0);
+        let mut pending = None;
+        while sent < COUNT || received < COUNT {
+            if sent < COUNT {
+                match agent.try_send_or_keep(other.clone(), pending.take().unwrap_or(sent)).unwrap() {
+                    None => sent += 1,
+                    Some(busy) => pending = Some(busy),
+                }
+            }
+
+            // Receive while the router is busy, rather than waiting for room
+            if pending.is_some() || sent == COUNT {
+                let recv = tokio::time::timeout(Duration::from_millis(1), agent.recv()).await;
+                if let Ok(msg) = recv {
+                    assert!(matches!(msg.unwrap(), Message::Value(_, _)));
+                    received += 1;
+                }
+            } else if agent.try_recv().unwrap().is_some() {
+                received += 1;
+            }
+        }
+        agent
+    }
+
+    let a = tokio::spawn(ping_pong(agent_a, Address::B));
+    let b = tokio::spawn(ping_pong(agent_b, Address::A));
+    let both = async { (a.await.unwrap(), b.await.unwrap()) };
+    let (agent_a, _agent_b) = tokio::time::timeout(Duration::from_secs(5), both).await.unwrap();
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn recv_timeout() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let res = agent_b.recv_timeout(Duration::from_millis(20)).await;
+    assert!(matches!(res, Err(Error::Timeout)));
+
+    // Arriving well within the timeout
+    let sender = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        agent_a.send(Address::B, "just in time".to_string()).await.unwrap();
+        agent_a
+    });
+    let msg = agent_b.recv_timeout(Duration::from_millis(500)).await.unwrap();
+    assert!(matches!(msg, Message::Value(val, Address::A) if val == "just in time"));
+
+    let agent_a = sender.await.unwrap();
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn recv_many() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<usize>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<usize>(None, Address::B).unwrap();
+    let router_tx = router.router_tx();
+    let handle = tokio::spawn(router.run());
+
+    for i in 0..50usize {
+        agent_a.send(Address::B, i).await.unwrap();
+    }
+    router_tx.queue_utilization().await.unwrap();
+
+    let messages = agent_b.recv_many(64).await.unwrap();
+    let values = messages
+        .into_iter()
+        .map(|msg| match msg {
+            Message::Value(val, Address::A) => val,
+            _ => panic!("invalid message"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!((0..50).collect::<Vec<_>>(), values);
+
+    // Zero doesn't wait, or take anything off the queue
+    agent_a.send(Address::B, 50usize).await.unwrap();
+    router_tx.queue_utilization().await.unwrap();
+    assert!(agent_b.recv_many(0).await.unwrap().is_empty());
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(50, Address::A)));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn request_reply() {
+    let mut router = Router::<Address>::new();
+    let mut agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let agent_c = router.new_agent::<()>(None, Address::C).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    // C sends something to A ahead of the reply,
+    // and B replies with the length of the request
+    agent_c.send(Address::A, "unrelated".to_string()).await.unwrap();
+    let echo = tokio::spawn(async move {
+        if let Message::Value(request, sender) = agent_b.recv().await.unwrap() {
+            agent_b.send(sender, request.len()).await.unwrap();
+        }
+        agent_b
+    });
+
+    let len: usize = agent_a.request(Address::B, "hello".to_string()).await.unwrap();
+    assert_eq!(5, len);
+
+    // Nothing received in the meantime is lost
+    let msg = agent_a.recv().await.unwrap();
+    assert!(matches!(msg, Message::Value(val, Address::C) if val == "unrelated"));
+
+    let _agent_b = echo.await.unwrap();
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn barrier() {
+    let mut router = Router::<Address>::new();
+    let agents = [Address::A, Address::B, Address::C]
+        .into_iter()
+        .map(|address| router.new_agent::<()>(None, address).unwrap())
+        .collect::<Vec<_>>();
+    let agent_d = router.new_agent::<()>(None, Address::D).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let released = Arc::new(AtomicUsize::new(0));
+    let mut agents = agents.into_iter();
+    let mut waiting = Vec::new();
+    for agent in agents.by_ref().take(2) {
+        let released = released.clone();
+        waiting.push(tokio::spawn(async move {
+            agent.arrive_barrier("startup", 3).await.unwrap();
+            released.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+
+    // Two out of three is not enough
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(0, released.load(Ordering::SeqCst));
+
+    let last = agents.next().unwrap();
+    last.arrive_barrier("startup", 3).await.unwrap();
+    for waiting in waiting {
+        waiting.await.unwrap();
+    }
+    assert_eq!(2, released.load(Ordering::SeqCst));
+
+    agent_d.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn send_all() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let mut agent_c = router.new_agent::<String>(None, Address::C).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    // There is no agent at D
+    let res = agent_a.send_all([Address::B, Address::D, Address::C], "hello".to_string()).await;
+    assert!(matches!(res, Err(Error::Undelivered(failed)) if failed == vec!["D".to_string()]));
+
+    for agent in [&mut agent_b, &mut agent_c] {
+        let msg = agent.recv().await.unwrap();
+        assert!(matches!(msg, Message::Value(val, Address::A) if val == "hello"));
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn cloned_sender() {
+    let mut router = Router::<Address>::new();
+    let mut agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let sender = agent_a.sender();
+    let workers = ["one", "two"]
+        .into_iter()
+        .map(|value| {
+            let sender = sender.clone();
+            tokio::spawn(async move { sender.send(Address::A, value.to_string()).await.unwrap() })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        worker.await.unwrap();
+    }
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        match agent_a.recv().await.unwrap() {
+            Message::Value(val, Address::A) => received.push(val),
+            _ => panic!("invalid message"),
+        }
+    }
+    received.sort();
+    assert_eq!(vec!["one", "two"], received);
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn untrack() {
+    let mut router = Router::<Address>::new();
+    let mut agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let agent_b = router.new_agent::<()>(None, Address::B).unwrap();
+    let agent_c = router.new_agent::<()>(None, Address::C).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_a.track(Address::B).await.unwrap();
+    agent_a.track(Address::C).await.unwrap();
+    agent_a.untrack(Address::B).await.unwrap();
+
+    // B is removed first, but only C is still tracked
+    drop(agent_b);
+    drop(agent_c);
+    let msg = agent_a.recv().await.unwrap();
+    assert!(matches!(msg, Message::AgentRemoved(Address::C, RemovalReason::Dropped)));
+    assert!(agent_a.try_recv().unwrap().is_none());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn agent_stream() {
+    let mut router = Router::<Address>::new();
+    let mut agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_a.track(Address::B).await.unwrap();
+    for i in 0..3u32 {
+        agent_a.send(Address::B, i).await.unwrap();
+    }
+
+    let mut stream = agent_b.into_stream();
+    for expected in 0..3u32 {
+        match stream.next().await {
+            Some(Ok(Message::Value(value, Address::A))) => assert_eq!(expected, value),
+            _ => panic!("expected a value"),
+        }
+    }
+
+    // Dropping the stream unregisters the agent
+    drop(stream);
+    let msg = agent_a.recv().await.unwrap();
+    assert!(matches!(msg, Message::AgentRemoved(Address::B, RemovalReason::Dropped)));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn recv_as_type() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    for i in 0..3u32 {
+        agent_a.send(Address::B, i.to_string()).await.unwrap();
+        agent_a.send(Address::B, i).await.unwrap();
+    }
+
+    for expected in 0..2u32 {
+        let (value, sender) = agent_b.recv_as_type::<u32>(Unmatched::Keep).await.unwrap();
+        assert_eq!(expected, value);
+        assert_eq!(Address::A, sender);
+    }
+
+    // The strings skipped so far are kept, in order
+    for expected in ["0", "1"] {
+        match agent_b.recv().await.unwrap() {
+            Message::Value(value, _) => assert_eq!(expected, value),
+            _ => panic!("expected a value"),
+        }
+    }
+
+    let (value, _) = agent_b.recv_as_type::<u32>(Unmatched::Drop).await.unwrap();
+    assert_eq!(2, value);
+    assert!(agent_b.try_recv().unwrap().is_none());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn pending_and_capacity() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let agent_b = router.new_agent::<u32>(Some(16), Address::B).unwrap();
+    let router_tx = router.router_tx();
+    let handle = tokio::spawn(router.run());
+
+    assert_eq!(usize::MAX, agent_a.capacity());
+    assert_eq!(16, agent_b.capacity());
+
+    for i in 0..5u32 {
+        agent_a.send(Address::B, i).await.unwrap();
+    }
+    // Wait for the router to route the messages
+    router_tx.queue_utilization().await.unwrap();
+    assert_eq!(5, agent_b.pending());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn removal_reasons() {
+    let mut router = Router::<Address>::new();
+    let mut agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let agent_b = router.new_agent::<()>(None, Address::B).unwrap();
+    let agent_c = router.new_agent::<()>(None, Address::C).unwrap();
+    let agent_d = router.new_agent::<()>(None, Address::D).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    for address in [Address::B, Address::C, Address::D] {
+        agent_a.track(address).await.unwrap();
+    }
+
+    agent_a.send_shutdown(Address::B).await.unwrap();
+    let msg = agent_a.recv().await.unwrap();
+    assert!(matches!(msg, Message::AgentRemoved(Address::B, RemovalReason::Shutdown)));
+    drop(agent_b);
+
+    drop(agent_c);
+    let msg = agent_a.recv().await.unwrap();
+    assert!(matches!(msg, Message::AgentRemoved(Address::C, RemovalReason::Dropped)));
+
+    agent_d.close().await.unwrap();
+    let msg = agent_a.recv().await.unwrap();
+    assert!(matches!(msg, Message::AgentRemoved(Address::D, RemovalReason::Unregistered)));
+    assert_eq!("AgentRemoved<D> (unregistered)", format!("{}", Message::<String, _>::AgentRemoved(Address::D, RemovalReason::Unregistered)));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn fifo_preserves_order() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(Some(1), Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    for i in 0..20u32 {
+        agent_a.send(Address::B, i).await.unwrap();
+    }
+
+    for expected in 0..20u32 {
+        match agent_b.recv().await.unwrap() {
+            Message::Value(value, _) => assert_eq!(expected, value),
+            _ => panic!("expected a value"),
+        }
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn relaxed_order() {
+    let mut router = Router::<Address>::new();
+    router.ordering(tinyroute::DeliveryOrder::Relaxed);
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(Some(1), Address::B).unwrap();
+    let mut agent_c = router.new_agent::<u32>(None, Address::C).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    // B is full after the first message, which doesn't hold up the message to C
+    for i in 0..20u32 {
+        agent_a.send(Address::B, i).await.unwrap();
+    }
+    agent_a.send(Address::C, 100u32).await.unwrap();
+    let msg = tokio::time::timeout(Duration::from_secs(1), agent_c.recv()).await.unwrap().unwrap();
+    assert!(matches!(msg, Message::Value(100, Address::A)));
+
+    // Every message to B arrives, in any order
+    let mut received = Vec::new();
+    for _ in 0..20 {
+        match agent_b.recv().await.unwrap() {
+            Message::Value(value, _) => received.push(value),
+            _ => panic!("expected a value"),
+        }
+    }
+    received.sort_unstable();
+    assert_eq!((0..20u32).collect::<Vec<_>>(), received);
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn send_after() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let router_tx = router.router_tx();
+    let handle = tokio::spawn(router.run());
+
+    let delay = Duration::from_millis(200);
+    let start = std::time::Instant::now();
+    agent_a.send_after(Address::B, "later".to_string(), delay).unwrap();
+
+    router_tx.queue_utilization().await.unwrap();
+    assert!(agent_b.try_recv().unwrap().is_none());
+
+    let msg = agent_b.recv_timeout(Duration::from_secs(1)).await.unwrap();
+    assert!(matches!(msg, Message::Value(value, Address::A) if value == "later"));
+    assert!(start.elapsed() >= delay);
+
+    // A delayed send outliving the router is dropped
+    agent_a.send_after(Address::B, "too late".to_string(), delay).unwrap();
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+    tokio::time::sleep(delay * 2).await;
+}
+
+#[tokio::test]
+async fn recv_or_shutdown() {
+    let (agent_a, mut agent_b, handle) = setup();
+
+    // A shutdown message
+    agent_a.send_shutdown(Address::B).await.unwrap();
+    let msg = agent_b.recv_or_shutdown().await.unwrap();
+    assert!(matches!(msg, Message::Shutdown));
+
+    // The channel is closed once the agent is unregistered
+    let msg = agent_b.recv_or_shutdown().await.unwrap();
+    assert!(matches!(msg, Message::Shutdown));
+    assert!(matches!(agent_b.recv().await, Err(Error::ChannelClosed)));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn watchdog() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let stalls = Arc::new(Mutex::new(Vec::new()));
+    let on_stall = stalls.clone();
+    let mut agent_b = agent_b.with_watchdog(Duration::from_millis(50), move |address: &Address| {
+        on_stall.lock().unwrap().push(address.clone());
+    });
+
+    agent_a.send(Address::B, 1u32).await.unwrap();
+    agent_a.send(Address::B, 2u32).await.unwrap();
+
+    // A quick handler
+    agent_b.recv().await.unwrap();
+
+    // A slow handler
+    agent_b.recv().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(vec![Address::B], *stalls.lock().unwrap());
+
+    // Waiting for a message isn't a stall
+    let _ = agent_b.recv_timeout(Duration::from_millis(200)).await;
+    assert_eq!(1, stalls.lock().unwrap().len());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn watchdog_recv_as_type() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let stalls = Arc::new(AtomicUsize::new(0));
+    let on_stall = stalls.clone();
+    let mut agent_b = agent_b.with_watchdog(Duration::from_millis(50), move |_: &Address| {
+        on_stall.fetch_add(1, Ordering::SeqCst);
+    });
+
+    agent_a.send(Address::B, 1u32).await.unwrap();
+    agent_b.recv().await.unwrap();
+
+    // Waiting for a value of another type isn't a stall
+    let sender = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        agent_a.send(Address::B, "late".to_string()).await.unwrap();
+        agent_a
+    });
+    let (value, _) = agent_b.recv_as_type::<String>(Unmatched::Drop).await.unwrap();
+    assert_eq!("late", value);
+    assert_eq!(0, stalls.load(Ordering::SeqCst));
+
+    let agent_a = sender.await.unwrap();
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn watchdog_request() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let stalls = Arc::new(AtomicUsize::new(0));
+    let on_stall = stalls.clone();
+    let mut agent_a = agent_a.with_watchdog(Duration::from_millis(50), move |_: &Address| {
+        on_stall.fetch_add(1, Ordering::SeqCst);
+    });
+
+    // B takes its time to reply
+    let slow = tokio::spawn(async move {
+        if let Message::Value(value, sender) = agent_b.recv().await.unwrap() {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            agent_b.send(sender, value + 1).await.unwrap();
+        }
+        agent_b
+    });
+
+    // Waiting for the reply isn't a stall
+    let reply: u32 = agent_a.request(Address::B, 1u32).await.unwrap();
+    assert_eq!(2, reply);
+    assert_eq!(0, stalls.load(Ordering::SeqCst));
+
+    // but handling the reply is
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(1, stalls.load(Ordering::SeqCst));
+
+    let _agent_b = slow.await.unwrap();
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[derive(Debug)]
+struct Counted(Arc<AtomicUsize>);
+
+impl Clone for Counted {
+    fn clone(&self) -> Self {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Self(self.0.clone())
+    }
+}
+
+#[tokio::test]
+async fn publish() {
+    let mut router = Router::<Address>::new();
+    let publisher = router.new_agent::<()>(None, Address::A).unwrap();
+    let mut sub_b = router.new_agent::<Counted>(None, Address::B).unwrap();
+    let mut sub_c = router.new_agent::<Counted>(None, Address::C).unwrap();
+    let sub_d = router.new_agent::<Counted>(None, Address::D).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    sub_b.subscribe("news").await.unwrap();
+    sub_c.subscribe("news").await.unwrap();
+    sub_d.subscribe("news").await.unwrap();
+    sub_d.unsubscribe("news").await.unwrap();
+
+    let clones = Arc::new(AtomicUsize::new(0));
+    let delivered = publisher.publish("news", Counted(clones.clone())).await.unwrap();
+    assert_eq!(2, delivered);
+    assert_eq!(2, clones.load(Ordering::SeqCst));
+
+    for subscriber in [&mut sub_b, &mut sub_c] {
+        let msg = subscriber.recv().await.unwrap();
+        assert!(matches!(msg, Message::Value(Counted(_), Address::A)));
+    }
+
+    // Removed agents are no longer subscribed
+    drop(sub_c);
+    publisher.router_tx().queue_utilization().await.unwrap();
+    assert_eq!(1, publisher.publish("news", Counted(clones.clone())).await.unwrap());
+    assert_eq!(0, publisher.publish("weather", Counted(clones)).await.unwrap());
+
+    publisher.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn too_many_timers() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap().with_max_timers(2);
+    let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_a.send_after(Address::B, 1u32, Duration::from_millis(20)).unwrap();
+    agent_a.send_after(Address::B, 2u32, Duration::from_millis(20)).unwrap();
+    let res = agent_a.send_after(Address::B, 3u32, Duration::from_millis(20));
+    assert!(matches!(res, Err(Error::TooManyTimers)));
+
+    // Sent messages no longer count
+    for expected in [1u32, 2] {
+        let msg = agent_b.recv().await.unwrap();
+        assert!(matches!(msg, Message::Value(v, Address::A) if v == expected));
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    agent_a.send_after(Address::B, 3u32, Duration::ZERO).unwrap();
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(3, Address::A)));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn drop_cancels_timers() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_a.send_after(Address::B, 1u32, Duration::from_millis(50)).unwrap();
+    drop(agent_a);
+
+    let res = agent_b.recv_timeout(Duration::from_millis(150)).await;
+    assert!(matches!(res, Err(Error::Timeout)));
+
+    agent_b.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn loop_lag() {
+    const SLOW: u32 = 1;
+    let mut router = Router::<Address>::new();
+    router.set_classifier(|_sender, opcode| {
+        if opcode == SLOW {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Some(Address::B)
+    });
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    let router_tx = router.router_tx();
+    let handle = tokio::spawn(router.run());
+
+    agent_a.send_classified(0, 1u32).await.unwrap();
+    agent_b.recv().await.unwrap();
+    assert!(router_tx.loop_lag() < Duration::from_millis(50));
+
+    agent_a.send_classified(SLOW, 2u32).await.unwrap();
+    agent_b.recv().await.unwrap();
+    assert!(router_tx.loop_lag() >= Duration::from_millis(50));
+
+    // Reading the lag resets it
+    assert!(router_tx.loop_lag() < Duration::from_millis(50));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn publish_sampled() {
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Id(usize);
+    impl ToAddress for Id {}
+
+    let mut router = Router::<Id>::new();
+    let publisher = router.new_agent::<()>(None, Id(0)).unwrap();
+    let mut subscribers = (1..=10).map(|id| router.new_agent::<u32>(None, Id(id)).unwrap()).collect::<Vec<_>>();
+    let handle = tokio::spawn(router.run());
+    for subscriber in &subscribers {
+        subscriber.subscribe("metrics").await.unwrap();
+    }
+
+    let trials = 200;
+    for _ in 0..trials {
+        assert_eq!(5, publisher.publish_sampled("metrics", 1u32, 0.5).await.unwrap());
+    }
+    assert_eq!(0, publisher.publish_sampled("metrics", 1u32, 0.0).await.unwrap());
+    assert_eq!(10, publisher.publish_sampled("metrics", 1u32, 2.0).await.unwrap());
+
+    // Every subscriber is picked about half the time
+    for subscriber in &mut subscribers {
+        let mut received = 0;
+        while let Ok(Message::Value(_, Id(0))) = subscriber.recv_timeout(Duration::from_millis(10)).await {
+            received += 1;
+        }
+        assert!((60..=140).contains(&(received - 1)), "received {} out of {}", received - 1, trials);
+    }
+
+    publisher.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn send_with_on_fail() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let failures = Arc::new(Mutex::new(Vec::new()));
+    for recipient in [Address::B, Address::D] {
+        let failures = failures.clone();
+        let failed = recipient.clone();
+        let on_fail = move |reason| failures.lock().unwrap().push((failed, reason));
+        agent_a.send_with_on_fail(recipient, 1u32, on_fail).await.unwrap();
+    }
+
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(1, Address::A)));
+    agent_a.router_tx().queue_utilization().await.unwrap();
+    assert_eq!(vec![(Address::D, DropReason::NoRecipient)], *failures.lock().unwrap());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn inbound_transform() {
+    let mut router = Router::<Address>::new();
+    let agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    let agent_c = router.new_agent::<()>(None, Address::C).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let mut agent_b = agent_b.with_inbound_transform(|msg| match msg {
+        Message::Value(_, Address::C) => None,
+        Message::Value(value, sender) => Some(Message::Value(value * 10, sender)),
+        _ => None,
+    });
+
+    agent_c.send(Address::B, 1u32).await.unwrap();
+    agent_a.send(Address::B, 2u32).await.unwrap();
+    agent_c.send(Address::B, 3u32).await.unwrap();
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Value(20, Address::A)));
+
+    // The same goes for the values taken by `recv_as_type`, and replies to `request`
+    agent_c.send(Address::B, 4u32).await.unwrap();
+    agent_a.send(Address::B, 5u32).await.unwrap();
+    let (value, sender) = agent_b.recv_as_type::<u32>(Unmatched::Keep).await.unwrap();
+    assert_eq!((50, Address::A), (value, sender));
+
+    let replier = tokio::spawn(async move {
+        let mut agent_a = agent_a;
+        agent_a.recv().await.unwrap();
+        agent_a.send(Address::B, 6u32).await.unwrap();
+        agent_a
+    });
+    let reply: u32 = agent_b.request(Address::A, ()).await.unwrap();
+    assert_eq!(60, reply);
+    let agent_a = replier.await.unwrap();
+
+    // Shutdown is never dropped
+    agent_a.send_shutdown(Address::B).await.unwrap();
+    assert!(matches!(agent_b.recv().await.unwrap(), Message::Shutdown));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
@@ -0,0 +1,419 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::time::{sleep, timeout, Instant};
+use tinyroute::bridge::{
+    Bridge, BridgeControl, BridgeError, BridgeEvent, BridgeMessageOut, CircuitBreaker, CircuitState, Reconnect,
+    Retry,
+};
+use tinyroute::client::DisconnectReason;
+use tinyroute::errors::Error;
+use tinyroute::frame::Frame;
+use tinyroute::{Agent, Bytes, Message, Router, ToAddress};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Bridge,
+    Client,
+    Sink,
+}
+
+impl ToAddress for Address {}
+
+fn setup() -> (Agent<BridgeMessageOut, Address>, Router<Address>) {
+    let mut router = Router::new();
+    let bridge_agent = router.new_agent(None, Address::Bridge).unwrap();
+    (bridge_agent, router)
+}
+
+// Run the bridge until it's idle, waiting for a message
+async fn exec_until_idle(bridge: &mut Bridge<'_, Address>) {
+    let _ = timeout(Duration::from_millis(50), bridge.exec()).await;
+}
+
+#[tokio::test]
+async fn connection_state() {
+    let (bridge_agent, _router) = setup();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Forever, None);
+    assert!(!bridge.is_connected());
+
+    exec_until_idle(&mut bridge).await;
+    assert!(bridge.is_connected());
+
+    // Close the connection from the server side
+    let (socket, _) = listener.accept().await.unwrap();
+    drop(socket);
+    for _ in 0..100 {
+        if !bridge.is_connected() {
+            break;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert!(!bridge.is_connected());
+
+    // Reconnect
+    exec_until_idle(&mut bridge).await;
+    assert!(bridge.is_connected());
+}
+
+#[tokio::test]
+async fn circuit_breaker() {
+    let (bridge_agent, _router) = setup();
+
+    // Get a free port with nothing listening on it
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+
+    let breaker = CircuitBreaker {
+        failure_threshold: 2,
+        cooldown: Duration::from_millis(100),
+        half_open_probes: 1,
+    };
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let on_event = events.clone();
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Forever, None)
+        .with_circuit_breaker(breaker)
+        .on_event(move |event| {
+            if let BridgeEvent::Circuit(state) = event {
+                on_event.lock().unwrap().push(state);
+            }
+        });
+    assert_eq!(Some(CircuitState::Closed), bridge.circuit_state());
+
+    // Two failed attempts opens the circuit
+    let res = bridge.exec().await;
+    assert!(matches!(res, Err(Error::Bridge(BridgeError::CircuitOpen))));
+    assert_eq!(Some(CircuitState::Open), bridge.circuit_state());
+
+    // Fail fast while the circuit is open
+    let res = timeout(Duration::from_millis(5), bridge.exec()).await.unwrap();
+    assert!(matches!(res, Err(Error::Bridge(BridgeError::CircuitOpen))));
+
+    // Half open after the cooldown
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(Some(CircuitState::HalfOpen), bridge.circuit_state());
+
+    // The server is back and the probe closes the circuit
+    let _listener = TcpListener::bind(&addr).await.unwrap();
+    exec_until_idle(&mut bridge).await;
+    assert_eq!(Some(CircuitState::Closed), bridge.circuit_state());
+    assert!(bridge.is_connected());
+
+    // Every change of state is sent as an event
+    let expected = vec![CircuitState::Open, CircuitState::HalfOpen, CircuitState::Closed];
+    assert_eq!(expected, *events.lock().unwrap());
+}
+
+#[tokio::test]
+async fn connected_before_data() {
+    let mut router = Router::new();
+    let bridge_agent = router.new_agent(None, Address::Bridge).unwrap();
+    let client = router.new_agent::<()>(None, Address::Client).unwrap();
+    tokio::spawn(router.run());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Forever, None);
+
+    let msg = bridge.exec().await.unwrap();
+    assert!(matches!(msg, Some(Message::Connected(Address::Bridge))));
+
+    // Close the connection from the server side
+    let (socket, _) = listener.accept().await.unwrap();
+    drop(socket);
+    while bridge.is_connected() {
+        sleep(Duration::from_millis(10)).await;
+    }
+    sleep(Duration::from_millis(10)).await;
+
+    // Queue data while disconnected
+    let payload = Bytes::from_static(b"hello");
+    let out = BridgeMessageOut::new(b"client".to_vec(), Bytes::from_static(b"remote"), payload);
+    client.send(Address::Bridge, out.unwrap()).await.unwrap();
+
+    // Connected comes first, with nothing written to the new connection
+    let msg = bridge.exec().await.unwrap();
+    assert!(matches!(msg, Some(Message::Connected(Address::Bridge))));
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut buf = [0u8; 64];
+    let read = timeout(Duration::from_millis(50), socket.read(&mut buf)).await;
+    assert!(read.is_err(), "data was written before Connected");
+
+    // followed by the data
+    assert!(bridge.exec().await.unwrap().is_none());
+    let expected = Frame::frame_message(b"remote|client|hello").into_bytes();
+    let mut written = vec![0u8; expected.len()];
+    socket.read_exact(&mut written).await.unwrap();
+    assert_eq!(expected.as_ref(), written.as_slice());
+}
+
+#[tokio::test]
+async fn binary_payload() {
+    let mut router = Router::new();
+    let bridge_agent = router.new_agent(None, Address::Bridge).unwrap();
+    let client = router.new_agent::<()>(None, Address::Client).unwrap();
+    tokio::spawn(router.run());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Forever, None);
+
+    let msg = bridge.exec().await.unwrap();
+    assert!(matches!(msg, Some(Message::Connected(Address::Bridge))));
+    let (mut socket, _) = listener.accept().await.unwrap();
+
+    // Not valid UTF-8
+    let payload = Bytes::from_static(&[0xff, 0xfe, 0x00, 0xc3]);
+    let out = BridgeMessageOut::new(b"client".to_vec(), Bytes::from_static(b"remote"), payload);
+    client.send(Address::Bridge, out.unwrap()).await.unwrap();
+    assert!(bridge.exec().await.unwrap().is_none());
+
+    let expected = Frame::frame_message(b"remote|client|\xff\xfe\x00\xc3").into_bytes();
+    let mut written = vec![0u8; expected.len()];
+    socket.read_exact(&mut written).await.unwrap();
+    assert_eq!(expected.as_ref(), written.as_slice());
+}
+
+#[tokio::test]
+async fn deliver_to_sink() {
+    let mut router = Router::new();
+    let bridge_agent = router.new_agent(None, Address::Bridge).unwrap();
+    let mut sink = router.new_agent::<()>(None, Address::Sink).unwrap();
+    tokio::spawn(router.run());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Forever, None).with_sink(Address::Sink);
+
+    let msg = bridge.exec().await.unwrap();
+    assert!(matches!(msg, Some(Message::Connected(Address::Bridge))));
+    let (mut socket, _) = listener.accept().await.unwrap();
+    socket.write_all(&Frame::frame_message(b"remote|hello").into_bytes()).await.unwrap();
+
+    assert!(bridge.exec().await.unwrap().is_none());
+    match sink.recv().await.unwrap() {
+        Message::RemoteMessage { bytes, sender, host } => {
+            assert_eq!(b"remote|hello", bytes.as_ref());
+            assert_eq!(Address::Bridge, sender);
+            assert_eq!(addr, host.to_string());
+        }
+        _ => panic!("expected a remote message"),
+    }
+}
+
+#[tokio::test]
+async fn connection_reset() {
+    let (bridge_agent, _router) = setup();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Count(2), None);
+
+    // A server that resets every connection as soon as it's accepted
+    for _ in 0..2 {
+        let msg = timeout(Duration::from_secs(1), bridge.exec()).await.unwrap().unwrap();
+        assert!(matches!(msg, Some(Message::Connected(Address::Bridge))));
+        let (socket, _) = listener.accept().await.unwrap();
+        socket.set_zero_linger().unwrap();
+        drop(socket);
+    }
+
+    // Once the server is gone the bridge gives up, as per the retry policy
+    drop(listener);
+    let res = timeout(Duration::from_secs(1), bridge.exec()).await.unwrap();
+    assert!(matches!(res, Err(Error::Bridge(BridgeError::Exhausted { attempts: 3, .. }))));
+    assert!(!bridge.is_connected());
+}
+
+#[tokio::test]
+async fn connect_timeout() {
+    let (bridge_agent, _router) = setup();
+
+    // A listener that never accepts, with a full backlog,
+    // so connection attempts hang rather than fail
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = socket.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut backlog = Vec::new();
+    while let Ok(Ok(stream)) = timeout(Duration::from_millis(50), TcpStream::connect(addr)).await {
+        backlog.push(stream);
+    }
+
+    let addr = addr.to_string();
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Count(2), None)
+        .with_connect_timeout(Duration::from_millis(50));
+
+    // Three attempts, each giving up after the timeout
+    let start = Instant::now();
+    let res = timeout(Duration::from_secs(1), bridge.exec()).await.unwrap();
+    assert!(matches!(res, Err(Error::Bridge(BridgeError::Exhausted { attempts: 3, .. }))));
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert!(!bridge.is_connected());
+}
+
+#[tokio::test]
+async fn retries_exhausted() {
+    let (bridge_agent, _router) = setup();
+
+    // Nothing listening on the address
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Count(1), None);
+    match bridge.exec().await {
+        Err(Error::Bridge(BridgeError::Exhausted { addr: exhausted_addr, attempts })) => {
+            assert_eq!(addr, exhausted_addr);
+            assert_eq!(2, attempts);
+        }
+        res => panic!("expected the retries to be exhausted, got {:?}", res.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn events() {
+    let (bridge_agent, _router) = setup();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let on_event = events.clone();
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Count(1), None)
+        .on_event(move |event| on_event.lock().unwrap().push(event));
+
+    // The server drops the connection once
+    bridge.exec().await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    drop(socket);
+    bridge.exec().await.unwrap();
+    assert_eq!(vec![BridgeEvent::Connected, BridgeEvent::Disconnected(DisconnectReason::Eof), BridgeEvent::Connected], *events.lock().unwrap());
+
+    // and then goes away
+    events.lock().unwrap().clear();
+    let (socket, _) = listener.accept().await.unwrap();
+    drop(listener);
+    drop(socket);
+    assert!(bridge.exec().await.is_err());
+    let expected = vec![BridgeEvent::Disconnected(DisconnectReason::Eof), BridgeEvent::Reconnecting { attempt: 1 }, BridgeEvent::Failed];
+    assert_eq!(expected, *events.lock().unwrap());
+}
+
+#[tokio::test]
+async fn disconnect_reason() {
+    let (bridge_agent, _router) = setup();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let on_event = events.clone();
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Forever, None)
+        .on_event(move |event| on_event.lock().unwrap().push(event));
+
+    bridge.exec().await.unwrap();
+    assert_eq!(None, bridge.last_disconnect_reason());
+
+    // Not a valid frame header
+    let (mut socket, _) = listener.accept().await.unwrap();
+    socket.write_all(&[0xff]).await.unwrap();
+    while bridge.is_connected() {
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(Some(DisconnectReason::Decode), bridge.last_disconnect_reason());
+
+    // Still reported once reconnected
+    bridge.exec().await.unwrap();
+    assert!(bridge.is_connected());
+    assert_eq!(Some(DisconnectReason::Decode), bridge.last_disconnect_reason());
+    assert_eq!(BridgeEvent::Disconnected(DisconnectReason::Decode), events.lock().unwrap()[1]);
+
+    bridge.control().send(BridgeControl::ForceReconnect).unwrap();
+    bridge.exec().await.unwrap();
+    assert_eq!(Some(DisconnectReason::Closed), bridge.last_disconnect_reason());
+}
+
+#[tokio::test]
+async fn force_reconnect() {
+    let (bridge_agent, _router) = setup();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let reconnect = Reconnect::Exponential { seconds: 1, max: None };
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Forever, None);
+    let control = bridge.control();
+
+    let msg = bridge.exec().await.unwrap();
+    assert!(matches!(msg, Some(Message::Connected(Address::Bridge))));
+    let (mut first, _) = listener.accept().await.unwrap();
+
+    control.send(BridgeControl::ForceReconnect).unwrap();
+    assert!(bridge.exec().await.unwrap().is_none());
+
+    // The old connection is closed...
+    let mut buf = [0u8; 8];
+    let read = timeout(Duration::from_secs(1), first.read(&mut buf)).await.unwrap();
+    assert_eq!(0, read.unwrap());
+
+    // ...and a new one established
+    let msg = bridge.exec().await.unwrap();
+    assert!(matches!(msg, Some(Message::Connected(Address::Bridge))));
+    let _second = timeout(Duration::from_secs(1), listener.accept()).await.unwrap().unwrap();
+    assert!(bridge.is_connected());
+}
+
+#[tokio::test]
+async fn pause_drops_past_max_pending() {
+    let mut router = Router::new();
+    let bridge_agent = router.new_agent(None, Address::Bridge).unwrap();
+    let client = router.new_agent::<()>(None, Address::Client).unwrap();
+    tokio::spawn(router.run());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let reconnect = Reconnect::Constant(Duration::from_millis(10));
+    let mut bridge = Bridge::new(bridge_agent, &addr, reconnect, Retry::Forever, None).with_max_pending(2);
+    let control = bridge.control();
+
+    let msg = bridge.exec().await.unwrap();
+    assert!(matches!(msg, Some(Message::Connected(Address::Bridge))));
+    let (mut socket, _) = listener.accept().await.unwrap();
+
+    control.send(BridgeControl::Pause).unwrap();
+    assert!(bridge.exec().await.unwrap().is_none());
+
+    for payload in [&b"a"[..], b"b", b"c"] {
+        let out = BridgeMessageOut::new(b"client".to_vec(), Bytes::from_static(b"remote"), Bytes::copy_from_slice(payload));
+        client.send(Address::Bridge, out.unwrap()).await.unwrap();
+        assert!(bridge.exec().await.unwrap().is_none());
+    }
+
+    // The oldest is dropped
+    assert_eq!(1, bridge.dropped());
+    let mut buf = [0u8; 64];
+    let read = timeout(Duration::from_millis(50), socket.read(&mut buf)).await;
+    assert!(read.is_err(), "data was written while paused");
+
+    // and the rest are forwarded once resumed
+    control.send(BridgeControl::Resume).unwrap();
+    for _ in 0..3 {
+        assert!(bridge.exec().await.unwrap().is_none());
+    }
+    for expected in [&b"remote|client|b"[..], b"remote|client|c"] {
+        let expected = Frame::frame_message(expected).into_bytes();
+        let mut written = vec![0u8; expected.len()];
+        socket.read_exact(&mut written).await.unwrap();
+        assert_eq!(expected.as_ref(), written.as_slice());
+    }
+}
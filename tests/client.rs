@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tinyroute::client::{connect, connect_with_transform, ClientMessage, Heartbeat, SharedClient, TcpClient};
+use tinyroute::errors::Error;
+use tinyroute::frame::{Direction, Frame, FrameTransform, FramedMessage, Header};
+use tinyroute::Bytes;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+
+// Echo everything back, stream ids included
+async fn echo_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+    addr
+}
+
+#[tokio::test]
+async fn shared_client_streams() {
+    let addr = echo_server().await;
+    let client = TcpClient::connect(addr).await.unwrap();
+    let shared_client = SharedClient::new(client, None).unwrap();
+
+    let streams = (0..3).map(|_| shared_client.stream()).collect::<Vec<_>>();
+    let ids = streams.iter().map(|s| s.id()).collect::<Vec<_>>();
+    assert_eq!(vec![0, 1, 2], ids);
+
+    for stream in streams.iter().rev() {
+        stream.send(format!("stream {}", stream.id()).as_bytes()).unwrap();
+    }
+
+    for stream in &streams {
+        let reply = stream.recv_async().await.unwrap();
+        assert_eq!(format!("stream {}", stream.id()).into_bytes(), reply);
+    }
+}
+
+#[tokio::test]
+async fn framed_message_is_not_framed_again() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut written = Vec::new();
+        socket.read_to_end(&mut written).await.unwrap();
+        written
+    });
+
+    let frame = Frame::frame_message(b"con|hello world").into_bytes();
+    let framed_message = FramedMessage::from_framed(frame.clone()).unwrap();
+
+    let client = TcpClient::connect(addr).await.unwrap();
+    let (tx, _rx) = connect(client, None).unwrap();
+    tx.send_async(ClientMessage::from(framed_message)).await.unwrap();
+    tx.send_async(ClientMessage::Quit).await.unwrap();
+
+    let written = server.await.unwrap();
+    assert_eq!(frame.as_ref(), written.as_slice());
+}
+
+#[tokio::test]
+async fn transform_outbound_frames() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut written = Vec::new();
+        socket.read_to_end(&mut written).await.unwrap();
+        written
+    });
+
+    let transform: FrameTransform = Arc::new(|direction: Direction, bytes: Bytes| match direction {
+        Direction::Outbound => [b"env|".as_ref(), &bytes].concat().into(),
+        Direction::Inbound => bytes,
+    });
+
+    let client = TcpClient::connect(addr).await.unwrap();
+    let (tx, _rx) = connect_with_transform(client, None, transform).unwrap();
+    tx.send_async(ClientMessage::channel_payload(b"con", b"hello world")).await.unwrap();
+    tx.send_async(ClientMessage::Quit).await.unwrap();
+
+    let written = server.await.unwrap();
+    assert_eq!(Frame::frame_message(b"env|con|hello world").into_bytes().as_ref(), written.as_slice());
+}
+
+#[tokio::test]
+async fn heartbeat_payload() {
+    assert_eq!([Header::Heartbeat as u8].as_slice(), Heartbeat::default().payload.as_ref());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let client = TcpClient::connect(addr).await.unwrap();
+    let interval = Duration::from_millis(1100);
+    let heartbeat = Heartbeat::new(interval).with_payload(Bytes::from_static(b"PING"));
+    let (_tx, _rx) = connect(client, Some(heartbeat)).unwrap();
+    let (mut socket, _) = listener.accept().await.unwrap();
+
+    let start = tokio::time::Instant::now();
+    for _ in 0..3 {
+        let mut beat = [0u8; 4];
+        socket.read_exact(&mut beat).await.unwrap();
+        assert_eq!(b"PING", &beat);
+    }
+    // Every heartbeat is sent at most `interval` after the last one
+    assert!(start.elapsed() <= interval * 3);
+}
+
+#[tokio::test]
+async fn zero_heartbeat() {
+    let addr = echo_server().await;
+    let client = TcpClient::connect(addr).await.unwrap();
+    let res = connect(client, Some(Duration::ZERO.into()));
+    assert!(matches!(res, Err(Error::InvalidHeartbeat)));
+}
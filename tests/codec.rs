@@ -0,0 +1,100 @@
+use tinyroute::codec::{AddressCodec, ToAddressCodec, CODEC_VERSION, ESCAPE};
+use tinyroute::server::{handle_payload, ConnectionAddr};
+use tinyroute::{Message, Router, ToAddress, ADDRESS_SEP};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, ToAddressCodec)]
+enum Address {
+    Log,
+    Connection(u64),
+    Worker { pool: String, id: u16 },
+    Relay(Option<Host>, bool),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, ToAddressCodec)]
+struct Host {
+    name: String,
+    port: u16,
+}
+
+impl ToAddress for Address {
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Self::decode(bytes)
+    }
+}
+
+#[test]
+fn round_trip() {
+    let addresses = vec![
+        Address::Log,
+        Address::Connection(u64::MAX),
+        Address::Worker { pool: "io".to_string(), id: 7 },
+        Address::Relay(None, false),
+        Address::Relay(Some(Host { name: "example.com".to_string(), port: 5000 }), true),
+    ];
+
+    for address in addresses {
+        let bytes = address.encode();
+        assert_eq!(Some(address), Address::from_bytes(&bytes));
+    }
+
+    // Version, tag, then the fields
+    assert_eq!(vec![CODEC_VERSION, 0], Address::Log.encode());
+    assert_eq!(vec![CODEC_VERSION, 1, 0, 0, 0, 0, 0, 0, 0, 9], Address::Connection(9).encode());
+}
+
+#[test]
+fn invalid_bytes() {
+    // Unknown tag
+    assert_eq!(None, Address::from_bytes(&[CODEC_VERSION, 4]));
+    // Unknown version
+    assert_eq!(None, Address::from_bytes(&[CODEC_VERSION + 1, 0]));
+    // Too short, and too long
+    assert_eq!(None, Address::from_bytes(&[CODEC_VERSION, 1, 0, 0]));
+    assert_eq!(None, Address::from_bytes(&[CODEC_VERSION, 0, 0]));
+    assert_eq!(None, Address::from_bytes(&[]));
+}
+
+#[test]
+fn separator_is_escaped() {
+    // 124 is the `ADDRESS_SEP`, 125 the `ESCAPE`
+    let address = Address::Connection(124);
+    let bytes = address.encode();
+    assert!(!bytes.contains(&ADDRESS_SEP));
+    assert_eq!(vec![CODEC_VERSION, 1, 0, 0, 0, 0, 0, 0, 0, ESCAPE, 0x5C], bytes);
+    assert_eq!(Some(address), Address::from_bytes(&bytes));
+
+    // The string is 124 bytes long, so its length prefix holds a separator too
+    let address = Address::Worker { pool: "a|b}".repeat(31), id: 0x7D7C };
+    let bytes = address.encode();
+    assert!(!bytes.contains(&ADDRESS_SEP));
+    assert_eq!(Some(address), Address::from_bytes(&bytes));
+
+    // An unescaped separator, or an escape of anything else
+    assert_eq!(None, Address::from_bytes(&[CODEC_VERSION, 1, 0, 0, 0, 0, 0, 0, 0, ADDRESS_SEP]));
+    assert_eq!(None, Address::from_bytes(&[CODEC_VERSION, 1, 0, 0, 0, 0, 0, 0, 0, ESCAPE, 0]));
+    assert_eq!(None, Address::from_bytes(&[CODEC_VERSION, 0, ESCAPE]));
+}
+
+#[tokio::test]
+async fn separator_round_trip_through_payload() {
+    let mut router = Router::<Address>::new();
+    let mut agent = router.new_agent::<()>(None, Address::Connection(124)).unwrap();
+    let router_tx = router.router_tx();
+    let handle = tokio::spawn(router.run());
+
+    let mut bytes = Address::Connection(124).encode();
+    bytes.push(ADDRESS_SEP);
+    bytes.extend_from_slice(b"hello");
+    assert!(handle_payload(bytes, &router_tx, ConnectionAddr::Uds, Address::Log).await);
+
+    match agent.recv().await.unwrap() {
+        Message::RemoteMessage { bytes, sender, .. } => {
+            assert_eq!(&b"hello"[..], &bytes[..]);
+            assert_eq!(Address::Log, sender);
+        }
+        _ => panic!("expected a remote message"),
+    }
+
+    agent.shutdown_router().await;
+    handle.await.unwrap();
+}
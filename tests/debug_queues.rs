@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant};
+
+use tinyroute::{Router, ToAddress};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    A,
+    B,
+    Stuck,
+}
+
+impl ToAddress for Address {}
+
+#[tokio::test]
+async fn inspect_queued_messages() {
+    let mut router = Router::new();
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let mut stuck = router.new_agent::<String>(None, Address::Stuck).unwrap();
+    let router_tx = router.router_tx();
+    assert!(router.inspect_queue(&Address::Stuck).is_empty());
+    let handle = tokio::spawn(router.run());
+
+    let before = Instant::now();
+    agent_a.send(Address::Stuck, "first".to_string()).await.unwrap();
+    agent_b.send(Address::Stuck, "second".to_string()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let queued = router_tx.inspect_queue(&Address::Stuck);
+    let senders = queued.iter().map(|meta| meta.sender.clone()).collect::<Vec<_>>();
+    assert_eq!(vec![Some(Address::A), Some(Address::B)], senders);
+    assert!(queued[0].arrived_at >= before);
+    assert!(queued[0].arrived_at <= queued[1].arrived_at);
+
+    // Receiving a message removes it from the queue
+    stuck.recv().await.unwrap();
+    let queued = router_tx.inspect_queue(&Address::Stuck);
+    assert_eq!(1, queued.len());
+    assert_eq!(Some(Address::B), queued[0].sender);
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
@@ -0,0 +1,28 @@
+use tinyroute::ToAddress;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ToAddress)]
+enum Address {
+    Logger,
+    Bridge,
+    Worker,
+}
+
+#[test]
+fn round_trip() {
+    for (tag, address) in [Address::Logger, Address::Bridge, Address::Worker].into_iter().enumerate() {
+        let bytes = address.to_bytes();
+        assert_eq!(vec![tag as u8], bytes);
+        assert_eq!(Some(bytes.clone()), address.into());
+
+        let decoded = Address::from_bytes(&bytes).unwrap();
+        assert_eq!(address, decoded);
+        assert_eq!(format!("{:?}", address), decoded.to_string());
+    }
+}
+
+#[test]
+fn invalid_bytes() {
+    assert_eq!(None, Address::from_bytes(&[]));
+    assert_eq!(None, Address::from_bytes(&[3]));
+    assert_eq!(None, Address::from_bytes(&[0, 0]));
+}
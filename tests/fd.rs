@@ -0,0 +1,102 @@
+#![cfg(unix)]
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use tinyroute::client::UdsClient;
+use tinyroute::frame::Frame;
+use tinyroute::server::{Server, UdsConnections};
+use tinyroute::{Router, ToAddress};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    A,
+    Server,
+    Con,
+}
+
+impl ToAddress for Address {}
+
+#[tokio::test]
+async fn pass_pipe_to_client() {
+    let mut router = Router::new();
+    let agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let server_agent = router.new_agent(None, Address::Server).unwrap();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let path = "/tmp/tinyroute-fd-test.sock";
+    let _ = std::fs::remove_file(path);
+    let connections = UdsConnections::bind(path).await.unwrap();
+    let mut server = Server::new(connections, server_agent);
+
+    let client = tokio::spawn(async move {
+        let uds_client = UdsClient::connect(path).await.unwrap();
+        let (fd, payload) = uds_client.recv_fd().await.unwrap();
+        let mut pipe_reader = std::fs::File::from(fd);
+        let mut contents = String::new();
+        pipe_reader.read_to_string(&mut contents).unwrap();
+        (payload, contents)
+    });
+
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+
+    let (pipe_reader, mut pipe_writer) = std::io::pipe().unwrap();
+    connection.send_fd(pipe_reader.as_raw_fd(), b"a pipe").await.unwrap();
+    drop(pipe_reader);
+    pipe_writer.write_all(b"hello through the pipe").unwrap();
+    drop(pipe_writer);
+
+    let (payload, contents) = client.await.unwrap();
+    assert_eq!(b"a pipe", payload.as_slice());
+    assert_eq!("hello through the pipe", contents);
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+    let _ = std::fs::remove_file(path);
+}
+
+// Send all of `fds` along with `bytes`, in a single message
+fn send_fds(socket: RawFd, bytes: &[u8], fds: &[RawFd]) {
+    let mut cmsg_buf = [0u64; 8];
+    let mut iov = libc::iovec { iov_base: bytes.as_ptr() as *mut libc::c_void, iov_len: bytes.len() };
+    let fds_len = std::mem::size_of_val(fds) as u32;
+
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+
+        assert_eq!(bytes.len() as isize, libc::sendmsg(socket, &msg, 0));
+    }
+}
+
+#[tokio::test]
+async fn received_fds_are_cloexec_and_extra_fds_closed() {
+    let (sender, receiver) = tokio::net::UnixStream::pair().unwrap();
+    let (first_reader, _first_writer) = std::io::pipe().unwrap();
+    let (mut extra_reader, extra_writer) = std::io::pipe().unwrap();
+
+    let framed = Frame::frame_message(b"two fds");
+    send_fds(sender.as_raw_fd(), framed.as_bytes(), &[first_reader.as_raw_fd(), extra_writer.as_raw_fd()]);
+    drop(extra_writer);
+
+    let (fd, payload) = tinyroute::fd::recv_fd(&receiver).await.unwrap();
+    assert_eq!(b"two fds", payload.as_slice());
+
+    // The file descriptor isn't passed on to child processes
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+    assert_ne!(0, flags & libc::FD_CLOEXEC);
+
+    // The extra write end was closed, so the pipe is at its end straight away
+    // (were it still open, the non blocking read would fail with `WouldBlock`)
+    unsafe { libc::fcntl(extra_reader.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+    let mut buf = [0u8; 1];
+    assert_eq!(0, extra_reader.read(&mut buf).unwrap());
+}
@@ -0,0 +1,68 @@
+use tinyroute::sequence::{Reorder, Sequenced, Sequencer};
+use tinyroute::{Message, Router, ToAddress};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Origin,
+    Middle,
+    Sink,
+}
+
+impl ToAddress for Address {}
+
+#[tokio::test]
+async fn reordered_forwards() {
+    let mut router = Router::<Address>::new();
+    let origin = router.new_agent::<()>(None, Address::Origin).unwrap();
+    let mut middle = router.new_agent::<Sequenced<Address, u32>>(None, Address::Middle).unwrap();
+    let mut sink = router.new_agent::<Sequenced<Address, u32>>(None, Address::Sink).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    let mut sequencer = Sequencer::new(Address::Origin);
+    for value in 0..5u32 {
+        origin.send(Address::Middle, sequencer.tag(value)).await.unwrap();
+    }
+
+    // The middle agent forwards them out of order, as if handled concurrently
+    let mut received = Vec::new();
+    for _ in 0..5 {
+        match middle.recv().await.unwrap() {
+            Message::Value(sequenced, _) => received.push(sequenced),
+            _ => panic!("invalid message"),
+        }
+    }
+    for index in [3, 1, 4, 0, 2] {
+        let forward = received[index].clone().map(|value| value * 10);
+        middle.send(Address::Sink, forward).await.unwrap();
+    }
+
+    let mut reorder = Reorder::new();
+    let mut delivered = Vec::new();
+    while delivered.len() < 5 {
+        match sink.recv().await.unwrap() {
+            Message::Value(sequenced, Address::Middle) => delivered.extend(reorder.push(sequenced)),
+            _ => panic!("invalid message"),
+        }
+    }
+    assert_eq!(vec![0, 10, 20, 30, 40], delivered);
+
+    origin.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[test]
+fn max_buffered() {
+    let mut sequencer = Sequencer::new(Address::Origin);
+    let values = (0..4).map(|value| sequencer.tag(value)).collect::<Vec<_>>();
+    let mut reorder = Reorder::with_max_buffered(2);
+
+    // The first value never arrives
+    let mut values = values.into_iter();
+    let lost = values.next().unwrap();
+    assert!(reorder.push(values.next().unwrap()).is_empty());
+    assert!(reorder.push(values.next().unwrap()).is_empty());
+    assert_eq!(vec![1, 2, 3], reorder.push(values.next().unwrap()));
+
+    // Too late
+    assert!(reorder.push(lost).is_empty());
+}
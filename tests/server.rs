@@ -1,18 +1,27 @@
-use tinyroute::client::{connect, ClientMessage, UdsClient};
-use tinyroute::server::{Server, UdsConnections};
-use tinyroute::{Agent, Message, Router, ToAddress};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use tinyroute::client::{connect, connect_with_tap, ClientMessage, TcpClient, UdsClient};
+use tinyroute::frame::{Direction, Frame, FrameTap, Header};
+use tinyroute::server::{Connections, Listeners, RateLimit, Server, TcpConnections, UdsConnections, UdsDatagrams, UnixStream};
+use tinyroute::{Agent, Bytes, Message, Router, ToAddress};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Address {
     A,
     Server,
     Con,
+    Con2,
+    Con3,
+    Relay,
 }
 
 impl ToAddress for Address {
     fn from_bytes(bytes: &[u8]) -> Option<Self> {
         match bytes {
             b"con" => Some(Address::Con),
+            b"relay" => Some(Address::Relay),
             _ => None,
         }
     }
@@ -43,7 +52,7 @@ async fn remote_message() {
     // and send a remote message
     tokio::spawn(async move {
         let uds_client = UdsClient::connect(path).await.unwrap();
-        let (tx, _rx) = connect(uds_client, None);
+        let (tx, _rx) = connect(uds_client, None).unwrap();
         let message = ClientMessage::channel_payload(b"con", b"hello world");
         tx.send_async(message).await.unwrap();
     });
@@ -61,3 +70,577 @@ async fn remote_message() {
     handle.await.unwrap();
     let _ = std::fs::remove_file(path);
 }
+
+#[tokio::test]
+async fn from_std_listener() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    // A listener bound by someone else, e.g passed on by systemd
+    let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = std_listener.local_addr().unwrap();
+    let connections = TcpConnections::from_std(std_listener).unwrap();
+
+    let mut server = Server::new(connections, server_agent);
+
+    tokio::spawn(async move {
+        let tcp_client = TcpClient::connect(addr).await.unwrap();
+        let (tx, _rx) = connect(tcp_client, None).unwrap();
+        let message = ClientMessage::channel_payload(b"con", b"hello world");
+        tx.send_async(message).await.unwrap();
+    });
+
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+    let msg = connection.recv().await.unwrap().unwrap();
+
+    match msg {
+        Message::RemoteMessage { bytes, .. } => assert_eq!(b"hello world", bytes.as_ref()),
+        _ => panic!("invalid message")
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn from_std_uds_listener() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let path = "/tmp/tinyroute-from-std-test.sock";
+    let _ = std::fs::remove_file(path);
+    let std_listener = std::os::unix::net::UnixListener::bind(path).unwrap();
+    let connections = UdsConnections::from_std(std_listener).unwrap();
+
+    let mut server = Server::new(connections, server_agent);
+
+    tokio::spawn(async move {
+        let uds_client = UdsClient::connect(path).await.unwrap();
+        let (tx, _rx) = connect(uds_client, None).unwrap();
+        let message = ClientMessage::channel_payload(b"con", b"hello world");
+        tx.send_async(message).await.unwrap();
+    });
+
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+    let msg = connection.recv().await.unwrap().unwrap();
+
+    match msg {
+        Message::RemoteMessage { bytes, .. } => assert_eq!(b"hello world", bytes.as_ref()),
+        _ => panic!("invalid message")
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+    let _ = std::fs::remove_file(path);
+}
+
+// An implementation from before `accept` returned `impl Future`
+struct BoxedAccept(TcpConnections);
+
+impl Connections for BoxedAccept {
+    type Reader = <TcpConnections as Connections>::Reader;
+    type Writer = <TcpConnections as Connections>::Writer;
+
+    #[allow(deprecated, refining_impl_trait)]
+    fn accept(&mut self) -> tinyroute::server::ServerFuture<'_, Self::Reader, Self::Writer> {
+        Box::pin(self.0.accept())
+    }
+}
+
+#[tokio::test]
+async fn boxed_accept_still_works() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = std_listener.local_addr().unwrap();
+    let connections = BoxedAccept(TcpConnections::from_std(std_listener).unwrap());
+    let mut server = Server::new(connections, server_agent);
+
+    tokio::spawn(async move {
+        let tcp_client = TcpClient::connect(addr).await.unwrap();
+        let (tx, _rx) = connect(tcp_client, None).unwrap();
+        let message = ClientMessage::channel_payload(b"con", b"hello world");
+        tx.send_async(message).await.unwrap();
+    });
+
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+    let msg = connection.recv().await.unwrap().unwrap();
+    assert!(matches!(msg, Message::RemoteMessage { bytes, .. } if bytes.as_ref() == b"hello world"));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn heartbeats_are_not_messages() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = std_listener.local_addr().unwrap();
+    let connections = TcpConnections::from_std(std_listener).unwrap();
+    let mut server = Server::new(connections, server_agent);
+
+    // Heartbeats mixed in with messages, all in a single write
+    let heartbeat = Header::Heartbeat as u8;
+    let mut bytes = vec![heartbeat];
+    bytes.extend_from_slice(Frame::frame_message(b"con|one").into_bytes().as_ref());
+    bytes.extend_from_slice(&[heartbeat, heartbeat]);
+    bytes.extend_from_slice(Frame::frame_message(b"con|two").into_bytes().as_ref());
+    bytes.push(heartbeat);
+
+    let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+    socket.write_all(&bytes).await.unwrap();
+
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+    for expected in [b"one", b"two"] {
+        match connection.recv().await.unwrap().unwrap() {
+            Message::RemoteMessage { bytes, .. } => assert_eq!(expected, bytes.as_ref()),
+            _ => panic!("invalid message")
+        }
+    }
+
+    let res = tokio::time::timeout(std::time::Duration::from_millis(100), connection.recv()).await;
+    assert!(res.is_err());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn stop_one_listener() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let external = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let external_addr = external.local_addr().unwrap();
+    let admin = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let admin_addr = admin.local_addr().unwrap();
+
+    let listeners = Listeners::new()
+        .with_listener("external", TcpConnections::from_std(external).unwrap())
+        .with_listener("admin", TcpConnections::from_std(admin).unwrap());
+    let mut server = Server::new(listeners, server_agent);
+
+    let external_client = TcpClient::connect(external_addr).await.unwrap();
+    let (external_tx, _rx) = connect(external_client, None).unwrap();
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+
+    assert!(server.stop_listener("external").await);
+    assert!(!server.stop_listener("external").await);
+    assert!(tokio::net::TcpStream::connect(external_addr).await.is_err());
+
+    // The admin listener still accepts connections
+    let admin_client = TcpClient::connect(admin_addr).await.unwrap();
+    let (admin_tx, _rx) = connect(admin_client, None).unwrap();
+    let _admin_connection = server.next(Address::Con2, None, None).await.unwrap();
+    admin_tx.send_async(ClientMessage::channel_payload(b"con", b"from admin")).await.unwrap();
+
+    // ...and the connection accepted before the listener was stopped is still open
+    external_tx.send_async(ClientMessage::channel_payload(b"con", b"from external")).await.unwrap();
+
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        match connection.recv().await.unwrap().unwrap() {
+            Message::RemoteMessage { bytes, sender, .. } => received.push((sender, bytes)),
+            _ => panic!("invalid message")
+        }
+    }
+    received.sort_by_key(|(sender, _)| sender == &Address::Con);
+    assert_eq!(vec![(Address::Con2, "from admin".into()), (Address::Con, "from external".into())], received);
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn send_file() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = std_listener.local_addr().unwrap();
+    let connections = TcpConnections::from_std(std_listener).unwrap();
+    let mut server = Server::new(connections, server_agent);
+
+    let contents = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let path = std::env::temp_dir().join("tinyroute-send-file");
+    std::fs::write(&path, &contents).unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+
+    let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+    let offset = 10;
+    let len = contents.len() - 20;
+    let writer = tokio::spawn(async move {
+        connection.send_file(&file, offset as u64, len).await.unwrap();
+        connection
+    });
+
+    // Header and content length, followed by the file
+    let mut header = [0u8; 5];
+    socket.read_exact(&mut header).await.unwrap();
+    assert_eq!(Header::Large as u8, header[0]);
+    assert_eq!(len as u32, u32::from_be_bytes(header[1..].try_into().unwrap()));
+
+    let mut received = vec![0u8; len];
+    socket.read_exact(&mut received).await.unwrap();
+    assert_eq!(&contents[offset..offset + len], received.as_slice());
+
+    let _connection = writer.await.unwrap();
+    let _ = std::fs::remove_file(&path);
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn uds_rejects_disallowed_uid() {
+    let path = "/tmp/tinyroute-allowed-uids.sock";
+    let _ = std::fs::remove_file(path);
+    let uid = unsafe { libc::getuid() };
+
+    // Only allow a user other than the one running the test
+    let mut connections = UdsConnections::bind(path)
+        .await
+        .unwrap()
+        .with_allowed_uids(HashSet::from([uid.wrapping_add(1)]));
+    tokio::spawn(async move { connections.accept().await });
+
+    let mut client = UnixStream::connect(path).await.unwrap();
+    let mut buf = [0u8; 1];
+    let read = tokio::time::timeout(
+        std::time::Duration::from_secs(1),
+        tokio::io::AsyncReadExt::read(&mut client, &mut buf),
+    )
+    .await
+    .expect("the connection was not closed");
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    // The same user is let through
+    let mut connections = UdsConnections::bind(format!("{}2", path))
+        .await
+        .unwrap()
+        .with_allowed_uids(HashSet::from([uid]));
+    let accept = tokio::spawn(async move { connections.accept().await.map(|_| ()) });
+    let _client = UnixStream::connect(format!("{}2", path)).await.unwrap();
+    accept.await.unwrap().unwrap();
+
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(format!("{}2", path));
+}
+
+#[tokio::test]
+async fn uds_datagrams() {
+    let mut router = Router::new();
+    let agent_a = router.new_agent::<()>(None, Address::A).unwrap();
+    let server_agent = router.new_agent(None, Address::Server).unwrap();
+    let mut con = router.new_agent::<()>(None, Address::Con).unwrap();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let path = "/tmp/tinyroute-datagram-test.sock";
+    let _ = std::fs::remove_file(path);
+    let datagrams = UdsDatagrams::bind(path).unwrap().with_max_len(16);
+    let server = tokio::spawn(datagrams.run(server_agent, Address::A));
+
+    let client = tokio::net::UnixDatagram::unbound().unwrap();
+    client.send_to(b"con|hello", path).await.unwrap();
+    client.send_to(b"con|this one is too long", path).await.unwrap();
+    client.send_to(b"con|world", path).await.unwrap();
+
+    for expected in [b"hello", b"world"] {
+        match con.recv().await.unwrap() {
+            Message::RemoteMessage { bytes, sender, .. } => {
+                assert_eq!(expected, bytes.as_ref());
+                assert_eq!(Address::A, sender);
+            }
+            _ => panic!("invalid message"),
+        }
+    }
+
+    agent_a.shutdown_router().await;
+    server.await.unwrap();
+    handle.await.unwrap();
+    let _ = std::fs::remove_file(path);
+}
+
+type Tapped = Arc<Mutex<Vec<(Direction, Vec<u8>)>>>;
+
+fn recording_tap() -> (FrameTap, Tapped) {
+    let tapped = Tapped::default();
+    let frames = tapped.clone();
+    let tap: FrameTap = Arc::new(move |direction, bytes| {
+        frames.lock().unwrap().push((direction, bytes.to_vec()));
+    });
+    (tap, tapped)
+}
+
+#[tokio::test]
+async fn frame_tap() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let path = "/tmp/tinyroute-tap-test.sock";
+    let _ = std::fs::remove_file(path);
+    let connections = UdsConnections::bind(path).await.unwrap();
+    let (server_tap, server_tapped) = recording_tap();
+    let mut server = Server::new(connections, server_agent).with_frame_tap(server_tap);
+
+    let (client_tap, client_tapped) = recording_tap();
+    let client = tokio::spawn(async move {
+        let uds_client = UdsClient::connect(path).await.unwrap();
+        let (tx, rx) = connect_with_tap(uds_client, None, client_tap).unwrap();
+        tx.send_async(ClientMessage::channel_payload(b"con", b"hello")).await.unwrap();
+        rx.recv_async().await.unwrap()
+    });
+
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+    assert!(matches!(connection.recv().await.unwrap(), Some(Message::RemoteMessage { .. })));
+    agent_a.send(Address::Con, Frame::frame_message(b"reply")).await.unwrap();
+    assert!(connection.recv().await.unwrap().is_none());
+    assert_eq!(b"reply".to_vec(), client.await.unwrap());
+
+    let sent = Frame::frame_message(b"con|hello").into_bytes().to_vec();
+    let reply = Frame::frame_message(b"reply").into_bytes().to_vec();
+    let expected = vec![(Direction::Outbound, sent), (Direction::Inbound, b"reply".to_vec())];
+    assert_eq!(expected, *client_tapped.lock().unwrap());
+    let expected = vec![(Direction::Inbound, b"con|hello".to_vec()), (Direction::Outbound, reply)];
+    assert_eq!(expected, *server_tapped.lock().unwrap());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn forward_framed() {
+    let mut router = Router::new();
+    let mut relay = router.new_agent::<()>(None, Address::Relay).unwrap();
+    let server_agent = router.new_agent(None, Address::Server).unwrap();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let path = "/tmp/tinyroute-forward-test.sock";
+    let _ = std::fs::remove_file(path);
+    let connections = UdsConnections::bind(path).await.unwrap();
+
+    // Keep track of the memory of every outbound frame
+    let outbound = Arc::new(Mutex::new(Vec::new()));
+    let frames = outbound.clone();
+    let tap: FrameTap = Arc::new(move |direction, bytes| {
+        if direction == Direction::Outbound {
+            frames.lock().unwrap().push(bytes.as_ptr() as usize);
+        }
+    });
+    let mut server = Server::new(connections, server_agent).with_frame_tap(tap);
+
+    // A client sending to the relay
+    tokio::spawn(async move {
+        let uds_client = UdsClient::connect(path).await.unwrap();
+        let (tx, _rx) = connect(uds_client, None).unwrap();
+        tx.send_async(ClientMessage::channel_payload(b"relay", b"hello")).await.unwrap();
+        tx
+    });
+    let _connection = server.next(Address::Con, None, None).await.unwrap();
+
+    // A client receiving from the relay
+    let client = tokio::spawn(async move {
+        let uds_client = UdsClient::connect(path).await.unwrap();
+        let (_tx, rx) = connect(uds_client, None).unwrap();
+        rx.recv_async().await.unwrap()
+    });
+    let mut connection = server.next(Address::Con2, None, None).await.unwrap();
+
+    let bytes = match relay.recv().await.unwrap() {
+        Message::RemoteMessage { bytes, .. } => bytes,
+        _ => panic!("invalid message"),
+    };
+
+    // Frame the message once, and forward the frame as is
+    let frame = Frame::frame_message(&bytes).into_bytes();
+    let frame_ptr = frame.as_ptr() as usize;
+    relay.forward_framed(Address::Con2, frame).await.unwrap();
+    assert!(connection.recv().await.unwrap().is_none());
+
+    assert_eq!(b"hello".to_vec(), client.await.unwrap());
+    assert_eq!(vec![frame_ptr], *outbound.lock().unwrap());
+
+    relay.shutdown_router().await;
+    handle.await.unwrap();
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn close_connection() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = TcpConnections::from_std(listener).unwrap();
+    let mut server = Server::new(connections, server_agent);
+
+    let client = tokio::spawn(async move {
+        let tcp_client = TcpClient::connect(addr).await.unwrap();
+        let (_tx, rx) = connect(tcp_client, None).unwrap();
+        rx.recv_async().await
+    });
+
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+    let connection_handle = connection.handle();
+    let connection_task = tokio::spawn(async move {
+        loop {
+            if let Some(Message::Shutdown) = connection.recv().await.unwrap() {
+                break;
+            }
+        }
+    });
+
+    connection_handle.close("kicked").await.unwrap();
+    connection_task.await.unwrap();
+
+    // The client sees the connection closed
+    assert!(client.await.unwrap().is_err());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn rate_limited_connection() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = std_listener.local_addr().unwrap();
+    let connections = TcpConnections::from_std(std_listener).unwrap();
+    let rate_limit = RateLimit { messages_per_second: Some(20), ..Default::default() };
+    let mut server = Server::new(connections, server_agent).with_rate_limit(rate_limit);
+
+    // A client sending as fast as it can
+    tokio::spawn(async move {
+        let tcp_client = TcpClient::connect(addr).await.unwrap();
+        let (tx, _rx) = connect(tcp_client, None).unwrap();
+        for _ in 0..30 {
+            let message = ClientMessage::channel_payload(b"con", b"hello");
+            tx.send_async(message).await.unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    });
+
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+    let start = std::time::Instant::now();
+    for _ in 0..30 {
+        let msg = connection.recv().await.unwrap().unwrap();
+        assert!(matches!(msg, Message::RemoteMessage { .. }));
+    }
+
+    // A burst of 20, and the remaining 10 at 20 per second
+    assert!(start.elapsed() >= std::time::Duration::from_millis(450));
+    assert_eq!(30, connection.stats().messages_read());
+    assert_eq!(30 * b"con|hello".len() as u64, connection.stats().bytes_read());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn zero_rate_limit_is_unlimited() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = std_listener.local_addr().unwrap();
+    let connections = TcpConnections::from_std(std_listener).unwrap();
+    let rate_limit = RateLimit { bytes_per_second: Some(0), messages_per_second: Some(0) };
+    let mut server = Server::new(connections, server_agent).with_rate_limit(rate_limit);
+
+    tokio::spawn(async move {
+        let tcp_client = TcpClient::connect(addr).await.unwrap();
+        let (tx, _rx) = connect(tcp_client, None).unwrap();
+        for _ in 0..30 {
+            let message = ClientMessage::channel_payload(b"con", b"hello");
+            tx.send_async(message).await.unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    });
+
+    // Every message arrives, without the reader panicking or pausing
+    let mut connection = server.next(Address::Con, None, None).await.unwrap();
+    for _ in 0..30 {
+        let msg = connection.recv().await.unwrap().unwrap();
+        assert!(matches!(msg, Message::RemoteMessage { .. }));
+    }
+    assert_eq!(30, connection.stats().messages_read());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn broadcast_remote() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = TcpConnections::from_std(listener).unwrap();
+    let mut server = Server::new(connections, server_agent);
+
+    let mut clients = Vec::new();
+    let mut connection_tasks = Vec::new();
+    for address in [Address::Con, Address::Con2, Address::Con3] {
+        let tcp_client = TcpClient::connect(addr).await.unwrap();
+        clients.push(connect(tcp_client, None).unwrap());
+
+        let mut connection = server.next(address, None, None).await.unwrap();
+        connection_tasks.push(tokio::spawn(async move {
+            while let Ok(None) = connection.recv().await {}
+        }));
+    }
+
+    // Every client receives the broadcast
+    assert_eq!(3, server.broadcast_remote(Bytes::from_static(b"hello")).await.unwrap());
+    for (_tx, rx) in &clients {
+        assert_eq!(b"hello".to_vec(), rx.recv_async().await.unwrap());
+    }
+
+    // A dead connection doesn't stop the others from receiving it,
+    // and isn't broadcast to once dropped
+    assert_eq!(3, server.connection_count());
+    let dead = connection_tasks.remove(0);
+    dead.abort();
+    let _ = dead.await;
+    assert_eq!(2, server.connection_count());
+    assert_eq!(2, server.broadcast_remote(Bytes::from_static(b"bye")).await.unwrap());
+    for (_tx, rx) in &clients[1..] {
+        assert_eq!(b"bye".to_vec(), rx.recv_async().await.unwrap());
+    }
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn configure_sockets() {
+    let (agent_a, server_agent, router) = setup();
+    let handle = tokio::spawn(async move { router.run().await });
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let nodelay = Arc::new(Mutex::new(None));
+    let server_nodelay = nodelay.clone();
+    let connections = TcpConnections::from_std(listener).unwrap().with_socket(move |stream| {
+        stream.set_nodelay(true).unwrap();
+        *server_nodelay.lock().unwrap() = Some(stream.nodelay().unwrap());
+    });
+    let mut server = Server::new(connections, server_agent);
+
+    let tcp_client = TcpClient::connect(addr).await.unwrap().with_socket(|stream| stream.set_ttl(42).unwrap());
+    assert_eq!(42, tcp_client.inner.ttl().unwrap());
+
+    let _connection = server.next(Address::Con, None, None).await.unwrap();
+    assert_eq!(Some(true), *nodelay.lock().unwrap());
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
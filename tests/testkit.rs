@@ -0,0 +1,56 @@
+use tinyroute::testkit::{assert_recv_sequence, assert_recv_sequence_within};
+use tinyroute::{Message, Router, ToAddress};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    A,
+    B,
+}
+
+impl ToAddress for Address {}
+
+fn is_value(msg: &Message<u32, Address>, expected: &u32) -> bool {
+    matches!(msg, Message::Value(val, Address::A) if val == expected)
+}
+
+#[tokio::test]
+async fn passing_sequence() {
+    let mut router = Router::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    tokio::spawn(router.run());
+
+    for i in 0..3u32 {
+        agent_a.send(Address::B, i).await.unwrap();
+    }
+
+    assert_recv_sequence(&mut agent_b, &[0, 1, 2], is_value).await;
+}
+
+#[tokio::test]
+#[should_panic(expected = "#1   MISMATCH expected 1")]
+async fn out_of_order_sequence() {
+    let mut router = Router::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    tokio::spawn(router.run());
+
+    agent_a.send(Address::B, 0u32).await.unwrap();
+    agent_a.send(Address::B, 2u32).await.unwrap();
+
+    assert_recv_sequence(&mut agent_b, &[0, 1], is_value).await;
+}
+
+#[tokio::test]
+#[should_panic(expected = "#1   MISSING  expected 1, received timed out")]
+async fn missing_message() {
+    let mut router = Router::new();
+    let agent_a = router.new_agent::<u32>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<u32>(None, Address::B).unwrap();
+    tokio::spawn(router.run());
+
+    agent_a.send(Address::B, 0u32).await.unwrap();
+
+    let wait = std::time::Duration::from_millis(50);
+    assert_recv_sequence_within(&mut agent_b, &[0, 1], is_value, wait).await;
+}
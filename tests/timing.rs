@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use tinyroute::{Message, Router, ToAddress};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    A,
+    B,
+}
+
+impl ToAddress for Address {}
+
+#[tokio::test]
+async fn queue_time_reflects_consumer_delay() {
+    let mut router = Router::new();
+    let agent_a = router.new_agent::<String>(None, Address::A).unwrap();
+    let mut agent_b = router.new_agent::<String>(None, Address::B).unwrap();
+    let handle = tokio::spawn(router.run());
+
+    agent_a.send(Address::B, "first".to_string()).await.unwrap();
+    agent_a.send(Address::B, "second".to_string()).await.unwrap();
+
+    // A slow consumer
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (msg, timing) = agent_b.recv_with_timing().await.unwrap();
+    assert!(matches!(msg, Message::Value(val, Address::A) if val == "first"));
+    assert!(timing.queued_for() >= Duration::from_millis(50));
+
+    // The next message was queued right behind the first one
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let (msg, timing) = agent_b.recv_with_timing().await.unwrap();
+    assert!(matches!(msg, Message::Value(val, Address::A) if val == "second"));
+    assert!(timing.queued_for() >= Duration::from_millis(100));
+
+    agent_a.shutdown_router().await;
+    handle.await.unwrap();
+}
@@ -0,0 +1,209 @@
+//! Derive macros for tinyroute.
+//!
+//! Use these through tinyroute with the `derive` feature,
+//! rather than depending on this crate directly.
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+/// Implement `tinyroute::codec::AddressCodec` for an enum or a struct.
+///
+/// An enum is encoded as the index of the variant (a single byte),
+/// followed by the fields of the variant in order.
+/// A struct is encoded as its fields in order.
+/// Every field has to implement `AddressCodec`.
+#[proc_macro_derive(ToAddressCodec)]
+pub fn derive_address_codec(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Implement `tinyroute::ToAddress` for an enum of unit variants.
+///
+/// Every variant is given a tag: its index, in the order the variants are declared,
+/// starting at zero (so reordering or removing variants changes the tags).
+/// The address is encoded as the single tag byte:
+/// `to_bytes` encodes the tag and `from_bytes` decodes it.
+/// `Option<Vec<u8>>` also implements `From` the enum, returning the tag
+/// (e.g for `tinyroute::Agent::send_bridged`).
+///
+/// As the tag is sent as is, it can't be `tinyroute::ADDRESS_SEP` (124),
+/// so the enum can have at most 124 variants.
+/// `to_string` is the name of the variant.
+#[proc_macro_derive(ToAddress)]
+pub fn derive_to_address(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_to_address(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_to_address(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => return Err(Error::new_spanned(name, "ToAddress can only be derived for an enum")),
+    };
+    // Tags start at zero and must stay below `ADDRESS_SEP`
+    if data.variants.len() > b'|' as usize {
+        return Err(Error::new_spanned(name, "ToAddress supports at most 124 variants"));
+    }
+
+    let mut decode_arms = Vec::new();
+    let mut encode_arms = Vec::new();
+    let mut name_arms = Vec::new();
+    for (tag, variant) in data.variants.iter().enumerate() {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new_spanned(variant, "ToAddress can only be derived for unit variants"));
+        }
+        let tag = tag as u8;
+        let variant_name = &variant.ident;
+        let variant_str = variant_name.to_string();
+        decode_arms.push(quote! { [#tag] => ::std::option::Option::Some(Self::#variant_name), });
+        encode_arms.push(quote! { Self::#variant_name => #tag, });
+        name_arms.push(quote! { Self::#variant_name => #variant_str, });
+    }
+
+    Ok(quote! {
+        impl #impl_generics ::tinyroute::ToAddress for #name #ty_generics #where_clause {
+            fn from_bytes(bytes: &[u8]) -> ::std::option::Option<Self> {
+                match bytes {
+                    #(#decode_arms)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+
+            fn to_string(&self) -> ::std::string::String {
+                let name = match self {
+                    #(#name_arms)*
+                };
+                ::std::string::String::from(name)
+            }
+
+            fn to_bytes(&self) -> ::std::vec::Vec<u8> {
+                let tag = match self {
+                    #(#encode_arms)*
+                };
+                ::std::vec![tag]
+            }
+        }
+
+        impl #impl_generics ::std::convert::From<#name #ty_generics> for ::std::option::Option<::std::vec::Vec<u8>> #where_clause {
+            fn from(address: #name #ty_generics) -> Self {
+                ::std::option::Option::Some(::tinyroute::ToAddress::to_bytes(&address))
+            }
+        }
+    })
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let (encode, decode) = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, fields) = bind_fields(&data.fields);
+            let constructor = construct(&data.fields);
+            let encode = quote! {
+                let Self #pattern = self;
+                #(::tinyroute::codec::AddressCodec::encode_into(#fields, buf);)*
+            };
+            let decode = quote! { ::std::option::Option::Some(Self #constructor) };
+            (encode, decode)
+        }
+        Data::Enum(data) => {
+            if data.variants.len() > u8::MAX as usize + 1 {
+                return Err(Error::new_spanned(name, "ToAddressCodec supports at most 256 variants"));
+            }
+
+            let mut encode_arms = Vec::new();
+            let mut decode_arms = Vec::new();
+            for (tag, variant) in data.variants.iter().enumerate() {
+                let tag = tag as u8;
+                let variant_name = &variant.ident;
+                let (pattern, fields) = bind_fields(&variant.fields);
+                let constructor = construct(&variant.fields);
+                encode_arms.push(quote! {
+                    Self::#variant_name #pattern => {
+                        buf.push(#tag);
+                        #(::tinyroute::codec::AddressCodec::encode_into(#fields, buf);)*
+                    }
+                });
+                decode_arms.push(quote! {
+                    #tag => ::std::option::Option::Some(Self::#variant_name #constructor),
+                });
+            }
+
+            let encode = quote! {
+                match self {
+                    #(#encode_arms)*
+                }
+            };
+            let decode = quote! {
+                let tag = <u8 as ::tinyroute::codec::AddressCodec>::decode_from(bytes)?;
+                match tag {
+                    #(#decode_arms)*
+                    _ => ::std::option::Option::None,
+                }
+            };
+            (encode, decode)
+        }
+        Data::Union(_) => return Err(Error::new_spanned(name, "ToAddressCodec can not be derived for a union")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::tinyroute::codec::AddressCodec for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn encode_into(&self, buf: &mut ::std::vec::Vec<u8>) {
+                #encode
+            }
+
+            #[allow(unused_variables)]
+            fn decode_from(bytes: &mut &[u8]) -> ::std::option::Option<Self> {
+                #decode
+            }
+        }
+    })
+}
+
+// A pattern binding every field to `field_0`, `field_1`, ...
+// along with the names of the bindings
+fn bind_fields(fields: &Fields) -> (TokenStream2, Vec<Ident>) {
+    let bindings = (0..fields.len())
+        .map(|i| Ident::new(&format!("field_{}", i), Span::call_site()))
+        .collect::<Vec<_>>();
+
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|f| &f.ident);
+            quote! { { #(#names: #bindings),* } }
+        }
+        Fields::Unnamed(_) => quote! { ( #(#bindings),* ) },
+        Fields::Unit => quote! {},
+    };
+
+    (pattern, bindings)
+}
+
+// Decode every field, in order
+fn construct(fields: &Fields) -> TokenStream2 {
+    let decode = quote! { ::tinyroute::codec::AddressCodec::decode_from(bytes)? };
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|f| &f.ident);
+            quote! { { #(#names: #decode),* } }
+        }
+        Fields::Unnamed(unnamed) => {
+            let decodes = unnamed.unnamed.iter().map(|_| &decode);
+            quote! { ( #(#decodes),* ) }
+        }
+        Fields::Unit => quote! {},
+    }
+}